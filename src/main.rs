@@ -1,5 +1,6 @@
-use log::error;
-use std::env;
+use log::{error, info};
+use std::{env, net::SocketAddr, sync::Arc, time::Duration};
+use structopt::StructOpt;
 use warp::Filter;
 
 #[tokio::main]
@@ -8,287 +9,7481 @@ async fn main() {
         env::set_var("RUST_LOG", "cnb-shim=info");
     }
     pretty_env_logger::init();
+    telemetry::init();
 
-    let buildpack_dir = std::env::current_dir().unwrap_or_else(|_| {
-        error!("Could not get the current directory.");
+    let config = config::Config::from_args();
+
+    let script_names = config.shim_scripts.as_deref();
+    let scripts = match &config.bin_dir {
+        Some(bin_dir) => scripts::ShimScripts::from_dir(bin_dir, script_names),
+        None => scripts::ShimScripts::bundled(script_names),
+    }
+    .unwrap_or_else(|err| {
+        error!("Invalid shim scripts: {}", err);
         std::process::exit(1);
     });
+    info!("shim scripts: {:?}", scripts);
 
-    let routes = filters::routes(buildpack_dir).with(warp::log("cnb-shim"));
-    warp::serve(routes).run(([0, 0, 0, 0], 3000)).await;
-}
+    let janitor = janitor::Janitor::new(
+        config
+            .work_dir
+            .clone()
+            .unwrap_or_else(|| env::temp_dir().join("cnb-shim")),
+        config.disk_quota,
+        config.min_free_space,
+        Duration::from_secs(config.max_work_dir_age),
+    )
+    .unwrap_or_else(|err| {
+        error!("Could not create the work directory: {}", err);
+        std::process::exit(1);
+    });
+    let janitor = Arc::new(janitor);
+    tokio::spawn(
+        janitor
+            .clone()
+            .run(Duration::from_secs(config.janitor_interval)),
+    );
 
-mod filters {
-    use super::{handlers, models};
-    use std::path::PathBuf;
-    use warp::{Filter, Rejection, Reply};
+    let cache = config.storage.as_deref().map(|url| {
+        let storage = storage::from_url(url).unwrap_or_else(|err| {
+            error!("Could not set up storage at {}: {}", url, err);
+            std::process::exit(1);
+        });
+        Arc::new(cache::Cache::new(
+            storage,
+            config.cache_ttl.map(Duration::from_secs),
+        ))
+    });
 
-    pub fn routes(
-        buildpack_dir: impl Into<PathBuf>,
-    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        shim(buildpack_dir).or(health())
+    let memory_cache = Some(config.memory_cache_size)
+        .filter(|size| *size > 0)
+        .map(|size| cache::MemoryCache::new(size, config.cache_ttl.map(Duration::from_secs)));
+
+    let allowed_hosts = hosts::AllowedHosts::new(config.allowed_hosts.clone());
+    let client = http_client::build(
+        &http_client::Settings {
+            https_proxy: config.https_proxy.clone(),
+            http_proxy: config.http_proxy.clone(),
+            no_proxy: config.no_proxy.clone(),
+            ca_bundle: config.ca_bundle.clone(),
+            pool_idle_timeout: Duration::from_secs(config.http_pool_idle_timeout),
+            pool_max_idle_per_host: config.http_pool_max_idle_per_host,
+            timeout: Duration::from_secs(config.http_timeout),
+            connect_timeout: Duration::from_secs(config.http_connect_timeout),
+            allowed_hosts: allowed_hosts.clone(),
+        }
+        .or_lowercase_env(|name| env::var(name).ok()),
+    )
+    .unwrap_or_else(|err| {
+        error!("Could not set up the HTTP client: {}", err);
+        std::process::exit(1);
+    });
+
+    let registry = registry::Registry::new(
+        config
+            .registry_url
+            .as_deref()
+            .unwrap_or(registry::V2_BUILDPACK_REGISTRY_URL),
+        config
+            .registry_api_url
+            .as_deref()
+            .unwrap_or(registry::REGISTRY_API_URL),
+        config
+            .cnb_registry_api_url
+            .as_deref()
+            .unwrap_or(registry::CNB_REGISTRY_API_URL),
+        client.clone(),
+    );
+
+    let signer = config.signing_key.as_ref().map(|path| {
+        signing::Signer::from_file(path, config.signing_key_password.clone()).unwrap_or_else(
+            |err| {
+                error!("Could not load the signing key {}: {}", path.display(), err);
+                std::process::exit(1);
+            },
+        )
+    });
+
+    let stacks = stacks::Stacks::new(config.default_stacks, config.deprecated_stacks);
+    info!("stacks: {:?}", stacks);
+
+    let limits = shim::Limits {
+        download_size: config.max_download_size,
+        extracted_size: config.max_extracted_size,
+        artifact_size: config.max_artifact_size,
+    };
+
+    let shimmer = Arc::new(shim::Shimmer::new(
+        scripts,
+        janitor,
+        cache,
+        memory_cache,
+        registry,
+        signer,
+        stacks,
+        limits,
+        client.clone(),
+        allowed_hosts,
+    ));
+
+    if let Some(config::Command::Warm { manifest }) = &config.command {
+        if shimmer.cache().is_none() {
+            error!("Warming needs a cache to warm, set --storage");
+            std::process::exit(1);
+        }
+        let manifest = warm::Manifest::load(manifest).unwrap_or_else(|err| {
+            error!("Could not load {}: {}", manifest.display(), err);
+            std::process::exit(1);
+        });
+        let failed = warm::warm(&shimmer, &manifest, config.batch_concurrency).await;
+        telemetry::shutdown();
+        std::process::exit(if failed == 0 { 0 } else { 1 });
     }
 
-    /// GET /health
-    pub fn health() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("health")
-            .and(warp::get())
-            .and_then(handlers::health_check)
+    if let Some(manifest) = config.warm_manifest.clone() {
+        if shimmer.cache().is_none() {
+            error!("--warm-manifest needs a cache to warm, set --storage");
+            std::process::exit(1);
+        }
+        tokio::spawn(warm::run(
+            shimmer.clone(),
+            manifest,
+            config.warm_interval.map(Duration::from_secs),
+            config.batch_concurrency,
+        ));
     }
 
-    /// GET /v1/:namespace/:name
-    pub fn shim(
-        buildpack_dir: impl Into<PathBuf>,
-    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("v1" / String / String)
-            .and(warp::get())
-            .and(warp::query::<models::ShimOptions>())
-            .and(with_buildpack_dir(buildpack_dir.into()))
-            .and_then(handlers::shim)
-            .recover(handlers::rejection)
+    let notifier = Arc::new(callback::Notifier::new(config.external_url.clone(), client));
+
+    let audit_log = match &config.audit_database_url {
+        Some(url) => Some(Arc::new(
+            audit::AuditLog::connect(url).await.unwrap_or_else(|err| {
+                error!("Could not open the audit log at {}: {}", url, err);
+                std::process::exit(1);
+            }),
+        )),
+        None => None,
+    };
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_port) = config.grpc_port {
+        let service = grpc::ShimService::new(
+            shimmer.clone(),
+            audit_log.clone(),
+            config.external_url.clone(),
+        )
+        .into_server();
+        let grpc_addr = SocketAddr::from(([0, 0, 0, 0], grpc_port));
+        tokio::spawn(async move {
+            info!("serving gRPC on {}", grpc_addr);
+            if let Err(err) = tonic::transport::Server::builder()
+                .add_service(service)
+                .serve(grpc_addr)
+                .await
+            {
+                error!("gRPC server failed: {}", err);
+            }
+        });
     }
 
-    fn with_buildpack_dir(
-        buildpack_dir: PathBuf,
-    ) -> impl Filter<Extract = (PathBuf,), Error = std::convert::Infallible> + Clone {
-        warp::any().map(move || buildpack_dir.clone())
+    let routes = filters::routes(
+        shimmer,
+        notifier,
+        audit_log,
+        config.readiness_check_registry,
+        config.batch_concurrency,
+        config.admin_token.clone(),
+        config.trusted_proxies.clone(),
+    )
+    .with(warp::log("cnb-shim"));
+    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+
+    let serve = async move {
+        match (config.tls_cert, config.tls_key) {
+            (Some(cert), Some(key)) => {
+                let resolver = Arc::new(tls::CertResolver::load(cert, key).unwrap_or_else(|err| {
+                    error!("Could not load the TLS certificate: {}", err);
+                    std::process::exit(1);
+                }));
+                if let Some(interval) = config.tls_reload_interval {
+                    tokio::spawn(tls::reload_on_change(
+                        resolver.clone(),
+                        Duration::from_secs(interval),
+                    ));
+                }
+                let listener = tokio::net::TcpListener::bind(addr)
+                    .await
+                    .unwrap_or_else(|err| {
+                        error!("Could not listen on {}: {}", addr, err);
+                        std::process::exit(1);
+                    });
+                info!("listening on https://{}", addr);
+                tls::serve(listener, resolver, warp::service(routes)).await
+            }
+            _ => warp::serve(routes).run(addr).await,
+        }
+    };
+    tokio::select! {
+        _ = serve => {}
+        _ = shutdown_signal() => info!("shutting down"),
     }
+    telemetry::shutdown();
 }
 
-mod handlers {
-    use super::models;
-    use flate2::{read::GzDecoder, write::GzEncoder, Compression};
-    use libcnb::data::buildpack;
-    use log::{error, info};
+/// Resolves on Ctrl-C, or the SIGTERM the service is stopped with
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(err) => {
+                error!("Can't listen for SIGTERM: {}", err);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+mod config {
+    use std::{net::IpAddr, path::PathBuf};
+    use structopt::StructOpt;
+
+    #[derive(Debug, StructOpt)]
+    #[structopt(
+        name = "cnb-shim",
+        about = "Shim Heroku classic buildpacks as Cloud Native Buildpacks"
+    )]
+    pub struct Config {
+        /// Port to listen on
+        #[structopt(long, env = "PORT", default_value = "3000")]
+        pub port: u16,
+
+        /// PEM encoded certificate chain, enables TLS when given with --tls-key
+        #[structopt(long, env = "TLS_CERT", parse(from_os_str), requires = "tls-key")]
+        pub tls_cert: Option<PathBuf>,
+
+        /// PEM encoded private key, enables TLS when given with --tls-cert
+        #[structopt(long, env = "TLS_KEY", parse(from_os_str), requires = "tls-cert")]
+        pub tls_key: Option<PathBuf>,
+
+        /// Seconds between checks of the TLS files for changes, which new connections are
+        /// served with once both load. Disabled when not set.
+        #[structopt(long, env = "TLS_RELOAD_INTERVAL")]
+        pub tls_reload_interval: Option<u64>,
+
+        /// Where registry buildpack tarballs are downloaded from, instead of the Heroku
+        /// Buildpack Registry's
+        #[structopt(long, env = "REGISTRY_URL")]
+        pub registry_url: Option<String>,
+
+        /// Buildpack registry API to resolve releases and metadata with, instead of the Heroku
+        /// Buildpack Registry's
+        #[structopt(long, env = "REGISTRY_API_URL")]
+        pub registry_api_url: Option<String>,
+
+        /// Cloud Native Buildpacks registry API that project.toml buildpacks are looked up in,
+        /// instead of registry.buildpacks.io
+        #[structopt(long, env = "CNB_REGISTRY_API_URL")]
+        pub cnb_registry_api_url: Option<String>,
+
+        /// Comma separated hosts besides GitHub's that requests may have buildpack tarballs
+        /// downloaded from and callbacks posted to, and that their redirects have to stay on.
+        /// A leading `.` allows the subdomains of a domain.
+        #[structopt(long, env = "ALLOWED_HOSTS", use_delimiter = true)]
+        pub allowed_hosts: Vec<String>,
+
+        /// Proxy HTTPS downloads, registry lookups and callbacks go through. `https_proxy` is
+        /// read too when HTTPS_PROXY isn't set, as are `http_proxy` and `no_proxy` below.
+        #[structopt(long, env = "HTTPS_PROXY")]
+        pub https_proxy: Option<String>,
+
+        /// Proxy plain HTTP downloads, registry lookups and callbacks go through
+        #[structopt(long, env = "HTTP_PROXY")]
+        pub http_proxy: Option<String>,
+
+        /// Comma separated hosts reached without a proxy. A domain also covers its subdomains
+        /// and `*` covers every host.
+        #[structopt(long, env = "NO_PROXY", use_delimiter = true)]
+        pub no_proxy: Vec<String>,
+
+        /// PEM bundle of certificate authorities to trust on top of the system's, for
+        /// registries and proxies behind a private CA
+        #[structopt(long, env = "CA_BUNDLE", parse(from_os_str))]
+        pub ca_bundle: Option<PathBuf>,
+
+        /// Seconds an idle outgoing connection is kept open for reuse
+        #[structopt(long, env = "HTTP_POOL_IDLE_TIMEOUT", default_value = "90")]
+        pub http_pool_idle_timeout: u64,
+
+        /// Idle outgoing connections kept open per host. Unlimited when not set.
+        #[structopt(long, env = "HTTP_POOL_MAX_IDLE_PER_HOST")]
+        pub http_pool_max_idle_per_host: Option<usize>,
+
+        /// Seconds an outgoing request may take from start to finish, downloads included
+        #[structopt(long, env = "HTTP_TIMEOUT", default_value = "300")]
+        pub http_timeout: u64,
+
+        /// Seconds to wait for an outgoing connection to be established
+        #[structopt(long, env = "HTTP_CONNECT_TIMEOUT", default_value = "10")]
+        pub http_connect_timeout: u64,
+
+        /// Port to serve the gRPC API on. Not served when not set.
+        #[cfg(feature = "grpc")]
+        #[structopt(long, env = "GRPC_PORT")]
+        pub grpc_port: Option<u16>,
+
+        /// Include reachability of the Heroku Buildpack Registry in /readyz
+        #[structopt(long, env = "READINESS_CHECK_REGISTRY")]
+        pub readiness_check_registry: bool,
+
+        /// Directory with the detect, build, release and exports scripts of the shim, instead
+        /// of the ones bundled with cnb-shim
+        #[structopt(long, env = "BIN_DIR", parse(from_os_str))]
+        pub bin_dir: Option<PathBuf>,
+
+        /// Comma separated scripts making up the shim's bin/, which must include detect and
+        /// build. Defaults to all bundled scripts, or those found in --bin-dir.
+        #[structopt(long, env = "SHIM_SCRIPTS", use_delimiter = true)]
+        pub shim_scripts: Option<Vec<String>>,
+
+        /// Comma separated stacks shims run on when the request doesn't list any. `heroku-all`
+        /// stands for every Heroku stack that isn't deprecated and `*` for any stack.
+        #[structopt(
+            long,
+            env = "DEFAULT_STACKS",
+            use_delimiter = true,
+            default_value = "heroku-22,heroku-24"
+        )]
+        pub default_stacks: Vec<String>,
+
+        /// Comma separated stacks that are still shimmed for, with a warning
+        #[structopt(
+            long,
+            env = "DEPRECATED_STACKS",
+            use_delimiter = true,
+            default_value = "heroku-16,heroku-18,heroku-20"
+        )]
+        pub deprecated_stacks: Vec<String>,
+
+        /// Directory shims are assembled in, defaults to a cnb-shim directory in the system
+        /// temp directory
+        #[structopt(long, env = "WORK_DIR", parse(from_os_str))]
+        pub work_dir: Option<PathBuf>,
+
+        /// Bytes the work directory may use before new shims are refused. Unlimited when not set.
+        #[structopt(long, env = "DISK_QUOTA")]
+        pub disk_quota: Option<u64>,
+
+        /// Bytes that must be free on the work directory's file system to accept a new shim
+        #[structopt(long, env = "MIN_FREE_SPACE", default_value = "536870912")]
+        pub min_free_space: u64,
+
+        /// Seconds after which anything left in the work directory is considered orphaned
+        #[structopt(long, env = "MAX_WORK_DIR_AGE", default_value = "3600")]
+        pub max_work_dir_age: u64,
+
+        /// Seconds between sweeps of the work directory
+        #[structopt(long, env = "JANITOR_INTERVAL", default_value = "60")]
+        pub janitor_interval: u64,
+
+        /// Bytes a v2 buildpack tarball may have, downloaded or uploaded
+        #[structopt(long, env = "MAX_DOWNLOAD_SIZE", default_value = "268435456")]
+        pub max_download_size: u64,
+
+        /// Bytes a v2 buildpack may unpack to
+        #[structopt(long, env = "MAX_EXTRACTED_SIZE", default_value = "1073741824")]
+        pub max_extracted_size: u64,
+
+        /// Bytes a shimmed buildpack archive may have
+        #[structopt(long, env = "MAX_ARTIFACT_SIZE", default_value = "536870912")]
+        pub max_artifact_size: u64,
+
+        /// Where downloads and shimmed buildpacks are cached: a directory, `file://<path>`,
+        /// `s3://<bucket>/<prefix>` or `gs://<bucket>/<prefix>`. Nothing is cached when not set.
+        #[structopt(long, env = "STORAGE_URL")]
+        pub storage: Option<String>,
+
+        /// Seconds cached downloads and shimmed buildpacks are served for. Forever when not set.
+        #[structopt(long, env = "CACHE_TTL")]
+        pub cache_ttl: Option<u64>,
+
+        /// Bytes of recently shimmed buildpacks kept in memory, on top of --storage. 0
+        /// keeps nothing in memory.
+        #[structopt(long, env = "MEMORY_CACHE_SIZE", default_value = "67108864")]
+        pub memory_cache_size: usize,
+
+        /// minisign secret key to sign shimmed buildpacks and their provenance with. Nothing is
+        /// signed when not set.
+        #[structopt(long, env = "SIGNING_KEY", parse(from_os_str))]
+        pub signing_key: Option<PathBuf>,
+
+        /// Password of the signing key, if it's encrypted
+        #[structopt(long, env = "SIGNING_KEY_PASSWORD", hide_env_values = true)]
+        pub signing_key_password: Option<String>,
+
+        /// `sqlite://` URL of the database recording every shim, for /v1/audit and /v1/stats.
+        /// Nothing is recorded when not set.
+        #[structopt(long, env = "AUDIT_DATABASE_URL")]
+        pub audit_database_url: Option<String>,
+
+        /// Buildpacks of a batch shimmed at the same time
+        #[structopt(long, env = "BATCH_CONCURRENCY", default_value = "4")]
+        pub batch_concurrency: usize,
+
+        /// Bearer token for the /admin endpoints to inspect and clear the cache, and for the
+        /// audit log at /v1/audit and /v1/stats. They aren't served when not set.
+        #[structopt(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+        pub admin_token: Option<String>,
+
+        /// Comma separated addresses of the reverse proxies in front of the service, which are
+        /// trusted to tell the client of a request with `X-Forwarded-For`. The client is the
+        /// address connecting otherwise.
+        #[structopt(long, env = "TRUSTED_PROXIES", use_delimiter = true)]
+        pub trusted_proxies: Vec<IpAddr>,
+
+        /// Base URL the service is reachable at, used for artifact URLs in callbacks and in the
+        /// status of gRPC publish jobs
+        #[structopt(long, env = "EXTERNAL_URL")]
+        pub external_url: Option<String>,
+
+        /// Manifest of buildpacks to shim into the cache on startup, see `warm`. Needs --storage.
+        #[structopt(long, env = "WARM_MANIFEST", parse(from_os_str))]
+        pub warm_manifest: Option<PathBuf>,
+
+        /// Seconds between warming the cache from --warm-manifest again, which is reloaded
+        /// every time. Only warmed on startup when not set.
+        #[structopt(long, env = "WARM_INTERVAL", requires = "warm-manifest")]
+        pub warm_interval: Option<u64>,
+
+        #[structopt(subcommand)]
+        pub command: Option<Command>,
+    }
+
+    #[derive(Debug, StructOpt)]
+    pub enum Command {
+        /// Shims the buildpacks of a manifest into the cache and exits, instead of serving
+        Warm {
+            /// TOML file with a `[[buildpacks]]` entry for each buildpack, taking an `id`, an
+            /// optional tarball `url` and the options of a shim
+            #[structopt(long, parse(from_os_str))]
+            manifest: PathBuf,
+        },
+    }
+}
+
+mod tls {
+    use log::{error, info, warn};
     use std::{
         convert::Infallible,
-        fs,
-        io::Write,
+        fs, io,
+        net::SocketAddr,
         path::{Path, PathBuf},
-        str::FromStr,
+        sync::{Arc, RwLock},
+        time::{Duration, SystemTime},
     };
-    use tar::Archive;
     use thiserror::Error;
-    use tokio_stream::StreamExt;
-    use warp::{
-        http::StatusCode,
-        reject::{Reject, Rejection},
-        Reply,
+    use tokio::net::TcpListener;
+    use tokio_rustls::{
+        rustls::{
+            internal::pemfile, sign, sign::CertifiedKey, ClientHello, NoClientAuth,
+            ResolvesServerCert, ServerConfig,
+        },
+        TlsAcceptor,
+    };
+    use warp::hyper::{
+        server::conn::Http,
+        service::{service_fn, Service},
+        Body, Request, Response,
     };
 
-    const DEFAULT_API_VERSION: &str = "0.4";
-    const DEFAULT_VERSION: &str = "0.1.0";
-    const V2_BUILDPACK_REGISTRY_URL: &str =
-        "https://buildpack-registry.s3.amazonaws.com/buildpacks";
+    /// Address of the peer of a TLS connection, as a request extension since warp only knows
+    /// the peers of connections it accepted itself
+    #[derive(Debug, Clone, Copy)]
+    pub struct RemoteAddr(pub SocketAddr);
 
-    #[derive(Debug)]
-    /// Unrecoverable Error, HTTP Status Code 500
-    struct ServiceError(String);
+    #[derive(Error, Debug)]
+    pub enum TlsError {
+        #[error("failed to read {}: {}", .0.display(), .1)]
+        IOError(PathBuf, #[source] io::Error),
+        #[error("no PEM encoded certificates in {}", .0.display())]
+        NoCertificates(PathBuf),
+        #[error("no usable PEM encoded private key in {}", .0.display())]
+        NoKey(PathBuf),
+    }
 
-    impl Reject for ServiceError {}
+    /// Hands every TLS handshake the certificate and key last loaded from their files, so
+    /// they can be replaced without restarting the listener.
+    pub struct CertResolver {
+        cert: PathBuf,
+        key: PathBuf,
+        current: RwLock<CertifiedKey>,
+    }
 
-    impl ServiceError {
-        fn new(msg: impl Into<String>) -> Self {
-            ServiceError(msg.into())
+    impl CertResolver {
+        pub fn load(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Result<Self, TlsError> {
+            let (cert, key) = (cert.into(), key.into());
+            let current = RwLock::new(certified_key(&cert, &key)?);
+
+            Ok(CertResolver { cert, key, current })
         }
-    }
 
-    #[derive(Debug)]
-    /// Bad Request Error, HTTP Status Code 400
-    struct BadRequestError(String);
+        /// Reads the certificate and key again. The ones loaded before stay in use when that
+        /// fails, like when only one of them was replaced yet.
+        pub fn reload(&self) -> Result<(), TlsError> {
+            let loaded = certified_key(&self.cert, &self.key)?;
+            *self.current.write().unwrap_or_else(|err| err.into_inner()) = loaded;
 
-    impl Reject for BadRequestError {}
+            Ok(())
+        }
 
-    impl BadRequestError {
-        fn new(msg: impl Into<String>) -> Self {
-            BadRequestError(msg.into())
+        /// The DER encoded certificate chain handshakes are done with
+        pub fn chain(&self) -> Vec<Vec<u8>> {
+            self.current().cert.into_iter().map(|cert| cert.0).collect()
+        }
+
+        fn current(&self) -> CertifiedKey {
+            self.current
+                .read()
+                .unwrap_or_else(|err| err.into_inner())
+                .clone()
         }
     }
 
-    pub async fn rejection(err: Rejection) -> Result<impl Reply, Rejection> {
-        if err.is_not_found() {
-            return Err(warp::reject::not_found());
+    impl ResolvesServerCert for CertResolver {
+        fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+            Some(self.current())
         }
+    }
 
-        let code;
-        let message;
+    fn certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey, TlsError> {
+        let read = |path: &Path| fs::read(path).map_err(|err| TlsError::IOError(path.into(), err));
 
-        if let Some(service_error) = err.find::<ServiceError>() {
-            error!("{}", service_error.0);
-        }
-        if let Some(request_error) = err.find::<BadRequestError>() {
-            error!("{}", request_error.0);
-        }
-        message = "INTERNAL SERVER ERROR";
-        code = StatusCode::INTERNAL_SERVER_ERROR;
+        let chain = pemfile::certs(&mut read(cert)?.as_slice())
+            .ok()
+            .filter(|chain| !chain.is_empty())
+            .ok_or_else(|| TlsError::NoCertificates(cert.into()))?;
+        let pem = read(key)?;
+        let signing_key = pemfile::pkcs8_private_keys(&mut pem.as_slice())
+            .ok()
+            .filter(|keys| !keys.is_empty())
+            .or_else(|| pemfile::rsa_private_keys(&mut pem.as_slice()).ok())
+            .and_then(|keys| keys.into_iter().next())
+            .and_then(|key| sign::any_supported_type(&key).ok())
+            .ok_or_else(|| TlsError::NoKey(key.into()))?;
 
-        Ok(warp::reply::with_status(message, code))
+        Ok(CertifiedKey::new(chain, Arc::new(signing_key)))
     }
 
-    pub async fn health_check() -> Result<impl Reply, Infallible> {
-        Ok(warp::reply::with_status("health check ok", StatusCode::OK))
+    /// Reloads the certificate and key of `resolver` whenever their files change. Connections
+    /// keep being accepted throughout, a pair that doesn't load is logged and skipped.
+    pub async fn reload_on_change(resolver: Arc<CertResolver>, interval: Duration) {
+        loop {
+            wait_for_change(&[resolver.cert.as_path(), resolver.key.as_path()], interval).await;
+            match resolver.reload() {
+                Ok(()) => info!("TLS certificate changed on disk, reloaded it"),
+                Err(err) => error!(
+                    "Could not reload the TLS certificate, keeping the previous one: {}",
+                    err
+                ),
+            }
+        }
     }
 
-    pub async fn shim(
-        namespace: String,
-        name: String,
-        query_params: models::ShimOptions,
-        buildpack_dir: PathBuf,
-    ) -> Result<impl Reply, Rejection> {
-        info!("shimming: {}/{}", namespace, name);
+    /// Serves `service` over TLS to whatever connects to `listener`, with the certificate
+    /// `resolver` holds at the time of each handshake.
+    pub async fn serve<S>(listener: TcpListener, resolver: Arc<CertResolver>, service: S)
+    where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+    {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = resolver;
+        config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+        let acceptor = TlsAcceptor::from(Arc::new(config));
 
-        let id = buildpack::BuildpackId::from_str(&format!("{}/{}", namespace, name))
-            .map_err(|_| BadRequestError::new("invalid buildpack id"))?;
-        let version = buildpack::Version::parse(
-            &query_params
-                .version
-                .unwrap_or_else(|| String::from(DEFAULT_VERSION)),
-        )
-        .map_err(|err| BadRequestError::new(format!("invalid buildpack version: {:?}", err)))?;
-        let name = query_params
-            .name
-            .unwrap_or_else(|| String::from(id.as_str()));
-        let api = buildpack::BuildpackApi::from_str(
-            &query_params
-                .api
-                .unwrap_or_else(|| String::from(DEFAULT_API_VERSION)),
-        )
-        .map_err(|_| BadRequestError::new("invalid buildpack api"))?;
-        let stacks = query_params
-            .stacks
-            .unwrap_or_else(|| [String::from("heroku-18"), String::from("heroku-20")].into())
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // like running out of file descriptors, which takes a moment to recover from
+                    error!("Could not accept a connection: {}", err);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let (acceptor, service) = (acceptor.clone(), service.clone());
+
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("TLS handshake with {} failed: {}", remote_addr, err);
+                        return;
+                    }
+                };
+                let service = service_fn(move |mut request: Request<Body>| {
+                    request.extensions_mut().insert(RemoteAddr(remote_addr));
+                    service.clone().call(request)
+                });
+                if let Err(err) = Http::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades()
+                    .await
+                {
+                    warn!("Connection with {} failed: {}", remote_addr, err);
+                }
+            });
+        }
+    }
+
+    /// Resolves once the modification time of any of `paths` changes and then stays the same
+    /// for one more `interval`, so a certificate and key that are rotated together are picked
+    /// up as a pair instead of half way through.
+    pub async fn wait_for_change(paths: &[&Path], interval: Duration) {
+        let initial = modified(paths);
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            let mut current = modified(paths);
+            if current == initial {
+                continue;
+            }
+
+            loop {
+                ticker.tick().await;
+                let settled = modified(paths);
+                if settled == current {
+                    return;
+                }
+                current = settled;
+            }
+        }
+    }
+
+    fn modified(paths: &[&Path]) -> Vec<Option<SystemTime>> {
+        paths
             .iter()
-            .map(|stack| {
-                Ok(buildpack::Stack {
-                    id: buildpack::StackId::from_str(stack)?,
-                    mixins: Vec::new(),
-                })
+            .map(|path| {
+                fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
             })
-            .collect::<Result<Vec<buildpack::Stack>, libcnb::Error>>()
-            .map_err(|_| BadRequestError::new("invalid stack"))?;
+            .collect()
+    }
+}
+
+mod http_client {
+    use super::hosts::AllowedHosts;
+    use reqwest::{redirect::Policy, Certificate, Client, Proxy, Url};
+    use std::{fs, path::PathBuf, time::Duration};
+    use thiserror::Error;
+
+    const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const PEM_END: &str = "-----END CERTIFICATE-----";
+    /// Redirects followed before giving up, as many as reqwest follows by default
+    const MAX_REDIRECTS: usize = 10;
 
-        let shimmed_buildpack = format!("{}.tgz", uuid::Uuid::new_v4());
-        let v2_buildpack_url = format!("{}/{}.tgz", V2_BUILDPACK_REGISTRY_URL, &id.as_str());
+    /// How outgoing requests leave the service, shared by downloads, registry lookups and
+    /// callbacks
+    #[derive(Debug, Clone)]
+    pub struct Settings {
+        pub https_proxy: Option<String>,
+        pub http_proxy: Option<String>,
+        /// Hosts reached without the proxy: `*`, a host, or a domain matching its subdomains
+        pub no_proxy: Vec<String>,
+        /// PEM bundle of certificate authorities trusted on top of the system's
+        pub ca_bundle: Option<PathBuf>,
+        pub pool_idle_timeout: Duration,
+        pub pool_max_idle_per_host: Option<usize>,
+        /// How long a request may take in all, unless it sets its own timeout
+        pub timeout: Duration,
+        pub connect_timeout: Duration,
+        /// Hosts requests that start out on one of them can be redirected to
+        pub allowed_hosts: AllowedHosts,
+    }
 
-        let tmp_dir = tempfile::tempdir().map_err(|_| ServiceError::new("Can't create tmp dir"))?;
+    impl Settings {
+        /// Fills in the proxy settings left unset from the lowercase `https_proxy`,
+        /// `http_proxy` and `no_proxy` variables, which curl and most other tools read too.
+        /// `var` looks a variable up, which is `std::env::var` outside of tests.
+        pub fn or_lowercase_env(mut self, var: impl Fn(&str) -> Option<String>) -> Self {
+            let var = |name: &str| var(name).filter(|value| !value.trim().is_empty());
+            if self.https_proxy.is_none() {
+                self.https_proxy = var("https_proxy");
+            }
+            if self.http_proxy.is_none() {
+                self.http_proxy = var("http_proxy");
+            }
+            if self.no_proxy.is_empty() {
+                self.no_proxy = var("no_proxy")
+                    .map(|hosts| hosts.split(',').map(String::from).collect())
+                    .unwrap_or_default();
+            }
 
-        let shimmed_buildpack_dir = tmp_dir.path().join("buildpack");
-        let bin_dir = shimmed_buildpack_dir.join("bin");
-        fs::create_dir_all(&bin_dir).map_err(|_| ServiceError::new("Can't create bin dir"))?;
-        for bin in ["detect", "build", "release", "exports"].iter() {
-            fs::copy(buildpack_dir.join("bin").join(bin), bin_dir.join(bin))
-                .map_err(|_| ServiceError::new("Can't copy file"))?;
+            self
         }
+    }
 
-        let buildpack_toml = buildpack::BuildpackToml {
-            api,
-            buildpack: buildpack::Buildpack {
-                id,
-                name,
-                version,
-                homepage: None,
-                clear_env: false,
-            },
-            stacks,
-            order: Vec::new(),
-            metadata: toml::value::Table::new(),
-        };
+    #[derive(Error, Debug)]
+    pub enum ClientError {
+        #[error("invalid proxy URL {0}")]
+        InvalidProxy(String),
+        #[error("failed to read CA bundle: {0}")]
+        IOError(#[from] std::io::Error),
+        #[error("no certificates in CA bundle {0}")]
+        NoCertificates(PathBuf),
+        #[error("failed to build client: {0}")]
+        ReqwestError(#[from] reqwest::Error),
+    }
 
-        let buildpack_toml_path = shimmed_buildpack_dir.join("buildpack.toml");
-        fs::write(
-            buildpack_toml_path,
-            toml::to_string(&buildpack_toml).map_err(|err| {
-                ServiceError::new(format!("Can't convert buildpack.toml to string: {:?}", err))
-            })?,
-        )
-        .map_err(|_| ServiceError::new("Can't write buildpack.toml to disk"))?;
+    /// The client every outgoing request goes through, so connections are pooled across them.
+    /// Proxies only come from `settings`, never from the environment reqwest would read itself.
+    pub fn build(settings: &Settings) -> Result<Client, ClientError> {
+        let mut builder = Client::builder()
+            .no_proxy()
+            .redirect(redirect_policy(settings.allowed_hosts.clone()))
+            .timeout(settings.timeout)
+            .connect_timeout(settings.connect_timeout)
+            .pool_idle_timeout(settings.pool_idle_timeout);
+        if let Some(max_idle) = settings.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
 
-        let v2_buildpack_path = tmp_dir.path().join("buildpack.tgz");
-        download(v2_buildpack_url, &v2_buildpack_path)
-            .await
-            .map_err(|err| match err {
-                DownloadError::IOError(_) => {
-                    Rejection::from(ServiceError::new("Can't download v2 buildpack"))
+        let https_proxy = settings.https_proxy.as_deref().map(proxy_url).transpose()?;
+        let http_proxy = settings.http_proxy.as_deref().map(proxy_url).transpose()?;
+        if https_proxy.is_some() || http_proxy.is_some() {
+            let no_proxy = settings.no_proxy.clone();
+            builder = builder.proxy(Proxy::custom(move |url| {
+                if bypasses_proxy(&no_proxy, url.host_str()?) {
+                    return None;
                 }
-                DownloadError::ReqwestError(_) => {
-                    Rejection::from(BadRequestError::new("Can't download v2 buildpack"))
+                match url.scheme() {
+                    "https" => https_proxy.clone(),
+                    "http" => http_proxy.clone(),
+                    _ => None,
                 }
-            })?;
+            }));
+        }
 
-        untar(&v2_buildpack_path, shimmed_buildpack_dir.join("target"))
-            .map_err(|_| ServiceError::new("Could not untar v2 buildpack"))?;
-        let shimmed_buildpack_archive = tmp_dir.path().join(&shimmed_buildpack);
-        archive(&shimmed_buildpack_archive, shimmed_buildpack_dir)
-            .map_err(|_| ServiceError::new("Could not create shimmed tarball"))?;
+        if let Some(path) = &settings.ca_bundle {
+            let bundle = fs::read_to_string(path)?;
+            let certificates = pem_certificates(&bundle);
+            if certificates.is_empty() {
+                return Err(ClientError::NoCertificates(path.clone()));
+            }
+            for pem in certificates {
+                builder = builder.add_root_certificate(Certificate::from_pem(pem.as_bytes())?);
+            }
+        }
 
-        Ok(http::response::Builder::new()
-            .status(200)
-            .header("Content-Type", "application/x-gzip")
-            .header(
-                "Content-Disposition",
-                format!("attachment; filename=\"{}\"", &shimmed_buildpack),
-            )
-            .body(
-                fs::read(&shimmed_buildpack_archive)
-                    .map_err(|_| ServiceError::new("Could not read shimmed buildpack"))?,
-            )
-            .map_err(|_| ServiceError::new("Could not send response."))?)
+        Ok(builder.build()?)
     }
 
-    async fn download(uri: impl AsRef<str>, dst: impl AsRef<Path>) -> Result<(), DownloadError> {
-        let response = reqwest::get(uri.as_ref()).await?;
-        let mut stream = response.bytes_stream();
-        let mut file = fs::File::create(dst)?;
-
-        while let Some(chunk) = stream.next().await {
-            file.write_all(&chunk?)?;
-        }
+    /// Keeps requests to allowed hosts on allowed hosts, or a tarball or callback URL that
+    /// passed the check could redirect the service anywhere. Requests to the registries the
+    /// service was configured with aren't allowed by a request and follow redirects freely.
+    fn redirect_policy(allowed_hosts: AllowedHosts) -> Policy {
+        Policy::custom(move |attempt| {
+            if attempt.previous().len() > MAX_REDIRECTS {
+                return attempt.error(format!("more than {} redirects", MAX_REDIRECTS));
+            }
+            let checked = attempt
+                .previous()
+                .first()
+                .map_or(false, |origin| allowed_hosts.check(origin.as_str()).is_ok());
+            if checked {
+                if let Err(err) = allowed_hosts.check(attempt.url().as_str()) {
+                    return attempt.error(format!("redirected to {}", err));
+                }
+            }
 
-        Ok(())
+            attempt.follow()
+        })
     }
 
-    fn untar(file: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<(), DownloadError> {
-        let tar_gz = fs::File::open(file.as_ref())?;
-        let tar = GzDecoder::new(tar_gz);
-        let mut archive = Archive::new(tar);
-        archive.unpack(dst.as_ref())?;
+    /// Proxies given as `host:port` are plain HTTP proxies, like curl takes them
+    fn proxy_url(proxy: &str) -> Result<Url, ClientError> {
+        let url = if proxy.contains("://") {
+            Url::parse(proxy)
+        } else {
+            Url::parse(&format!("http://{}", proxy))
+        };
 
-        Ok(())
+        url.map_err(|_| ClientError::InvalidProxy(String::from(proxy)))
     }
 
-    fn archive(dst: impl AsRef<Path>, src: impl AsRef<Path>) -> Result<(), ArchiveError> {
-        let file = fs::File::create(dst.as_ref())?;
-        let enc = GzEncoder::new(file, Compression::default());
-        let mut builder = tar::Builder::new(enc);
-
-        builder.append_dir_all(".", src)?;
+    fn bypasses_proxy(no_proxy: &[String], host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        no_proxy
+            .iter()
+            .map(|entry| entry.trim().to_ascii_lowercase())
+            .filter(|entry| !entry.is_empty())
+            .any(|entry| {
+                if entry == "*" {
+                    return true;
+                }
+                let entry = match entry.rsplit_once(':') {
+                    Some((entry, port)) if port.chars().all(|c| c.is_ascii_digit()) => entry,
+                    _ => entry.as_str(),
+                };
+                let domain = entry.trim_start_matches("*.").trim_start_matches('.');
 
-        Ok(())
+                host == domain || host.ends_with(&format!(".{}", domain))
+            })
     }
 
-    #[derive(Error, Debug)]
-    enum DownloadError {
-        #[error("failed to write to disk")]
-        IOError(#[from] std::io::Error),
-        #[error("failed to download file")]
-        ReqwestError(#[from] reqwest::Error),
-    }
+    /// Every certificate of a PEM bundle, which reqwest only parses one at a time
+    fn pem_certificates(bundle: &str) -> Vec<&str> {
+        let mut certificates = Vec::new();
+        let mut rest = bundle;
+        while let Some(start) = rest.find(PEM_BEGIN) {
+            let end = match rest[start..].find(PEM_END) {
+                Some(end) => start + end + PEM_END.len(),
+                None => break,
+            };
+            certificates.push(&rest[start..end]);
+            rest = &rest[end..];
+        }
 
-    #[derive(Error, Debug)]
-    enum ArchiveError {
-        #[error("failed to write to disk")]
-        IOError(#[from] std::io::Error),
+        certificates
     }
 }
 
-mod models {
-    use serde::Deserialize;
+mod hosts {
+    use reqwest::Url;
 
-    #[derive(Debug, Deserialize)]
-    pub struct ShimOptions {
-        pub version: Option<String>,
-        pub name: Option<String>,
-        pub api: Option<String>,
-        pub stacks: Option<Vec<String>>,
+    /// Hosts buildpack tarballs may always be fetched from: github.com and the hosts its
+    /// archive and release downloads redirect to
+    const GITHUB: &[&str] = &[
+        "github.com",
+        "codeload.github.com",
+        "objects.githubusercontent.com",
+    ];
+
+    /// Hosts a request may point the service at. Without it, a request could make the service
+    /// fetch from or post to anything it can reach, internal addresses included.
+    #[derive(Debug, Clone)]
+    pub struct AllowedHosts {
+        hosts: Vec<String>,
     }
-}
+
+    impl AllowedHosts {
+        /// GitHub and `hosts`, where an entry starting with `.` allows the subdomains of a
+        /// domain instead
+        pub fn new(hosts: Vec<String>) -> Self {
+            let mut hosts = hosts
+                .iter()
+                .map(|host| host.trim().trim_end_matches('.').to_ascii_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect::<Vec<_>>();
+            hosts.extend(GITHUB.iter().map(|host| String::from(*host)));
+
+            AllowedHosts { hosts }
+        }
+
+        /// Checks `url` is an HTTP(S) URL on an allowed host
+        pub fn check(&self, url: &str) -> Result<(), String> {
+            let parsed = Url::parse(url).map_err(|_| format!("invalid URL {}", url))?;
+            if !matches!(parsed.scheme(), "http" | "https") {
+                return Err(format!("unsupported URL scheme {}", parsed.scheme()));
+            }
+            let host = parsed
+                .host_str()
+                .map(|host| host.trim_end_matches('.').to_ascii_lowercase())
+                .ok_or_else(|| format!("URL without a host {}", url))?;
+
+            if self.hosts.iter().any(|allowed| {
+                host == *allowed || (allowed.starts_with('.') && host.ends_with(allowed.as_str()))
+            }) {
+                Ok(())
+            } else {
+                Err(format!("{} is not an allowed host", host))
+            }
+        }
+    }
+}
+
+mod telemetry {
+    use opentelemetry::{
+        global,
+        propagation::Extractor,
+        sdk::propagation::TraceContextPropagator,
+        trace::{FutureExt, SpanKind, TraceContextExt, Tracer},
+        Context, KeyValue,
+    };
+    #[cfg(feature = "otel")]
+    use opentelemetry_otlp::WithExportConfig;
+    use std::future::Future;
+
+    const TRACER_NAME: &str = "cnb-shim";
+
+    /// Sets up `traceparent` propagation. With the otel feature, spans are exported over OTLP
+    /// when `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set, and
+    /// the exporter is configured by the other standard `OTEL_*` variables.
+    pub fn init() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        #[cfg(feature = "otel")]
+        if [
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        ]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some())
+        {
+            match opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_env())
+                .install_batch(opentelemetry::runtime::Tokio)
+            {
+                Ok(_) => log::info!("exporting traces over OTLP"),
+                Err(err) => log::error!("Could not set up the OTLP exporter: {}", err),
+            }
+        }
+    }
+
+    /// Exports the spans still waiting in the batch, so they aren't lost when the process exits
+    pub fn shutdown() {
+        global::shutdown_tracer_provider();
+    }
+
+    /// The trace context a request was sent with, from its `traceparent` header
+    pub fn extract(headers: &http::HeaderMap) -> Context {
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+    }
+
+    /// A server span for a request, in the trace the request was sent with
+    pub fn server_span(parent: &Context, name: &'static str, attributes: Vec<KeyValue>) -> Context {
+        let tracer = global::tracer(TRACER_NAME);
+        let builder = tracer
+            .span_builder(name)
+            .with_kind(SpanKind::Server)
+            .with_attributes(attributes);
+
+        parent.with_span(tracer.build_with_context(builder, parent))
+    }
+
+    /// A span as a child of the current one, ended when the returned context is dropped
+    pub fn span(name: &'static str) -> Context {
+        Context::current_with_span(global::tracer(TRACER_NAME).start(name))
+    }
+
+    /// Runs `future` in a span of its own
+    pub fn in_span<F: Future>(name: &'static str, future: F) -> impl Future<Output = F::Output> {
+        future.with_context(span(name))
+    }
+
+    struct HeaderExtractor<'a>(&'a http::HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|key| key.as_str()).collect()
+        }
+    }
+}
+
+mod janitor {
+    use log::{info, warn};
+    use std::{
+        fs, io,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::{Duration, SystemTime},
+    };
+    use thiserror::Error;
+
+    /// Keeps the work directory in check: removes what crashed or abandoned requests left
+    /// behind, and tells handlers when there's no room for another shim.
+    #[derive(Debug)]
+    pub struct Janitor {
+        work_dir: PathBuf,
+        quota: Option<u64>,
+        min_free_space: u64,
+        max_age: Duration,
+        usage: AtomicU64,
+    }
+
+    #[derive(Debug, Default)]
+    pub struct SweepReport {
+        pub removed: usize,
+        pub freed: u64,
+        pub usage: u64,
+    }
+
+    #[derive(Error, Debug)]
+    pub enum JanitorError {
+        #[error("disk quota of {quota} bytes exhausted, {usage} bytes in use")]
+        QuotaExceeded { quota: u64, usage: u64 },
+        #[error("{available} bytes of free space left, {required} bytes required")]
+        InsufficientSpace { available: u64, required: u64 },
+        #[error("failed to inspect the work directory")]
+        IOError(#[from] io::Error),
+    }
+
+    impl Janitor {
+        pub fn new(
+            work_dir: impl Into<PathBuf>,
+            quota: Option<u64>,
+            min_free_space: u64,
+            max_age: Duration,
+        ) -> io::Result<Self> {
+            let work_dir = work_dir.into();
+            fs::create_dir_all(&work_dir)?;
+
+            Ok(Janitor {
+                work_dir,
+                quota,
+                min_free_space,
+                max_age,
+                usage: AtomicU64::new(0),
+            })
+        }
+
+        pub fn work_dir(&self) -> &Path {
+            &self.work_dir
+        }
+
+        /// Checks there's room in the work directory for another shim.
+        pub fn check(&self) -> Result<(), JanitorError> {
+            if let Some(quota) = self.quota {
+                let usage = self.usage.load(Ordering::Relaxed);
+                if usage >= quota {
+                    return Err(JanitorError::QuotaExceeded { quota, usage });
+                }
+            }
+
+            let available = fs2::available_space(&self.work_dir)?;
+            if available < self.min_free_space {
+                return Err(JanitorError::InsufficientSpace {
+                    available,
+                    required: self.min_free_space,
+                });
+            }
+
+            Ok(())
+        }
+
+        /// Removes entries of the work directory older than the max age and records how much
+        /// space is left in use. Younger entries belong to in-flight requests and are kept even
+        /// when over quota; `check` refuses new shims until they finish instead.
+        pub fn sweep(&self) -> io::Result<SweepReport> {
+            let mut report = SweepReport::default();
+            let now = SystemTime::now();
+
+            for entry in fs::read_dir(&self.work_dir)? {
+                let entry = match unless_gone(entry)? {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let path = entry.path();
+                let size = disk_usage(&path)?;
+                let metadata = match unless_gone(entry.metadata())? {
+                    Some(metadata) => metadata,
+                    None => continue,
+                };
+                let age = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| now.duration_since(modified).ok())
+                    .unwrap_or_default();
+
+                if age > self.max_age {
+                    let removed = if metadata.is_dir() {
+                        fs::remove_dir_all(&path)
+                    } else {
+                        fs::remove_file(&path)
+                    };
+                    match unless_gone(removed) {
+                        Ok(Some(())) => {
+                            report.removed += 1;
+                            report.freed += size;
+                        }
+                        // its request got to it first
+                        Ok(None) => (),
+                        Err(err) => {
+                            warn!("Could not remove {}: {}", path.display(), err);
+                            report.usage += size;
+                        }
+                    }
+                } else {
+                    report.usage += size;
+                }
+            }
+
+            self.usage.store(report.usage, Ordering::Relaxed);
+
+            Ok(report)
+        }
+
+        /// Sweeps the work directory every `interval`, forever.
+        pub async fn run(self: Arc<Self>, interval: Duration) {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+
+                let janitor = self.clone();
+                match tokio::task::spawn_blocking(move || janitor.sweep()).await {
+                    Ok(Ok(report)) if report.removed > 0 => info!(
+                        "Removed {} orphaned entries ({} bytes), {} bytes in use",
+                        report.removed, report.freed, report.usage
+                    ),
+                    Ok(Ok(_)) => (),
+                    Ok(Err(err)) => warn!("Could not sweep the work directory: {}", err),
+                    Err(err) => warn!("Work directory sweep panicked: {}", err),
+                }
+            }
+        }
+    }
+
+    /// Bytes used by a file or directory tree, without following symlinks. Whatever is gone
+    /// by the time it's looked at counts as nothing.
+    fn disk_usage(path: &Path) -> io::Result<u64> {
+        let metadata = match unless_gone(fs::symlink_metadata(path))? {
+            Some(metadata) => metadata,
+            None => return Ok(0),
+        };
+        if !metadata.is_dir() {
+            return Ok(metadata.len());
+        }
+        let entries = match unless_gone(fs::read_dir(path))? {
+            Some(entries) => entries,
+            None => return Ok(0),
+        };
+
+        let mut size = 0;
+        for entry in entries {
+            if let Some(entry) = unless_gone(entry)? {
+                size += disk_usage(&entry.path())?;
+            }
+        }
+
+        Ok(size)
+    }
+
+    /// `None` for what doesn't exist (anymore). Requests remove their work directories while
+    /// they're being swept, which isn't an error.
+    fn unless_gone<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+mod storage {
+    use async_trait::async_trait;
+    use std::{
+        fmt, fs,
+        io::{self, Write},
+        path::{Component, Path, PathBuf},
+        sync::Arc,
+        time::SystemTime,
+    };
+    use thiserror::Error;
+    use tokio::task;
+
+    /// Prefix of the files `LocalStorage` writes objects to before renaming them into place
+    const TEMP_PREFIX: &str = ".tmp-";
+
+    /// Somewhere to keep blobs by key. Keys are `/` separated relative paths.
+    #[async_trait]
+    pub trait Storage: fmt::Debug + Send + Sync {
+        async fn get(&self, key: &str) -> Result<Option<Object>, StorageError>;
+        async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StorageError>;
+        async fn delete(&self, key: &str) -> Result<(), StorageError>;
+        async fn list(&self, prefix: &str) -> Result<Vec<Entry>, StorageError>;
+    }
+
+    #[derive(Debug)]
+    pub struct Object {
+        pub data: Vec<u8>,
+        pub modified: Option<SystemTime>,
+    }
+
+    #[derive(Debug)]
+    pub struct Entry {
+        pub key: String,
+        pub size: u64,
+        pub modified: Option<SystemTime>,
+    }
+
+    #[derive(Error, Debug)]
+    pub enum StorageError {
+        #[error("invalid storage key {0}")]
+        InvalidKey(String),
+        #[error("unsupported storage url {0}")]
+        Unsupported(String),
+        #[error("failed to access local storage: {0}")]
+        IOError(#[from] io::Error),
+        #[error("storage backend failed: {0}")]
+        Backend(String),
+    }
+
+    /// Picks a backend from a directory, `file://<path>`, `s3://<bucket>/<prefix>` or
+    /// `gs://<bucket>/<prefix>`.
+    pub fn from_url(url: &str) -> Result<Arc<dyn Storage>, StorageError> {
+        let (scheme, location) = url.split_once("://").unwrap_or(("file", url));
+        match scheme {
+            "file" => Ok(Arc::new(LocalStorage::new(location)?)),
+            #[cfg(feature = "s3")]
+            "s3" => {
+                let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+                Ok(Arc::new(s3::S3Storage::new(bucket, prefix)))
+            }
+            #[cfg(feature = "gcs")]
+            "gs" => {
+                let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+                Ok(Arc::new(gcs::GcsStorage::new(bucket, prefix)))
+            }
+            _ => Err(StorageError::Unsupported(String::from(url))),
+        }
+    }
+
+    /// Joins `key` onto `prefix`, the way object stores namespace keys.
+    #[cfg(any(feature = "s3", feature = "gcs"))]
+    fn prefixed(prefix: &str, key: &str) -> String {
+        if prefix.is_empty() {
+            String::from(key)
+        } else {
+            format!("{}/{}", prefix.trim_end_matches('/'), key)
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct LocalStorage {
+        root: PathBuf,
+    }
+
+    impl LocalStorage {
+        pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+            let root = root.into();
+            fs::create_dir_all(&root)?;
+
+            Ok(LocalStorage { root })
+        }
+
+        /// Resolves a key below the root, refusing keys that would escape it.
+        fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+            let relative = Path::new(key);
+            if key.is_empty()
+                || !relative
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            {
+                return Err(StorageError::InvalidKey(String::from(key)));
+            }
+
+            Ok(self.root.join(relative))
+        }
+    }
+
+    /// Runs blocking filesystem calls on the blocking thread pool instead of the runtime's
+    async fn blocking<T, F>(f: F) -> Result<T, StorageError>
+    where
+        F: FnOnce() -> Result<T, StorageError> + Send + 'static,
+        T: Send + 'static,
+    {
+        task::spawn_blocking(f)
+            .await
+            .map_err(|err| StorageError::IOError(io::Error::new(io::ErrorKind::Other, err)))?
+    }
+
+    /// The objects below `dir` as keys relative to `root`, leaving out objects still being
+    /// written
+    fn walk(root: &Path, dir: &Path, entries: &mut Vec<Entry>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                walk(root, &entry.path(), entries)?;
+            } else if let Ok(relative) = entry.path().strip_prefix(root) {
+                entries.push(Entry {
+                    key: relative
+                        .components()
+                        .map(|component| component.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    #[async_trait]
+    impl Storage for LocalStorage {
+        async fn get(&self, key: &str) -> Result<Option<Object>, StorageError> {
+            let path = self.path(key)?;
+            blocking(move || match fs::read(&path) {
+                Ok(data) => Ok(Some(Object {
+                    data,
+                    modified: fs::metadata(&path)?.modified().ok(),
+                })),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            })
+            .await
+        }
+
+        /// Writes next to the destination first and renames into place, so readers never see
+        /// a partial object.
+        async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StorageError> {
+            let path = self.path(key)?;
+            let dir = path.parent().unwrap_or(&self.root).to_path_buf();
+            blocking(move || {
+                fs::create_dir_all(&dir)?;
+
+                let mut file = tempfile::Builder::new()
+                    .prefix(TEMP_PREFIX)
+                    .tempfile_in(&dir)?;
+                file.write_all(&data)?;
+                file.persist(&path).map_err(|err| err.error)?;
+
+                Ok(())
+            })
+            .await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), StorageError> {
+            let path = self.path(key)?;
+            blocking(move || match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            })
+            .await
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<Entry>, StorageError> {
+            let root = self.root.clone();
+            let mut entries = blocking(move || {
+                let mut entries = Vec::new();
+                walk(&root, &root, &mut entries)?;
+
+                Ok(entries)
+            })
+            .await?;
+            entries.retain(|entry| entry.key.starts_with(prefix));
+
+            Ok(entries)
+        }
+    }
+
+    #[cfg(feature = "s3")]
+    mod s3 {
+        use super::{prefixed, Entry, Object, Storage, StorageError};
+        use async_trait::async_trait;
+        use rusoto_core::{Region, RusotoError};
+        use rusoto_s3::{
+            DeleteObjectRequest, GetObjectError, GetObjectRequest, ListObjectsV2Request,
+            PutObjectRequest, S3Client, S3,
+        };
+        use std::{fmt, time::SystemTime};
+        use tokio::io::AsyncReadExt;
+
+        /// Objects in an S3 bucket, with credentials and region from the usual `AWS_*`
+        /// environment variables
+        pub struct S3Storage {
+            client: S3Client,
+            bucket: String,
+            prefix: String,
+        }
+
+        impl fmt::Debug for S3Storage {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_struct("S3Storage")
+                    .field("bucket", &self.bucket)
+                    .field("prefix", &self.prefix)
+                    .finish()
+            }
+        }
+
+        impl S3Storage {
+            pub fn new(bucket: &str, prefix: &str) -> Self {
+                S3Storage {
+                    client: S3Client::new(Region::default()),
+                    bucket: String::from(bucket),
+                    prefix: String::from(prefix),
+                }
+            }
+        }
+
+        fn parse_date(date: &str) -> Option<SystemTime> {
+            chrono::DateTime::parse_from_rfc3339(date)
+                .or_else(|_| chrono::DateTime::parse_from_rfc2822(date))
+                .ok()
+                .map(SystemTime::from)
+        }
+
+        fn backend<E: std::error::Error + 'static>(err: RusotoError<E>) -> StorageError {
+            StorageError::Backend(err.to_string())
+        }
+
+        #[async_trait]
+        impl Storage for S3Storage {
+            async fn get(&self, key: &str) -> Result<Option<Object>, StorageError> {
+                let request = GetObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: prefixed(&self.prefix, key),
+                    ..Default::default()
+                };
+                match self.client.get_object(request).await {
+                    Ok(output) => {
+                        let mut data = Vec::new();
+                        if let Some(body) = output.body {
+                            body.into_async_read().read_to_end(&mut data).await?;
+                        }
+
+                        Ok(Some(Object {
+                            data,
+                            modified: output.last_modified.as_deref().and_then(parse_date),
+                        }))
+                    }
+                    Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => Ok(None),
+                    Err(err) => Err(backend(err)),
+                }
+            }
+
+            async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StorageError> {
+                let request = PutObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: prefixed(&self.prefix, key),
+                    content_length: Some(data.len() as i64),
+                    body: Some(data.into()),
+                    ..Default::default()
+                };
+                self.client.put_object(request).await.map_err(backend)?;
+
+                Ok(())
+            }
+
+            async fn delete(&self, key: &str) -> Result<(), StorageError> {
+                let request = DeleteObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: prefixed(&self.prefix, key),
+                    ..Default::default()
+                };
+                self.client.delete_object(request).await.map_err(backend)?;
+
+                Ok(())
+            }
+
+            async fn list(&self, prefix: &str) -> Result<Vec<Entry>, StorageError> {
+                let key_prefix = prefixed(&self.prefix, "");
+                let mut entries = Vec::new();
+                let mut continuation_token = None;
+
+                loop {
+                    let request = ListObjectsV2Request {
+                        bucket: self.bucket.clone(),
+                        prefix: Some(prefixed(&self.prefix, prefix)),
+                        continuation_token: continuation_token.take(),
+                        ..Default::default()
+                    };
+                    let output = self
+                        .client
+                        .list_objects_v2(request)
+                        .await
+                        .map_err(backend)?;
+
+                    for object in output.contents.unwrap_or_default() {
+                        if let Some(key) = object.key {
+                            entries.push(Entry {
+                                key: String::from(key.strip_prefix(&key_prefix).unwrap_or(&key)),
+                                size: object.size.unwrap_or_default() as u64,
+                                modified: object.last_modified.as_deref().and_then(parse_date),
+                            });
+                        }
+                    }
+
+                    match output.next_continuation_token {
+                        Some(token) if output.is_truncated == Some(true) => {
+                            continuation_token = Some(token)
+                        }
+                        _ => break,
+                    }
+                }
+
+                Ok(entries)
+            }
+        }
+    }
+
+    #[cfg(feature = "gcs")]
+    mod gcs {
+        use super::{prefixed, Entry, Object, Storage, StorageError};
+        use async_trait::async_trait;
+        use cloud_storage::{Client, ListRequest};
+        use std::{fmt, time::SystemTime};
+        use tokio_stream::StreamExt;
+
+        /// Objects in a Google Cloud Storage bucket, with credentials from
+        /// `SERVICE_ACCOUNT`/`GOOGLE_APPLICATION_CREDENTIALS`
+        pub struct GcsStorage {
+            client: Client,
+            bucket: String,
+            prefix: String,
+        }
+
+        impl fmt::Debug for GcsStorage {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_struct("GcsStorage")
+                    .field("bucket", &self.bucket)
+                    .field("prefix", &self.prefix)
+                    .finish()
+            }
+        }
+
+        impl GcsStorage {
+            pub fn new(bucket: &str, prefix: &str) -> Self {
+                GcsStorage {
+                    client: Client::default(),
+                    bucket: String::from(bucket),
+                    prefix: String::from(prefix),
+                }
+            }
+        }
+
+        fn is_not_found(err: &cloud_storage::Error) -> bool {
+            matches!(err, cloud_storage::Error::Google(response) if response.error.code == 404)
+        }
+
+        fn backend(err: cloud_storage::Error) -> StorageError {
+            StorageError::Backend(err.to_string())
+        }
+
+        #[async_trait]
+        impl Storage for GcsStorage {
+            async fn get(&self, key: &str) -> Result<Option<Object>, StorageError> {
+                let name = prefixed(&self.prefix, key);
+                let object = match self.client.object().read(&self.bucket, &name).await {
+                    Ok(object) => object,
+                    Err(err) if is_not_found(&err) => return Ok(None),
+                    Err(err) => return Err(backend(err)),
+                };
+                let data = self
+                    .client
+                    .object()
+                    .download(&self.bucket, &name)
+                    .await
+                    .map_err(backend)?;
+
+                Ok(Some(Object {
+                    data,
+                    modified: Some(SystemTime::from(object.updated)),
+                }))
+            }
+
+            async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StorageError> {
+                self.client
+                    .object()
+                    .create(
+                        &self.bucket,
+                        data,
+                        &prefixed(&self.prefix, key),
+                        "application/octet-stream",
+                    )
+                    .await
+                    .map_err(backend)?;
+
+                Ok(())
+            }
+
+            async fn delete(&self, key: &str) -> Result<(), StorageError> {
+                match self
+                    .client
+                    .object()
+                    .delete(&self.bucket, &prefixed(&self.prefix, key))
+                    .await
+                {
+                    Err(err) if !is_not_found(&err) => Err(backend(err)),
+                    _ => Ok(()),
+                }
+            }
+
+            async fn list(&self, prefix: &str) -> Result<Vec<Entry>, StorageError> {
+                let key_prefix = prefixed(&self.prefix, "");
+                let request = ListRequest {
+                    prefix: Some(prefixed(&self.prefix, prefix)),
+                    ..Default::default()
+                };
+                let mut pages = Box::pin(
+                    self.client
+                        .object()
+                        .list(&self.bucket, request)
+                        .await
+                        .map_err(backend)?,
+                );
+
+                let mut entries = Vec::new();
+                while let Some(page) = pages.next().await {
+                    for object in page.map_err(backend)?.items {
+                        entries.push(Entry {
+                            key: String::from(
+                                object
+                                    .name
+                                    .strip_prefix(&key_prefix)
+                                    .unwrap_or(&object.name),
+                            ),
+                            size: object.size,
+                            modified: Some(SystemTime::from(object.updated)),
+                        });
+                    }
+                }
+
+                Ok(entries)
+            }
+        }
+    }
+}
+
+mod cache {
+    use super::storage::{Entry, Storage, StorageError};
+    use log::warn;
+    use serde::Serialize;
+    use sha2::{Digest, Sha256};
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant, SystemTime},
+    };
+    use warp::hyper::body::Bytes;
+
+    /// Ending of the keys metadata is kept at next to the entry it's about
+    const METADATA_SUFFIX: &str = ".meta.json";
+
+    /// Downloads and shimmed buildpacks kept in a storage backend, so replicas of the service
+    /// share them and they survive restarts. Storage failures are logged and treated as
+    /// misses; the cache only ever makes a shim faster, never fail.
+    #[derive(Debug)]
+    pub struct Cache {
+        storage: Arc<dyn Storage>,
+        ttl: Option<Duration>,
+    }
+
+    impl Cache {
+        pub fn new(storage: Arc<dyn Storage>, ttl: Option<Duration>) -> Self {
+            Cache { storage, ttl }
+        }
+
+        /// Key of an upstream v2 buildpack tarball
+        pub fn download_key(url: &str) -> String {
+            format!("downloads/{}.tgz", digest(url.as_bytes()))
+        }
+
+        /// Key of a shimmed buildpack, derived from everything that goes into shimming it
+        pub fn artifact_key(fingerprint: &impl Serialize, extension: &str) -> String {
+            let fingerprint = serde_json::to_vec(fingerprint).unwrap_or_default();
+            format!("artifacts/{}.{}", digest(&fingerprint), extension)
+        }
+
+        /// Key of a shimmed buildpack by the file name its key ends in
+        pub fn artifact_file_key(file_name: &str) -> String {
+            format!("artifacts/{}", file_name)
+        }
+
+        /// Key of what's known about the entry at `key`, which is only a digest
+        pub fn metadata_key(key: &str) -> String {
+            format!("{}{}", key, METADATA_SUFFIX)
+        }
+
+        pub fn is_metadata_key(key: &str) -> bool {
+            key.ends_with(METADATA_SUFFIX)
+        }
+
+        pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
+            match self.storage.get(key).await {
+                Ok(Some(object)) if self.is_fresh(object.modified) => Some(object.data),
+                Ok(_) => None,
+                Err(err) => {
+                    warn!("Could not read {} from the cache: {}", key, err);
+                    None
+                }
+            }
+        }
+
+        pub async fn put(&self, key: &str, data: Vec<u8>) {
+            if let Err(err) = self.storage.put(key, data).await {
+                warn!("Could not write {} to the cache: {}", key, err);
+            }
+        }
+
+        /// Every cached download and shimmed buildpack, fresh or not
+        pub async fn entries(&self) -> Result<Vec<Entry>, StorageError> {
+            self.storage.list("").await
+        }
+
+        pub async fn remove(&self, key: &str) -> Result<(), StorageError> {
+            self.storage.delete(key).await
+        }
+
+        /// Removes every entry, or only those past the TTL when `expired_only`, and returns
+        /// how many were removed.
+        pub async fn purge(&self, expired_only: bool) -> Result<usize, StorageError> {
+            let mut removed = 0;
+            for entry in self.storage.list("").await? {
+                if expired_only && self.is_fresh(entry.modified) {
+                    continue;
+                }
+                self.storage.delete(&entry.key).await?;
+                removed += 1;
+            }
+
+            Ok(removed)
+        }
+
+        fn is_fresh(&self, modified: Option<SystemTime>) -> bool {
+            match (self.ttl, modified) {
+                (None, _) => true,
+                (Some(ttl), Some(modified)) => {
+                    modified.elapsed().map(|age| age <= ttl).unwrap_or(true)
+                }
+                (Some(_), None) => false,
+            }
+        }
+    }
+
+    /// Recently shimmed buildpacks kept in memory, by the same keys as in the `Cache`. The
+    /// least recently used go first once they take up more than `capacity` bytes. Entries are
+    /// shared with whoever gets them rather than copied.
+    #[derive(Debug)]
+    pub struct MemoryCache {
+        capacity: usize,
+        ttl: Option<Duration>,
+        lru: Mutex<Lru>,
+    }
+
+    #[derive(Debug, Default)]
+    struct Lru {
+        /// Data by key, with when it was added and when it was last used
+        entries: HashMap<String, (Instant, u64, Bytes)>,
+        /// Keys by when they were last used
+        order: BTreeMap<u64, String>,
+        size: usize,
+        clock: u64,
+    }
+
+    impl MemoryCache {
+        pub fn new(capacity: usize, ttl: Option<Duration>) -> Self {
+            MemoryCache {
+                capacity,
+                ttl,
+                lru: Mutex::new(Lru::default()),
+            }
+        }
+
+        pub fn get(&self, key: &str) -> Option<Bytes> {
+            let mut lru = self.lru.lock().unwrap_or_else(|err| err.into_inner());
+            let (added, _, _) = lru.entries.get(key)?;
+            if self.ttl.map_or(false, |ttl| added.elapsed() > ttl) {
+                lru.remove(key);
+                return None;
+            }
+
+            lru.clock += 1;
+            let Lru {
+                entries,
+                order,
+                clock,
+                ..
+            } = &mut *lru;
+            let (_, used, data) = entries.get_mut(key)?;
+            order.remove(&*used);
+            order.insert(*clock, String::from(key));
+            *used = *clock;
+
+            Some(data.clone())
+        }
+
+        /// Anything larger than the whole cache isn't kept
+        pub fn put(&self, key: &str, data: Bytes) {
+            if data.len() > self.capacity {
+                return;
+            }
+
+            let mut lru = self.lru.lock().unwrap_or_else(|err| err.into_inner());
+            lru.remove(key);
+            lru.clock += 1;
+            let clock = lru.clock;
+            lru.size += data.len();
+            lru.order.insert(clock, String::from(key));
+            lru.entries
+                .insert(String::from(key), (Instant::now(), clock, data));
+            while lru.size > self.capacity {
+                let oldest = match lru.order.values().next() {
+                    Some(oldest) => oldest.clone(),
+                    None => break,
+                };
+                lru.remove(&oldest);
+            }
+        }
+
+        /// Key, size and age of everything kept, expired or not
+        pub fn entries(&self) -> Vec<(String, usize, Duration)> {
+            let lru = self.lru.lock().unwrap_or_else(|err| err.into_inner());
+            lru.entries
+                .iter()
+                .map(|(key, (added, _, data))| (key.clone(), data.len(), added.elapsed()))
+                .collect()
+        }
+
+        /// Whether there was anything to remove
+        pub fn remove(&self, key: &str) -> bool {
+            let mut lru = self.lru.lock().unwrap_or_else(|err| err.into_inner());
+            let found = lru.entries.contains_key(key);
+            lru.remove(key);
+
+            found
+        }
+
+        /// Removes everything, or only what's past the TTL when `expired_only`, and returns
+        /// how many entries were removed.
+        pub fn purge(&self, expired_only: bool) -> usize {
+            let mut lru = self.lru.lock().unwrap_or_else(|err| err.into_inner());
+            let ttl = self.ttl;
+            let keys = lru
+                .entries
+                .iter()
+                .filter(|(_, (added, _, _))| {
+                    !expired_only || ttl.map_or(false, |ttl| added.elapsed() > ttl)
+                })
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            for key in &keys {
+                lru.remove(key);
+            }
+
+            keys.len()
+        }
+    }
+
+    impl Lru {
+        fn remove(&mut self, key: &str) {
+            if let Some((_, used, data)) = self.entries.remove(key) {
+                self.order.remove(&used);
+                self.size -= data.len();
+            }
+        }
+    }
+
+    fn digest(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+}
+
+mod filters {
+    use super::{
+        audit::AuditLog, callback::Notifier, handlers, models, shim::Shimmer, telemetry, tls,
+    };
+    use opentelemetry::Context;
+    use std::{
+        net::{IpAddr, SocketAddr},
+        sync::Arc,
+    };
+    use tokio_stream::StreamExt;
+    use warp::{hyper::body::Buf, Filter, Rejection, Reply};
+
+    /// Bytes a multipart upload may have on top of its tarball, for boundaries and part headers
+    const MULTIPART_OVERHEAD: u64 = 64 * 1024;
+    /// Header a callback secret is sent in, since query strings end up in access logs
+    const CALLBACK_SECRET_HEADER: &str = "x-cnb-shim-callback-secret";
+
+    pub fn routes(
+        shimmer: Arc<Shimmer>,
+        notifier: Arc<Notifier>,
+        audit_log: Option<Arc<AuditLog>>,
+        check_registry: bool,
+        batch_concurrency: usize,
+        admin_token: Option<String>,
+        trusted_proxies: Vec<IpAddr>,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        let admin_token = admin_token.map(Arc::new);
+        let trusted_proxies = Arc::new(trusted_proxies);
+
+        upload(
+            shimmer.clone(),
+            notifier.clone(),
+            audit_log.clone(),
+            trusted_proxies.clone(),
+        )
+        .or(batch(
+            shimmer.clone(),
+            audit_log.clone(),
+            batch_concurrency,
+            trusted_proxies.clone(),
+        ))
+        .or(migrate(
+            shimmer.clone(),
+            audit_log.clone(),
+            trusted_proxies.clone(),
+        ))
+        .or(project(
+            shimmer.clone(),
+            audit_log.clone(),
+            trusted_proxies.clone(),
+        ))
+        .or(artifact(shimmer.clone()))
+        .or(audit(audit_log.clone(), admin_token.clone()))
+        .or(stats(audit_log.clone(), admin_token.clone()))
+        .or(readiness(shimmer.clone(), check_registry))
+        .or(admin(shimmer.clone(), admin_token))
+        .or(shim(shimmer, notifier, audit_log, trusted_proxies))
+        .or(health())
+        .or(liveness())
+    }
+
+    /// GET /health
+    pub fn health() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("health")
+            .and(warp::get())
+            .and_then(handlers::health_check)
+    }
+
+    /// GET /healthz
+    pub fn liveness() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("healthz")
+            .and(warp::get())
+            .and_then(handlers::liveness)
+    }
+
+    /// GET /readyz
+    pub fn readiness(
+        shimmer: Arc<Shimmer>,
+        check_registry: bool,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("readyz")
+            .and(warp::get())
+            .and(with_shimmer(shimmer))
+            .and(warp::any().map(move || check_registry))
+            .and_then(handlers::readiness)
+    }
+
+    /// GET /v1/:namespace/:name
+    /// POST /v1/:namespace/:name
+    pub fn shim(
+        shimmer: Arc<Shimmer>,
+        notifier: Arc<Notifier>,
+        audit_log: Option<Arc<AuditLog>>,
+        trusted_proxies: Arc<Vec<IpAddr>>,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("v1" / String / String)
+            .and(shim_options())
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .and(client(trusted_proxies))
+            .and(trace_context())
+            .and(with_shimmer(shimmer))
+            .and(with_notifier(notifier))
+            .and(with_audit_log(audit_log))
+            .and_then(handlers::shim)
+            .recover(handlers::rejection)
+    }
+
+    /// POST /v1/upload
+    pub fn upload(
+        shimmer: Arc<Shimmer>,
+        notifier: Arc<Notifier>,
+        audit_log: Option<Arc<AuditLog>>,
+        trusted_proxies: Arc<Vec<IpAddr>>,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("v1" / "upload")
+            .and(warp::post())
+            .and(
+                warp::query::<models::UploadOptions>()
+                    .and(query_metadata())
+                    .map(|mut options: models::UploadOptions, metadata| {
+                        options.shim = with_query_metadata(options.shim, metadata);
+                        options
+                    }),
+            )
+            .and(callback_options())
+            .and(upload_body(shimmer.limits().download_size))
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .and(client(trusted_proxies))
+            .and(trace_context())
+            .and(with_shimmer(shimmer))
+            .and(with_notifier(notifier))
+            .and(with_audit_log(audit_log))
+            .and_then(handlers::upload)
+            .recover(handlers::rejection)
+    }
+
+    /// POST /v1/batch
+    pub fn batch(
+        shimmer: Arc<Shimmer>,
+        audit_log: Option<Arc<AuditLog>>,
+        concurrency: usize,
+        trusted_proxies: Arc<Vec<IpAddr>>,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("v1" / "batch")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(1024 * 64))
+            .and(warp::body::json::<models::BatchRequest>())
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .and(client(trusted_proxies))
+            .and(trace_context())
+            .and(with_shimmer(shimmer))
+            .and(with_audit_log(audit_log))
+            .and(warp::any().map(move || concurrency))
+            .and_then(handlers::batch)
+            .recover(handlers::rejection)
+    }
+
+    /// GET /v1/artifacts/:file
+    pub fn artifact(
+        shimmer: Arc<Shimmer>,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("v1" / "artifacts" / String)
+            .and(warp::get())
+            .and(with_shimmer(shimmer))
+            .and_then(handlers::artifact)
+            .recover(handlers::rejection)
+    }
+
+    /// POST /v1/migrate
+    pub fn migrate(
+        shimmer: Arc<Shimmer>,
+        audit_log: Option<Arc<AuditLog>>,
+        trusted_proxies: Arc<Vec<IpAddr>>,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("v1" / "migrate")
+            .and(warp::post())
+            .and(warp::query::<models::MigrateOptions>())
+            .and(warp::body::content_length_limit(1024 * 64))
+            .and(warp::body::bytes())
+            .and(client(trusted_proxies))
+            .and(trace_context())
+            .and(with_shimmer(shimmer))
+            .and(with_audit_log(audit_log))
+            .and_then(handlers::migrate)
+            .recover(handlers::rejection)
+    }
+
+    /// POST /v1/project
+    pub fn project(
+        shimmer: Arc<Shimmer>,
+        audit_log: Option<Arc<AuditLog>>,
+        trusted_proxies: Arc<Vec<IpAddr>>,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("v1" / "project")
+            .and(warp::post())
+            .and(warp::query::<models::ShimOptions>())
+            .and(warp::body::content_length_limit(1024 * 64))
+            .and(warp::body::bytes())
+            .and(client(trusted_proxies))
+            .and(trace_context())
+            .and(with_shimmer(shimmer))
+            .and(with_audit_log(audit_log))
+            .and_then(handlers::project)
+            .recover(handlers::rejection)
+    }
+
+    /// GET /v1/audit
+    pub fn audit(
+        audit_log: Option<Arc<AuditLog>>,
+        token: Option<Arc<String>>,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("v1" / "audit")
+            .and(warp::get())
+            .and(authorized(token))
+            .and(warp::query::<super::audit::AuditQuery>())
+            .and(with_audit_log(audit_log))
+            .and_then(handlers::audit)
+            .recover(handlers::rejection)
+    }
+
+    /// GET /v1/stats
+    pub fn stats(
+        audit_log: Option<Arc<AuditLog>>,
+        token: Option<Arc<String>>,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        warp::path!("v1" / "stats")
+            .and(warp::get())
+            .and(authorized(token))
+            .and(warp::query::<super::audit::StatsQuery>())
+            .and(with_audit_log(audit_log))
+            .and_then(handlers::stats)
+            .recover(handlers::rejection)
+    }
+
+    /// GET /admin/cache
+    /// DELETE /admin/cache
+    /// DELETE /admin/cache/:key
+    /// POST /admin/gc
+    pub fn admin(
+        shimmer: Arc<Shimmer>,
+        token: Option<Arc<String>>,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        let entries = warp::path!("cache")
+            .and(warp::get())
+            .and(with_shimmer(shimmer.clone()))
+            .and_then(handlers::cache_entries);
+        let clear = warp::path!("cache")
+            .and(warp::delete())
+            .and(with_shimmer(shimmer.clone()))
+            .and_then(handlers::clear_cache);
+        let remove = warp::path("cache")
+            .and(warp::delete())
+            .and(warp::path::tail())
+            .and(with_shimmer(shimmer.clone()))
+            .and_then(handlers::remove_cache_entry);
+        let gc = warp::path!("gc")
+            .and(warp::post())
+            .and(with_shimmer(shimmer))
+            .and_then(handlers::gc);
+
+        // matched on the path first, so other routes never see the authorization rejection
+        warp::path("admin")
+            .and(authorized(token))
+            .and(entries.or(clear).or(remove).or(gc))
+            .recover(handlers::rejection)
+    }
+
+    /// Requests carrying the admin token as a bearer token
+    fn authorized(
+        token: Option<Arc<String>>,
+    ) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        warp::header::optional::<String>("authorization")
+            .and(warp::any().map(move || token.clone()))
+            .and_then(handlers::authorize)
+            .untuple_one()
+    }
+
+    /// A multipart form with a `buildpack` part, refused upfront when it's larger than
+    /// `max_size` allows, or the tarball as the raw body. Neither is read yet.
+    fn upload_body(
+        max_size: u64,
+    ) -> impl Filter<Extract = (handlers::Upload,), Error = Rejection> + Clone {
+        warp::multipart::form()
+            .max_length(max_size + MULTIPART_OVERHEAD)
+            .map(handlers::Upload::Form)
+            .or(not_multipart()
+                .and(warp::header::optional::<u64>("content-length"))
+                .and(warp::body::stream())
+                .map(|length, body| {
+                    let body = body.map(|chunk: Result<_, warp::Error>| {
+                        chunk.map(|mut buf| buf.copy_to_bytes(buf.remaining()))
+                    });
+                    handlers::Upload::Raw(length, Box::pin(body))
+                }))
+            .unify()
+    }
+
+    /// Lets through anything but multipart forms, so a form that isn't accepted isn't taken
+    /// for a raw tarball instead
+    fn not_multipart() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        warp::header::optional::<String>("content-type")
+            .and_then(|content_type: Option<String>| async move {
+                match content_type {
+                    Some(content_type)
+                        if content_type
+                            .to_ascii_lowercase()
+                            .starts_with("multipart/form-data") =>
+                    {
+                        Err(warp::reject())
+                    }
+                    _ => Ok(()),
+                }
+            })
+            .untuple_one()
+    }
+
+    /// Shim and callback options from the query string of a GET or the JSON body of a POST
+    fn shim_options(
+    ) -> impl Filter<Extract = (models::ShimOptions, models::CallbackOptions), Error = Rejection> + Clone
+    {
+        warp::get()
+            .and(warp::query::<models::ShimOptions>())
+            .and(query_metadata())
+            .map(with_query_metadata)
+            .and(callback_options())
+            .or(warp::post()
+                .and(warp::body::content_length_limit(1024 * 16))
+                .and(warp::body::json::<models::ShimBody>())
+                .and(callback_options())
+                .map(|body: models::ShimBody, query: models::CallbackOptions| {
+                    let callback = models::CallbackOptions {
+                        callback_url: body.callback.callback_url.or(query.callback_url),
+                        callback_secret: query.callback_secret.or(body.callback.callback_secret),
+                    };
+                    (body.shim, callback)
+                })
+                .untuple_one())
+            .unify()
+    }
+
+    /// Callback options from the query string, with the secret from its header
+    fn callback_options(
+    ) -> impl Filter<Extract = (models::CallbackOptions,), Error = Rejection> + Clone {
+        warp::query::<models::CallbackOptions>()
+            .and(warp::header::optional::<String>(CALLBACK_SECRET_HEADER))
+            .and_then(handlers::callback_options)
+    }
+
+    /// Metadata from `metadata.<key>=<value>` query parameters
+    fn query_metadata() -> impl Filter<
+        Extract = (Option<serde_json::Map<String, serde_json::Value>>,),
+        Error = Rejection,
+    > + Clone {
+        warp::query::<Vec<(String, String)>>().map(|query: Vec<(String, String)>| {
+            models::metadata_from_pairs(query.into_iter().filter_map(|(key, value)| {
+                key.strip_prefix("metadata.")
+                    .map(|key| (String::from(key), value))
+            }))
+        })
+    }
+
+    fn with_query_metadata(
+        mut options: models::ShimOptions,
+        metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    ) -> models::ShimOptions {
+        options.metadata = options.metadata.or(metadata);
+        options
+    }
+
+    fn with_shimmer(
+        shimmer: Arc<Shimmer>,
+    ) -> impl Filter<Extract = (Arc<Shimmer>,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || shimmer.clone())
+    }
+
+    /// The client of a request, which is the address connecting unless that's a trusted proxy.
+    /// Behind one, it's the last `X-Forwarded-For` address that isn't another trusted proxy,
+    /// since whatever comes before could have been made up by the client.
+    fn client(
+        trusted_proxies: Arc<Vec<IpAddr>>,
+    ) -> impl Filter<Extract = (Option<String>,), Error = std::convert::Infallible> + Clone {
+        warp::header::optional::<String>("x-forwarded-for")
+            .or(warp::any().map(|| None))
+            .unify()
+            .and(remote())
+            .map(
+                move |forwarded_for: Option<String>, remote: Option<SocketAddr>| {
+                    let remote = remote?.ip();
+                    if !trusted_proxies.contains(&remote) {
+                        return Some(remote.to_string());
+                    }
+
+                    forwarded_for
+                        .as_deref()
+                        .unwrap_or_default()
+                        .rsplit(',')
+                        .map(str::trim)
+                        .filter(|client| !client.is_empty())
+                        .find(|client| {
+                            client
+                                .parse::<IpAddr>()
+                                .map_or(true, |client| !trusted_proxies.contains(&client))
+                        })
+                        .map(String::from)
+                        .or_else(|| Some(remote.to_string()))
+                },
+            )
+    }
+
+    /// Address of the peer connecting, as warp knows it or else as the TLS listener recorded it
+    fn remote(
+    ) -> impl Filter<Extract = (Option<SocketAddr>,), Error = std::convert::Infallible> + Clone
+    {
+        warp::addr::remote()
+            .and(
+                warp::ext::get::<tls::RemoteAddr>()
+                    .map(Some)
+                    .or(warp::any().map(|| None))
+                    .unify(),
+            )
+            .map(|remote: Option<SocketAddr>, tls: Option<tls::RemoteAddr>| {
+                remote.or_else(|| tls.map(|tls| tls.0))
+            })
+    }
+
+    /// The trace context a request was sent with, so its spans join the caller's trace
+    fn trace_context() -> impl Filter<Extract = (Context,), Error = std::convert::Infallible> + Clone
+    {
+        warp::header::headers_cloned().map(|headers: http::HeaderMap| telemetry::extract(&headers))
+    }
+
+    fn with_audit_log(
+        audit_log: Option<Arc<AuditLog>>,
+    ) -> impl Filter<Extract = (Option<Arc<AuditLog>>,), Error = std::convert::Infallible> + Clone
+    {
+        warp::any().map(move || audit_log.clone())
+    }
+
+    fn with_notifier(
+        notifier: Arc<Notifier>,
+    ) -> impl Filter<Extract = (Arc<Notifier>,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || notifier.clone())
+    }
+}
+
+#[cfg(feature = "grpc")]
+mod grpc {
+    use super::{
+        audit::AuditLog,
+        callback, models,
+        shim::{Artifact, ShimError, Shimmer, Source},
+    };
+    use sha2::{Digest, Sha256};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::{Request, Response, Status};
+
+    pub mod proto {
+        tonic::include_proto!("cnb_shim.v1");
+    }
+
+    use proto::{job_status_response::State, shim_response::Payload, shim_server};
+
+    /// Size of the archive chunks streamed back by `Shim`
+    const CHUNK_SIZE: usize = 64 * 1024;
+    /// How long finished `Publish` jobs can still be looked up
+    const JOB_TTL: Duration = Duration::from_secs(60 * 60);
+    /// `Publish` jobs kept at most, running or finished
+    const MAX_JOBS: usize = 1024;
+
+    /// The gRPC flavor of the shim API, backed by the same [`Shimmer`] and audit log as the
+    /// HTTP routes. There are no callbacks, the caller gets the outcome on the stream it's
+    /// waiting on, or from `JobStatus` for what it published.
+    #[derive(Debug)]
+    pub struct ShimService {
+        shimmer: Arc<Shimmer>,
+        audit_log: Option<Arc<AuditLog>>,
+        external_url: Option<String>,
+        jobs: Arc<Mutex<HashMap<String, Job>>>,
+    }
+
+    /// A `Publish` job as reported by `JobStatus`, and when it finished if it did
+    #[derive(Debug)]
+    struct Job {
+        status: proto::JobStatusResponse,
+        finished: Option<Instant>,
+    }
+
+    impl Job {
+        fn expired(&self) -> bool {
+            self.finished
+                .map_or(false, |finished| finished.elapsed() >= JOB_TTL)
+        }
+    }
+
+    /// A buildpack to shim, checked as far as it can be before shimming it
+    #[derive(Debug)]
+    struct Requested {
+        id: String,
+        source: Source,
+        options: models::ShimOptions,
+        format: models::ArchiveFormat,
+    }
+
+    impl Requested {
+        fn new(request: proto::ShimRequest, shimmer: &Shimmer) -> Result<Self, Status> {
+            let source = if request.url.is_empty() {
+                Source::Registry
+            } else {
+                Source::Url(request.url)
+            };
+            shimmer.check_source(&source).map_err(status)?;
+            let format = match proto::ArchiveFormat::from_i32(request.format) {
+                Some(proto::ArchiveFormat::TarZst) => models::ArchiveFormat::TarZst,
+                Some(proto::ArchiveFormat::Tar) => models::ArchiveFormat::Tar,
+                Some(proto::ArchiveFormat::TarGz) => models::ArchiveFormat::TarGz,
+                None => return Err(Status::invalid_argument("unknown archive format")),
+            };
+
+            Ok(Requested {
+                id: request.id,
+                source,
+                options: shim_options(request.options.unwrap_or_default(), format),
+                format,
+            })
+        }
+
+        async fn shim(
+            self,
+            shimmer: &Shimmer,
+            audit_log: Option<&AuditLog>,
+            client: Option<&str>,
+        ) -> Result<Artifact, ShimError> {
+            let started = Instant::now();
+            let result = shimmer
+                .shim(&self.id, &self.source, self.options, self.format)
+                .await;
+            if let Some(audit_log) = audit_log {
+                audit_log
+                    .record(
+                        &self.id,
+                        self.source.kind(),
+                        client,
+                        started.elapsed(),
+                        &result,
+                    )
+                    .await;
+            }
+
+            result
+        }
+    }
+
+    impl ShimService {
+        pub fn new(
+            shimmer: Arc<Shimmer>,
+            audit_log: Option<Arc<AuditLog>>,
+            external_url: Option<String>,
+        ) -> Self {
+            ShimService {
+                shimmer,
+                audit_log,
+                external_url,
+                jobs: Arc::new(Mutex::new(HashMap::new())),
+            }
+        }
+
+        pub fn into_server(self) -> shim_server::ShimServer<Self> {
+            shim_server::ShimServer::new(self)
+        }
+    }
+
+    #[tonic::async_trait]
+    impl shim_server::Shim for ShimService {
+        type ShimStream = ReceiverStream<Result<proto::ShimResponse, Status>>;
+
+        async fn shim(
+            &self,
+            request: Request<proto::ShimRequest>,
+        ) -> Result<Response<Self::ShimStream>, Status> {
+            let client = request.remote_addr().map(|addr| addr.ip().to_string());
+            let requested = Requested::new(request.into_inner(), &self.shimmer)?;
+            let format = requested.format;
+            let artifact = requested
+                .shim(&self.shimmer, self.audit_log.as_deref(), client.as_deref())
+                .await
+                .map_err(status)?;
+
+            let (tx, rx) = mpsc::channel(4);
+            tokio::spawn(async move {
+                let messages = std::iter::once(Payload::Artifact(artifact_info(&artifact, format)))
+                    .chain(
+                        artifact
+                            .data
+                            .chunks(CHUNK_SIZE)
+                            .map(|chunk| Payload::Chunk(chunk.to_vec())),
+                    );
+                for payload in messages {
+                    let response = proto::ShimResponse {
+                        payload: Some(payload),
+                    };
+                    // the client went away
+                    if tx.send(Ok(response)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+            Ok(Response::new(ReceiverStream::new(rx)))
+        }
+
+        async fn resolve(
+            &self,
+            request: Request<proto::ResolveRequest>,
+        ) -> Result<Response<proto::ResolveResponse>, Status> {
+            let release = self
+                .shimmer
+                .resolve(&request.into_inner().id)
+                .await
+                .map_err(status)?;
+
+            Ok(Response::new(proto::ResolveResponse {
+                version: release.version(),
+                tarball_url: release.tar_link,
+            }))
+        }
+
+        async fn publish(
+            &self,
+            request: Request<proto::ShimRequest>,
+        ) -> Result<Response<proto::PublishResponse>, Status> {
+            // published buildpacks are served from the cache, there's nowhere else to put them
+            if self.shimmer.cache().is_none() {
+                return Err(Status::failed_precondition(
+                    "publishing needs a cache, set --storage",
+                ));
+            }
+            let client = request.remote_addr().map(|addr| addr.ip().to_string());
+            let requested = Requested::new(request.into_inner(), &self.shimmer)?;
+
+            let job_id = uuid::Uuid::new_v4().to_string();
+            {
+                let mut jobs = self.jobs.lock().unwrap_or_else(|err| err.into_inner());
+                jobs.retain(|_, job| !job.expired());
+                if jobs.len() >= MAX_JOBS {
+                    return Err(Status::resource_exhausted(
+                        "too many publish jobs, try again later",
+                    ));
+                }
+                jobs.insert(
+                    job_id.clone(),
+                    Job {
+                        status: proto::JobStatusResponse::default(),
+                        finished: None,
+                    },
+                );
+            }
+
+            let (shimmer, audit_log) = (self.shimmer.clone(), self.audit_log.clone());
+            let (external_url, jobs, id) =
+                (self.external_url.clone(), self.jobs.clone(), job_id.clone());
+            tokio::spawn(async move {
+                let format = requested.format;
+                let job_status = match requested
+                    .shim(&shimmer, audit_log.as_deref(), client.as_deref())
+                    .await
+                {
+                    Ok(artifact) => proto::JobStatusResponse {
+                        state: State::Succeeded as i32,
+                        artifact: Some(artifact_info(&artifact, format)),
+                        artifact_url: external_url
+                            .and_then(|url| callback::artifact_url(&url, &artifact))
+                            .unwrap_or_default(),
+                        ..Default::default()
+                    },
+                    Err(err) => {
+                        let failure = status(err);
+                        proto::JobStatusResponse {
+                            state: State::Failed as i32,
+                            code: failure.code() as i32,
+                            error: String::from(failure.message()),
+                            ..Default::default()
+                        }
+                    }
+                };
+                jobs.lock().unwrap_or_else(|err| err.into_inner()).insert(
+                    id,
+                    Job {
+                        status: job_status,
+                        finished: Some(Instant::now()),
+                    },
+                );
+            });
+
+            Ok(Response::new(proto::PublishResponse { job_id }))
+        }
+
+        async fn job_status(
+            &self,
+            request: Request<proto::JobStatusRequest>,
+        ) -> Result<Response<proto::JobStatusResponse>, Status> {
+            let job_id = request.into_inner().job_id;
+            let jobs = self.jobs.lock().unwrap_or_else(|err| err.into_inner());
+
+            match jobs.get(&job_id).filter(|job| !job.expired()) {
+                Some(job) => Ok(Response::new(job.status.clone())),
+                None => Err(Status::not_found(format!("no publish job {}", job_id))),
+            }
+        }
+    }
+
+    fn artifact_info(artifact: &Artifact, format: models::ArchiveFormat) -> proto::Artifact {
+        proto::Artifact {
+            id: artifact.id.clone(),
+            version: artifact.version.clone(),
+            digest: format!("sha256:{}", hex::encode(Sha256::digest(&artifact.data))),
+            size: artifact.data.len() as u64,
+            content_type: String::from(format.content_type()),
+        }
+    }
+
+    fn shim_options(
+        options: proto::ShimOptions,
+        format: models::ArchiveFormat,
+    ) -> models::ShimOptions {
+        let non_empty = |value: String| Some(value).filter(|value| !value.is_empty());
+        let non_empty_list = |values: Vec<String>| Some(values).filter(|values| !values.is_empty());
+
+        models::ShimOptions {
+            version: non_empty(options.version),
+            name: non_empty(options.name),
+            api: non_empty(options.api),
+            stacks: non_empty_list(options.stacks),
+            targets: non_empty_list(options.targets),
+            homepage: non_empty(options.homepage),
+            description: non_empty(options.description),
+            keywords: non_empty_list(options.keywords),
+            licenses: non_empty_list(options.licenses),
+            format: Some(format),
+            exclude: non_empty_list(options.exclude),
+            strip_vcs: Some(options.strip_vcs).filter(|strip_vcs| *strip_vcs),
+            shebang: non_empty(options.shebang),
+            metadata: models::metadata_from_pairs(options.metadata),
+        }
+    }
+
+    fn status(err: ShimError) -> Status {
+        match err {
+            ShimError::BadRequest(message) => Status::invalid_argument(message),
+            ShimError::Unavailable(message) => Status::unavailable(message),
+            ShimError::InsufficientStorage(message) => Status::resource_exhausted(message),
+            ShimError::PayloadTooLarge(message) => Status::resource_exhausted(message),
+            ShimError::Unprocessable(message) => Status::failed_precondition(message),
+            ShimError::Internal(message) => Status::internal(message),
+        }
+    }
+}
+
+mod health {
+    use super::shim::Shimmer;
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+    use tokio::task;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Status {
+        Ok,
+        Fail,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Check {
+        pub status: Status,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub message: Option<String>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Report {
+        pub status: Status,
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        pub checks: BTreeMap<&'static str, Check>,
+    }
+
+    impl Default for Report {
+        fn default() -> Self {
+            Report {
+                status: Status::Ok,
+                checks: BTreeMap::new(),
+            }
+        }
+    }
+
+    impl Report {
+        pub fn is_ok(&self) -> bool {
+            self.status == Status::Ok
+        }
+
+        fn record(&mut self, name: &'static str, result: Result<(), String>) {
+            let check = match result {
+                Ok(()) => Check {
+                    status: Status::Ok,
+                    message: None,
+                },
+                Err(message) => {
+                    self.status = Status::Fail;
+                    Check {
+                        status: Status::Fail,
+                        message: Some(message),
+                    }
+                }
+            };
+            self.checks.insert(name, check);
+        }
+    }
+
+    /// Checks everything a shim needs: the shim scripts, a writable work directory with room
+    /// to spare and, optionally, a reachable registry.
+    pub async fn readiness(shimmer: &Shimmer, check_registry: bool) -> Report {
+        let mut report = Report::default();
+
+        // both stat and write to disk, which is left to the blocking thread pool
+        let scripts = shimmer.scripts().clone();
+        let work_dir = shimmer.janitor().work_dir().to_path_buf();
+        let (scripts, work_dir) = task::spawn_blocking(move || {
+            (
+                scripts.check().map_err(|err| err.to_string()),
+                tempfile::tempfile_in(work_dir)
+                    .map(|_| ())
+                    .map_err(|err| format!("work directory is not writable: {}", err)),
+            )
+        })
+        .await
+        .unwrap_or_else(|err| {
+            let failed = Err(format!("check failed: {}", err));
+            (failed.clone(), failed)
+        });
+        report.record("scripts", scripts);
+        report.record("work_dir", work_dir);
+        report.record(
+            "disk_space",
+            shimmer.janitor().check().map_err(|err| err.to_string()),
+        );
+        if check_registry {
+            report.record(
+                "registry",
+                shimmer
+                    .registry()
+                    .ping()
+                    .await
+                    .map_err(|err| format!("registry is unreachable: {}", err)),
+            );
+        }
+
+        report
+    }
+}
+
+mod callback {
+    use super::{
+        hosts::AllowedHosts,
+        models,
+        shim::{Artifact, ShimError},
+    };
+    use hmac::{Hmac, Mac, NewMac};
+    use log::{info, warn};
+    use serde::Serialize;
+    use sha2::{Digest, Sha256};
+    use std::time::Duration;
+
+    /// Header carrying the `sha256=<hex>` HMAC of the notification body
+    const SIGNATURE_HEADER: &str = "X-Cnb-Shim-Signature";
+    /// Attempts at delivering a notification before giving up on it
+    const MAX_ATTEMPTS: u32 = 3;
+
+    /// Where to deliver the notification for a single shim
+    #[derive(Debug)]
+    pub struct Callback {
+        url: String,
+        secret: Option<String>,
+    }
+
+    impl Callback {
+        /// A callback from the request's options, if it asked for one. The URL has to be on an
+        /// allowed host like any tarball URL, since the service posts signed results to it.
+        pub fn from_options(
+            options: models::CallbackOptions,
+            allowed_hosts: &AllowedHosts,
+        ) -> Result<Option<Self>, String> {
+            let url = match options.callback_url {
+                Some(url) => url,
+                None if options.callback_secret.is_some() => {
+                    return Err(String::from("callback_secret requires a callback_url"))
+                }
+                None => return Ok(None),
+            };
+            allowed_hosts
+                .check(&url)
+                .map_err(|err| format!("invalid callback_url: {}", err))?;
+
+            Ok(Some(Callback {
+                url,
+                secret: options.callback_secret,
+            }))
+        }
+    }
+
+    #[derive(Debug, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Status {
+        Succeeded,
+        Failed,
+    }
+
+    /// What gets POSTed to a callback URL
+    #[derive(Debug, Serialize)]
+    pub struct Notification {
+        pub id: String,
+        pub status: Status,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub version: Option<String>,
+        /// `sha256:<hex>` of the shimmed buildpack
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub checksum: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub artifact_url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub error: Option<String>,
+    }
+
+    /// Delivers callback notifications in the background, so they never hold up a response.
+    #[derive(Debug)]
+    pub struct Notifier {
+        client: reqwest::Client,
+        external_url: Option<String>,
+    }
+
+    impl Notifier {
+        pub fn new(external_url: Option<String>, client: reqwest::Client) -> Self {
+            Notifier {
+                client,
+                external_url,
+            }
+        }
+
+        pub fn notify(&self, callback: Callback, id: &str, result: &Result<Artifact, ShimError>) {
+            let notification = match result {
+                Ok(artifact) => Notification {
+                    id: String::from(id),
+                    status: Status::Succeeded,
+                    version: Some(artifact.version.clone()),
+                    checksum: Some(format!(
+                        "sha256:{}",
+                        hex::encode(Sha256::digest(&artifact.data))
+                    )),
+                    artifact_url: self.artifact_url(artifact),
+                    error: None,
+                },
+                Err(err) => Notification {
+                    id: String::from(id),
+                    status: Status::Failed,
+                    version: None,
+                    checksum: None,
+                    artifact_url: None,
+                    error: Some(err.to_string()),
+                },
+            };
+
+            tokio::spawn(deliver(self.client.clone(), callback, notification));
+        }
+
+        fn artifact_url(&self, artifact: &Artifact) -> Option<String> {
+            artifact_url(self.external_url.as_deref()?, artifact)
+        }
+    }
+
+    /// Where `artifact` is served from by GET /v1/artifacts. Artifacts can only be linked to
+    /// when they're cached and the service knows its URL.
+    pub fn artifact_url(external_url: &str, artifact: &Artifact) -> Option<String> {
+        let file_name = artifact.key.as_ref()?.rsplit('/').next()?;
+
+        Some(format!(
+            "{}/v1/artifacts/{}",
+            external_url.trim_end_matches('/'),
+            file_name
+        ))
+    }
+
+    async fn deliver(client: reqwest::Client, callback: Callback, notification: Notification) {
+        let body = match serde_json::to_vec(&notification) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!(
+                    "Could not serialize callback for {}: {}",
+                    notification.id, err
+                );
+                return;
+            }
+        };
+        let signature = callback.secret.as_ref().map(|secret| sign(secret, &body));
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut request = client
+                .post(&callback.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .timeout(Duration::from_secs(10))
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            match request.send().await.and_then(|res| res.error_for_status()) {
+                Ok(_) => {
+                    info!("notified {} about {}", callback.url, notification.id);
+                    return;
+                }
+                Err(err) => warn!(
+                    "Callback to {} failed (attempt {}/{}): {}",
+                    callback.url, attempt, MAX_ATTEMPTS, err
+                ),
+            }
+            if attempt < MAX_ATTEMPTS {
+                tokio::time::sleep(Duration::from_secs(2u64.pow(attempt))).await;
+            }
+        }
+    }
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(body);
+
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+}
+
+mod audit {
+    use super::shim::{Artifact, ShimError};
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use log::warn;
+    use serde::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+    use std::{str::FromStr, time::Duration};
+    use thiserror::Error;
+
+    const DEFAULT_LIMIT: i64 = 50;
+    const MAX_LIMIT: i64 = 500;
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS shims (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            created_at TEXT NOT NULL,
+            buildpack_id TEXT NOT NULL,
+            version TEXT,
+            source TEXT NOT NULL,
+            client TEXT,
+            duration_ms INTEGER NOT NULL,
+            result TEXT NOT NULL,
+            error TEXT,
+            digest TEXT
+        );
+        CREATE INDEX IF NOT EXISTS shims_buildpack_id ON shims (buildpack_id);
+        CREATE INDEX IF NOT EXISTS shims_created_at ON shims (created_at);
+    ";
+
+    /// A shim as recorded in the audit log
+    #[derive(Debug, Serialize, sqlx::FromRow)]
+    pub struct Entry {
+        pub id: i64,
+        pub created_at: String,
+        pub buildpack_id: String,
+        pub version: Option<String>,
+        /// `registry`, `url` or `upload`
+        pub source: String,
+        pub client: Option<String>,
+        pub duration_ms: i64,
+        /// `succeeded` or `failed`
+        pub result: String,
+        pub error: Option<String>,
+        /// `sha256:<hex>` of the shimmed buildpack
+        pub digest: Option<String>,
+    }
+
+    /// How often a buildpack was shimmed
+    #[derive(Debug, Serialize, sqlx::FromRow)]
+    pub struct Stat {
+        pub buildpack_id: String,
+        pub shims: i64,
+        pub failures: i64,
+        pub last_shimmed_at: String,
+    }
+
+    /// Filters and pagination of GET /v1/audit
+    #[derive(Debug, Default, Deserialize)]
+    pub struct AuditQuery {
+        pub buildpack_id: Option<String>,
+        pub result: Option<String>,
+        /// RFC 3339 timestamps
+        pub since: Option<String>,
+        pub until: Option<String>,
+        pub limit: Option<i64>,
+        pub offset: Option<i64>,
+    }
+
+    /// Filters and pagination of GET /v1/stats
+    #[derive(Debug, Default, Deserialize)]
+    pub struct StatsQuery {
+        pub since: Option<String>,
+        pub until: Option<String>,
+        pub limit: Option<i64>,
+        pub offset: Option<i64>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Page<T> {
+        pub items: Vec<T>,
+        pub limit: i64,
+        pub offset: i64,
+        /// Where the next page starts, if there might be one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub next_offset: Option<i64>,
+    }
+
+    impl<T> Page<T> {
+        fn new(items: Vec<T>, limit: i64, offset: i64) -> Self {
+            let next_offset = if items.len() as i64 == limit {
+                Some(offset + limit)
+            } else {
+                None
+            };
+
+            Page {
+                items,
+                limit,
+                offset,
+                next_offset,
+            }
+        }
+    }
+
+    /// Every shim the service ran, persisted in SQLite.
+    #[derive(Debug)]
+    pub struct AuditLog {
+        pool: SqlitePool,
+    }
+
+    impl AuditLog {
+        /// Opens the database at a `sqlite://` URL, creating it and its schema as needed
+        pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
+            let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+            let pool = SqlitePoolOptions::new()
+                .max_connections(4)
+                .connect_with(options)
+                .await?;
+            sqlx::query(SCHEMA).execute(&pool).await?;
+
+            Ok(AuditLog { pool })
+        }
+
+        /// Records the outcome of shimming `buildpack_id`, logging rather than failing the
+        /// request when it can't.
+        pub async fn record(
+            &self,
+            buildpack_id: &str,
+            source: &str,
+            client: Option<&str>,
+            duration: Duration,
+            result: &Result<Artifact, ShimError>,
+        ) {
+            let (version, outcome, error, digest) = match result {
+                Ok(artifact) => (
+                    Some(artifact.version.as_str()),
+                    "succeeded",
+                    None,
+                    Some(format!(
+                        "sha256:{}",
+                        hex::encode(Sha256::digest(&artifact.data))
+                    )),
+                ),
+                Err(err) => (None, "failed", Some(err.to_string()), None),
+            };
+
+            let recorded = sqlx::query(
+                "INSERT INTO shims
+                    (created_at, buildpack_id, version, source, client, duration_ms, result, error, digest)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(timestamp(Utc::now()))
+            .bind(buildpack_id)
+            .bind(version)
+            .bind(source)
+            .bind(client)
+            .bind(duration.as_millis() as i64)
+            .bind(outcome)
+            .bind(error)
+            .bind(digest)
+            .execute(&self.pool)
+            .await;
+            if let Err(err) = recorded {
+                warn!(
+                    "Could not record shim of {} in the audit log: {}",
+                    buildpack_id, err
+                );
+            }
+        }
+
+        /// Recorded shims, most recent first
+        pub async fn entries(&self, query: AuditQuery) -> Result<Page<Entry>, AuditError> {
+            let (limit, offset) = pagination(query.limit, query.offset)?;
+            let entries = sqlx::query_as::<_, Entry>(
+                "SELECT * FROM shims
+                WHERE (?1 IS NULL OR buildpack_id = ?1)
+                    AND (?2 IS NULL OR result = ?2)
+                    AND (?3 IS NULL OR created_at >= ?3)
+                    AND (?4 IS NULL OR created_at < ?4)
+                ORDER BY id DESC
+                LIMIT ?5 OFFSET ?6",
+            )
+            .bind(query.buildpack_id)
+            .bind(query.result)
+            .bind(parse_timestamp(query.since)?)
+            .bind(parse_timestamp(query.until)?)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await?;
+
+            Ok(Page::new(entries, limit, offset))
+        }
+
+        /// Shims per buildpack, most shimmed first
+        pub async fn stats(&self, query: StatsQuery) -> Result<Page<Stat>, AuditError> {
+            let (limit, offset) = pagination(query.limit, query.offset)?;
+            let stats = sqlx::query_as::<_, Stat>(
+                "SELECT
+                    buildpack_id,
+                    COUNT(*) AS shims,
+                    COALESCE(SUM(result = 'failed'), 0) AS failures,
+                    MAX(created_at) AS last_shimmed_at
+                FROM shims
+                WHERE (?1 IS NULL OR created_at >= ?1)
+                    AND (?2 IS NULL OR created_at < ?2)
+                GROUP BY buildpack_id
+                ORDER BY shims DESC, buildpack_id
+                LIMIT ?3 OFFSET ?4",
+            )
+            .bind(parse_timestamp(query.since)?)
+            .bind(parse_timestamp(query.until)?)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.pool)
+            .await?;
+
+            Ok(Page::new(stats, limit, offset))
+        }
+    }
+
+    #[derive(Error, Debug)]
+    pub enum AuditError {
+        #[error("{0}")]
+        InvalidQuery(String),
+        #[error("failed to query the audit log: {0}")]
+        Database(#[from] sqlx::Error),
+    }
+
+    fn pagination(limit: Option<i64>, offset: Option<i64>) -> Result<(i64, i64), AuditError> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT);
+        let offset = offset.unwrap_or(0);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            return Err(AuditError::InvalidQuery(format!(
+                "limit must be between 1 and {}",
+                MAX_LIMIT
+            )));
+        }
+        if offset < 0 {
+            return Err(AuditError::InvalidQuery(String::from(
+                "offset must not be negative",
+            )));
+        }
+
+        Ok((limit, offset))
+    }
+
+    /// Timestamps are stored in one format so they compare as strings
+    fn timestamp(time: DateTime<Utc>) -> String {
+        time.to_rfc3339_opts(SecondsFormat::Millis, true)
+    }
+
+    fn parse_timestamp(time: Option<String>) -> Result<Option<String>, AuditError> {
+        time.map(|time| {
+            DateTime::parse_from_rfc3339(&time)
+                .map(|time| timestamp(time.with_timezone(&Utc)))
+                .map_err(|_| AuditError::InvalidQuery(format!("invalid timestamp: {}", time)))
+        })
+        .transpose()
+    }
+}
+
+mod handlers {
+    use super::{
+        audit::{self, AuditLog},
+        batch,
+        cache::Cache,
+        callback::{Callback, Notifier},
+        health, migrate, models,
+        shim::{Artifact, ShimError, Shimmer, Source},
+        storage::StorageError,
+        telemetry,
+    };
+    use libcnb::data::buildpack;
+    use log::{error, info};
+    use opentelemetry::{trace::FutureExt, Context, KeyValue};
+    use std::{
+        collections::HashSet, convert::Infallible, path::Path, pin::Pin, str::FromStr, sync::Arc,
+        time::Instant,
+    };
+    use tokio::{
+        fs,
+        io::AsyncWriteExt,
+        sync::{mpsc, Semaphore},
+        task,
+    };
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use tokio_stream::{Stream, StreamExt};
+    use warp::{
+        body::BodyDeserializeError,
+        http::StatusCode,
+        hyper::body::{Body, Buf, Bytes},
+        multipart::FormData,
+        reject::{InvalidQuery, Reject, Rejection},
+        Reply,
+    };
+
+    /// Header carrying the base64 encoded minisign signature of a shimmed buildpack
+    const SIGNATURE_HEADER: &str = "X-Cnb-Shim-Minisig";
+    /// Header carrying the base64 encoded DSSE envelope of a shimmed buildpack's provenance
+    const PROVENANCE_HEADER: &str = "X-Cnb-Shim-Provenance";
+    /// Header of a batch part carrying the id of the buildpack it's for
+    const BUILDPACK_ID_HEADER: &str = "X-Cnb-Shim-Buildpack-Id";
+    /// Header of a batch part carrying the status code shimming its buildpack would have had
+    const STATUS_HEADER: &str = "X-Cnb-Shim-Status";
+
+    #[derive(Debug)]
+    /// Unrecoverable Error, HTTP Status Code 500
+    struct ServiceError(String);
+
+    impl Reject for ServiceError {}
+
+    impl ServiceError {
+        fn new(msg: impl Into<String>) -> Self {
+            ServiceError(msg.into())
+        }
+    }
+
+    #[derive(Debug)]
+    /// Bad Request Error, HTTP Status Code 400
+    struct BadRequestError(String);
+
+    impl Reject for BadRequestError {}
+
+    impl BadRequestError {
+        fn new(msg: impl Into<String>) -> Self {
+            BadRequestError(msg.into())
+        }
+    }
+
+    #[derive(Debug)]
+    /// Temporarily Unavailable Error, HTTP Status Code 503
+    struct UnavailableError(String);
+
+    impl Reject for UnavailableError {}
+
+    impl UnavailableError {
+        fn new(msg: impl Into<String>) -> Self {
+            UnavailableError(msg.into())
+        }
+    }
+
+    #[derive(Debug)]
+    /// Insufficient Storage Error, HTTP Status Code 507
+    struct InsufficientStorageError(String);
+
+    impl Reject for InsufficientStorageError {}
+
+    impl InsufficientStorageError {
+        fn new(msg: impl Into<String>) -> Self {
+            InsufficientStorageError(msg.into())
+        }
+    }
+
+    #[derive(Debug)]
+    /// Payload Too Large Error, HTTP Status Code 413
+    struct PayloadTooLargeError(String);
+
+    impl Reject for PayloadTooLargeError {}
+
+    impl PayloadTooLargeError {
+        fn new(msg: impl Into<String>) -> Self {
+            PayloadTooLargeError(msg.into())
+        }
+    }
+
+    #[derive(Debug)]
+    /// Unprocessable Entity Error, HTTP Status Code 422
+    struct UnprocessableError(String);
+
+    impl Reject for UnprocessableError {}
+
+    impl UnprocessableError {
+        fn new(msg: impl Into<String>) -> Self {
+            UnprocessableError(msg.into())
+        }
+    }
+
+    #[derive(Debug)]
+    /// Not Acceptable Error, HTTP Status Code 406
+    struct NotAcceptableError(String);
+
+    impl Reject for NotAcceptableError {}
+
+    impl NotAcceptableError {
+        fn new(msg: impl Into<String>) -> Self {
+            NotAcceptableError(msg.into())
+        }
+    }
+
+    #[derive(Debug)]
+    /// Unauthorized Error, HTTP Status Code 401
+    struct UnauthorizedError(String);
+
+    impl Reject for UnauthorizedError {}
+
+    impl UnauthorizedError {
+        fn new(msg: impl Into<String>) -> Self {
+            UnauthorizedError(msg.into())
+        }
+    }
+
+    pub async fn rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+        if err.is_not_found() {
+            return Err(warp::reject::not_found());
+        }
+
+        let code;
+        let message;
+
+        if let Some(service_error) = err.find::<ServiceError>() {
+            error!("{}", service_error.0);
+            message = String::from("INTERNAL SERVER ERROR");
+            code = StatusCode::INTERNAL_SERVER_ERROR;
+        } else if let Some(request_error) = err.find::<BadRequestError>() {
+            error!("{}", request_error.0);
+            message = request_error.0.clone();
+            code = StatusCode::BAD_REQUEST;
+        } else if let Some(unavailable_error) = err.find::<UnavailableError>() {
+            error!("{}", unavailable_error.0);
+            message = unavailable_error.0.clone();
+            code = StatusCode::SERVICE_UNAVAILABLE;
+        } else if let Some(storage_error) = err.find::<InsufficientStorageError>() {
+            error!("{}", storage_error.0);
+            message = storage_error.0.clone();
+            code = StatusCode::INSUFFICIENT_STORAGE;
+        } else if let Some(too_large_error) = err.find::<PayloadTooLargeError>() {
+            error!("{}", too_large_error.0);
+            message = too_large_error.0.clone();
+            code = StatusCode::PAYLOAD_TOO_LARGE;
+        } else if let Some(unprocessable_error) = err.find::<UnprocessableError>() {
+            error!("{}", unprocessable_error.0);
+            message = unprocessable_error.0.clone();
+            code = StatusCode::UNPROCESSABLE_ENTITY;
+        } else if let Some(not_acceptable_error) = err.find::<NotAcceptableError>() {
+            error!("{}", not_acceptable_error.0);
+            message = not_acceptable_error.0.clone();
+            code = StatusCode::NOT_ACCEPTABLE;
+        } else if let Some(unauthorized_error) = err.find::<UnauthorizedError>() {
+            error!("{}", unauthorized_error.0);
+            message = unauthorized_error.0.clone();
+            code = StatusCode::UNAUTHORIZED;
+        } else if let Some(query_error) = err.find::<InvalidQuery>() {
+            message = query_error.to_string();
+            code = StatusCode::BAD_REQUEST;
+        } else if let Some(body_error) = err.find::<BodyDeserializeError>() {
+            message = body_error.to_string();
+            code = StatusCode::BAD_REQUEST;
+        } else {
+            return Err(err);
+        }
+
+        Ok(warp::reply::with_status(message, code))
+    }
+
+    pub async fn health_check() -> Result<impl Reply, Infallible> {
+        Ok(warp::reply::with_status("health check ok", StatusCode::OK))
+    }
+
+    pub async fn liveness() -> Result<impl Reply, Infallible> {
+        Ok(warp::reply::json(&health::Report::default()))
+    }
+
+    pub async fn readiness(
+        shimmer: Arc<Shimmer>,
+        check_registry: bool,
+    ) -> Result<impl Reply, Infallible> {
+        let report = health::readiness(&shimmer, check_registry).await;
+        let code = if report.is_ok() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        Ok(warp::reply::with_status(warp::reply::json(&report), code))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn shim(
+        namespace: String,
+        name: String,
+        options: models::ShimOptions,
+        callback_options: models::CallbackOptions,
+        accept_encoding: Option<String>,
+        client: Option<String>,
+        trace_context: Context,
+        shimmer: Arc<Shimmer>,
+        notifier: Arc<Notifier>,
+        audit_log: Option<Arc<AuditLog>>,
+    ) -> Result<impl Reply, Rejection> {
+        info!("shimming: {}/{}", namespace, name);
+
+        let id = format!("{}/{}", namespace, name);
+        let callback = Callback::from_options(callback_options, shimmer.allowed_hosts())
+            .map_err(BadRequestError::new)?;
+        let format = negotiate_format(options.format, accept_encoding.as_deref())?;
+        let started = Instant::now();
+        let source = Source::Registry;
+        let result = shimmer
+            .shim(&id, &source, options, format)
+            .with_context(telemetry::server_span(
+                &trace_context,
+                "/v1/:namespace/:name",
+                vec![KeyValue::new("cnb_shim.buildpack_id", id.clone())],
+            ))
+            .await;
+        if let Some(callback) = callback {
+            notifier.notify(callback, &id, &result);
+        }
+        if let Some(audit_log) = audit_log {
+            audit_log
+                .record(
+                    &id,
+                    source.kind(),
+                    client.as_deref(),
+                    started.elapsed(),
+                    &result,
+                )
+                .await;
+        }
+
+        signed_artifact_response(result.map_err(reject)?, format)
+    }
+
+    /// Takes the callback secret from its header, refusing one in the query string where
+    /// access logs and proxies would record it
+    pub async fn callback_options(
+        query: models::CallbackOptions,
+        secret: Option<String>,
+    ) -> Result<models::CallbackOptions, Rejection> {
+        if query.callback_secret.is_some() {
+            return Err(BadRequestError::new(
+                "callback_secret isn't accepted in the query string, send it in the X-Cnb-Shim-Callback-Secret header",
+            )
+            .into());
+        }
+
+        Ok(models::CallbackOptions {
+            callback_secret: secret,
+            ..query
+        })
+    }
+
+    /// A request body read as it arrives
+    pub type BodyStream = Pin<Box<dyn Stream<Item = Result<Bytes, warp::Error>> + Send>>;
+
+    /// A v2 buildpack tarball uploaded as part of a multipart form or as the raw request body,
+    /// with its Content-Length unless it's chunked
+    pub enum Upload {
+        Form(FormData),
+        Raw(Option<u64>, BodyStream),
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn upload(
+        options: models::UploadOptions,
+        callback_options: models::CallbackOptions,
+        upload: Upload,
+        accept_encoding: Option<String>,
+        client: Option<String>,
+        trace_context: Context,
+        shimmer: Arc<Shimmer>,
+        notifier: Arc<Notifier>,
+        audit_log: Option<Arc<AuditLog>>,
+    ) -> Result<impl Reply, Rejection> {
+        let callback = Callback::from_options(callback_options, shimmer.allowed_hosts())
+            .map_err(BadRequestError::new)?;
+        // before any of the body is read
+        shimmer.check_capacity().map_err(reject)?;
+        let max_size = shimmer.limits().download_size;
+        if let Upload::Raw(Some(length), _) = &upload {
+            if *length > max_size {
+                return Err(upload_too_large(max_size));
+            }
+        }
+
+        let tmp_dir = shimmer.work_dir().map_err(reject)?;
+        let v2_buildpack_path = tmp_dir.path().join("buildpack.tgz");
+        let options = match upload {
+            Upload::Form(form) => save_form(form, &v2_buildpack_path)
+                .await?
+                .unwrap_or(options),
+            Upload::Raw(_, body) => {
+                save_body(body, &v2_buildpack_path, max_size).await?;
+                options
+            }
+        };
+
+        let id = options
+            .id
+            .ok_or_else(|| BadRequestError::new("missing buildpack id"))?;
+        info!("shimming upload: {}", id);
+
+        let format = negotiate_format(options.shim.format, accept_encoding.as_deref())?;
+        let started = Instant::now();
+        let source = Source::File(v2_buildpack_path);
+        let result = shimmer
+            .shim(&id, &source, options.shim, format)
+            .with_context(telemetry::server_span(
+                &trace_context,
+                "/v1/upload",
+                vec![KeyValue::new("cnb_shim.buildpack_id", id.clone())],
+            ))
+            .await;
+        if let Some(callback) = callback {
+            notifier.notify(callback, &id, &result);
+        }
+        if let Some(audit_log) = audit_log {
+            audit_log
+                .record(
+                    &id,
+                    source.kind(),
+                    client.as_deref(),
+                    started.elapsed(),
+                    &result,
+                )
+                .await;
+        }
+
+        signed_artifact_response(result.map_err(reject)?, format)
+    }
+
+    /// Shims the classic buildpacks a project.toml references and rewrites it to use them
+    pub async fn project(
+        options: models::ShimOptions,
+        body: Bytes,
+        client: Option<String>,
+        trace_context: Context,
+        shimmer: Arc<Shimmer>,
+        audit_log: Option<Arc<AuditLog>>,
+    ) -> Result<impl Reply, Rejection> {
+        let span = telemetry::server_span(&trace_context, "/v1/project", Vec::new());
+        let mut project = migrate::ProjectDescriptor::parse(&body).map_err(BadRequestError::new)?;
+
+        let mut classic = Vec::new();
+        for entry in project.classic_candidates() {
+            if entry.check_cnb_registry {
+                match shimmer
+                    .registry()
+                    .cnb_exists(&entry.id)
+                    .with_context(span.clone())
+                    .await
+                {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(err) => {
+                        return Err(UnavailableError::new(format!(
+                            "Can't look up {} in the CNB registry: {}",
+                            entry.id, err
+                        ))
+                        .into())
+                    }
+                }
+            }
+            classic.push(entry);
+        }
+        if classic.is_empty() {
+            return Err(BadRequestError::new("no classic buildpacks in project.toml").into());
+        }
+        for entry in &classic {
+            shimmer.check_source(&entry.source).map_err(reject)?;
+        }
+        info!(
+            "shimming project buildpacks: {}",
+            classic
+                .iter()
+                .map(|entry| entry.id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let mut shim_options = options;
+        // every buildpack is named after its own id
+        shim_options.name = None;
+        let mut artifacts = Vec::new();
+        for entry in &classic {
+            let started = Instant::now();
+            let result = shimmer
+                .shim(
+                    &entry.id,
+                    &entry.source,
+                    shim_options.clone(),
+                    models::ArchiveFormat::TarGz,
+                )
+                .with_context(span.clone())
+                .await;
+            if let Some(audit_log) = &audit_log {
+                audit_log
+                    .record(
+                        &entry.id,
+                        entry.source.kind(),
+                        client.as_deref(),
+                        started.elapsed(),
+                        &result,
+                    )
+                    .await;
+            }
+            let artifact = result.map_err(reject)?;
+            project.rewrite(entry.index, &artifact);
+            artifacts.push(artifact);
+        }
+
+        let bundle = project.bundle(&artifacts).map_err(|err| {
+            ServiceError::new(format!("Could not bundle project buildpacks: {}", err))
+        })?;
+
+        Ok(http::response::Builder::new()
+            .status(200)
+            .header("Content-Type", "application/zip")
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.zip\"", uuid::Uuid::new_v4()),
+            )
+            .body(bundle)
+            .map_err(|_| ServiceError::new("Could not send response."))?)
+    }
+
+    pub async fn audit(
+        query: audit::AuditQuery,
+        audit_log: Option<Arc<AuditLog>>,
+    ) -> Result<impl Reply, Rejection> {
+        let audit_log = audit_log.ok_or_else(warp::reject::not_found)?;
+        let entries = audit_log.entries(query).await.map_err(reject_audit)?;
+
+        Ok(warp::reply::json(&entries))
+    }
+
+    pub async fn stats(
+        query: audit::StatsQuery,
+        audit_log: Option<Arc<AuditLog>>,
+    ) -> Result<impl Reply, Rejection> {
+        let audit_log = audit_log.ok_or_else(warp::reject::not_found)?;
+        let stats = audit_log.stats(query).await.map_err(reject_audit)?;
+
+        Ok(warp::reply::json(&stats))
+    }
+
+    fn reject_audit(err: audit::AuditError) -> Rejection {
+        match err {
+            audit::AuditError::InvalidQuery(_) => BadRequestError::new(err.to_string()).into(),
+            audit::AuditError::Database(_) => {
+                error!("{}", err);
+                ServiceError::new("Could not query the audit log").into()
+            }
+        }
+    }
+
+    /// Lets a request through when it carries the admin token as a bearer token. Without a
+    /// token configured, there's no admin API to get through to.
+    pub async fn authorize(
+        authorization: Option<String>,
+        token: Option<Arc<String>>,
+    ) -> Result<(), Rejection> {
+        let token = token.ok_or_else(warp::reject::not_found)?;
+        let given = authorization
+            .as_deref()
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        if constant_time_eq(given.trim().as_bytes(), token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(UnauthorizedError::new("Invalid admin token").into())
+        }
+    }
+
+    /// Compares without returning early, so the time taken doesn't tell how much of a token
+    /// was right
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    /// Lists what's cached in memory and in storage, with the buildpack each entry is of
+    /// when that's known
+    pub async fn cache_entries(shimmer: Arc<Shimmer>) -> Result<impl Reply, Rejection> {
+        let mut entries = Vec::new();
+        if let Some(memory_cache) = shimmer.memory_cache() {
+            entries.extend(memory_cache.entries().into_iter().map(|(key, size, age)| {
+                models::CacheEntry {
+                    key,
+                    tier: models::CacheTier::Memory,
+                    size: size as u64,
+                    age: Some(age.as_secs()),
+                    metadata: models::CacheMetadata::default(),
+                }
+            }));
+        }
+        if let Some(cache) = shimmer.cache() {
+            let stored = cache.entries().await.map_err(reject_storage)?;
+            entries.extend(stored.into_iter().map(|entry| {
+                models::CacheEntry {
+                    key: entry.key,
+                    tier: models::CacheTier::Storage,
+                    size: entry.size,
+                    age: entry
+                        .modified
+                        .and_then(|modified| modified.elapsed().ok())
+                        .map(|age| age.as_secs()),
+                    metadata: models::CacheMetadata::default(),
+                }
+            }));
+        }
+        // metadata is shown with the entry it's about rather than as an entry of its own
+        let keys = entries
+            .iter()
+            .map(|entry| entry.key.clone())
+            .collect::<HashSet<_>>();
+        entries.retain(|entry| !Cache::is_metadata_key(&entry.key));
+        for entry in &mut entries {
+            if keys.contains(&Cache::metadata_key(&entry.key)) {
+                entry.metadata = shimmer.cache_metadata(&entry.key).await.unwrap_or_default();
+            }
+        }
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(warp::reply::json(&entries))
+    }
+
+    /// Removes an entry and its metadata from memory and storage, which is a no-op where it
+    /// isn't cached
+    pub async fn remove_cache_entry(
+        key: warp::path::Tail,
+        shimmer: Arc<Shimmer>,
+    ) -> Result<impl Reply, Rejection> {
+        let key = key.as_str();
+        if key.is_empty() {
+            return Err(BadRequestError::new("Missing cache key").into());
+        }
+
+        for key in [String::from(key), Cache::metadata_key(key)] {
+            if let Some(memory_cache) = shimmer.memory_cache() {
+                memory_cache.remove(&key);
+            }
+            if let Some(cache) = shimmer.cache() {
+                cache.remove(&key).await.map_err(reject_storage)?;
+            }
+        }
+        info!("Removed {} from the cache", key);
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Empties the cache, in memory and in storage
+    pub async fn clear_cache(shimmer: Arc<Shimmer>) -> Result<impl Reply, Rejection> {
+        let removed = purge_cache(&shimmer, false).await?;
+        info!(
+            "Cleared the cache, {} entries in memory and {} in storage",
+            removed.memory, removed.storage
+        );
+
+        Ok(warp::reply::json(&removed))
+    }
+
+    /// Sweeps the work directory and removes expired cache entries now, instead of waiting
+    /// for the janitor or for them to be looked up again
+    pub async fn gc(shimmer: Arc<Shimmer>) -> Result<impl Reply, Rejection> {
+        let sweeping = shimmer.clone();
+        let report = task::spawn_blocking(move || sweeping.janitor().sweep())
+            .await
+            .map_err(|_| ServiceError::new("Work directory sweep panicked"))?
+            .map_err(|err| {
+                ServiceError::new(format!("Could not sweep the work directory: {}", err))
+            })?;
+        let expired = purge_cache(&shimmer, true).await?;
+
+        Ok(warp::reply::json(&models::GcReport {
+            removed: report.removed,
+            freed: report.freed,
+            usage: report.usage,
+            expired,
+        }))
+    }
+
+    async fn purge_cache(
+        shimmer: &Shimmer,
+        expired_only: bool,
+    ) -> Result<models::CacheRemoval, Rejection> {
+        let mut removed = models::CacheRemoval::default();
+        if let Some(memory_cache) = shimmer.memory_cache() {
+            removed.memory = memory_cache.purge(expired_only);
+        }
+        if let Some(cache) = shimmer.cache() {
+            removed.storage = cache.purge(expired_only).await.map_err(reject_storage)?;
+        }
+
+        Ok(removed)
+    }
+
+    fn reject_storage(err: StorageError) -> Rejection {
+        match err {
+            StorageError::InvalidKey(_) => BadRequestError::new(err.to_string()).into(),
+            _ => ServiceError::new(format!("Could not access the cache: {}", err)).into(),
+        }
+    }
+
+    /// Serves a cached shimmed buildpack, as linked from callbacks, or its signature or
+    /// provenance
+    pub async fn artifact(
+        file: String,
+        shimmer: Arc<Shimmer>,
+    ) -> Result<http::Response<Bytes>, Rejection> {
+        let content_type = if file.ends_with(".minisig") {
+            Some("text/plain")
+        } else if file.ends_with(".intoto.json") {
+            Some("application/json")
+        } else {
+            None
+        };
+        if let Some(content_type) = content_type {
+            let cache = shimmer.cache().ok_or_else(warp::reject::not_found)?;
+            let data = cache
+                .get(&Cache::artifact_file_key(&file))
+                .await
+                .ok_or_else(warp::reject::not_found)?;
+
+            return http::response::Builder::new()
+                .status(200)
+                .header("Content-Type", content_type)
+                .body(Bytes::from(data))
+                .map_err(|_| ServiceError::new("Could not send response.").into());
+        }
+
+        let format =
+            models::ArchiveFormat::from_file_name(&file).ok_or_else(warp::reject::not_found)?;
+        let cache = shimmer.cache().ok_or_else(warp::reject::not_found)?;
+        let data = cache
+            .get(&Cache::artifact_file_key(&file))
+            .await
+            .ok_or_else(warp::reject::not_found)?;
+
+        artifact_response(Bytes::from(data), format)
+    }
+
+    pub async fn migrate(
+        options: models::MigrateOptions,
+        body: Bytes,
+        client: Option<String>,
+        trace_context: Context,
+        shimmer: Arc<Shimmer>,
+        audit_log: Option<Arc<AuditLog>>,
+    ) -> Result<impl Reply, Rejection> {
+        let span = telemetry::server_span(&trace_context, "/v1/migrate", Vec::new());
+        let migration = migrate::Migration::parse(&body).map_err(BadRequestError::new)?;
+        if migration.buildpacks.is_empty() {
+            return Err(BadRequestError::new("no buildpacks to migrate").into());
+        }
+        // refused before any of them is shimmed
+        for buildpack in &migration.buildpacks {
+            shimmer.check_source(&buildpack.source).map_err(reject)?;
+        }
+        info!(
+            "migrating: {}",
+            migration
+                .buildpacks
+                .iter()
+                .map(|buildpack| buildpack.id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let mut shim_options = options.shim;
+        // every buildpack is named after its own id
+        shim_options.name = None;
+        if shim_options.stacks.is_none() {
+            shim_options.stacks = migration.stack.clone().map(|stack| vec![stack]);
+        }
+
+        let mut artifacts = Vec::new();
+        for buildpack in &migration.buildpacks {
+            let started = Instant::now();
+            let result = shimmer
+                .shim(
+                    &buildpack.id,
+                    &buildpack.source,
+                    shim_options.clone(),
+                    models::ArchiveFormat::TarGz,
+                )
+                .with_context(span.clone())
+                .await;
+            if let Some(audit_log) = &audit_log {
+                audit_log
+                    .record(
+                        &buildpack.id,
+                        buildpack.source.kind(),
+                        client.as_deref(),
+                        started.elapsed(),
+                        &result,
+                    )
+                    .await;
+            }
+            artifacts.push(result.map_err(reject)?);
+        }
+
+        let (bundle, content_type, extension) = match options.output {
+            models::MigrateOutput::Project => (
+                migrate::project_bundle(&artifacts),
+                "application/zip",
+                "zip",
+            ),
+            models::MigrateOutput::Meta => (
+                migrate::meta_buildpack(
+                    &options.meta_id,
+                    &options.meta_version,
+                    options.meta_name.as_deref(),
+                    shim_options.api.as_deref(),
+                    &artifacts,
+                ),
+                models::ArchiveFormat::TarGz.content_type(),
+                models::ArchiveFormat::TarGz.extension(),
+            ),
+        };
+        let bundle = bundle.map_err(|err| {
+            ServiceError::new(format!("Could not bundle migrated buildpacks: {}", err))
+        })?;
+
+        Ok(http::response::Builder::new()
+            .status(200)
+            .header("Content-Type", content_type)
+            .header(
+                "Content-Disposition",
+                format!(
+                    "attachment; filename=\"{}.{}\"",
+                    uuid::Uuid::new_v4(),
+                    extension
+                ),
+            )
+            .body(bundle)
+            .map_err(|_| ServiceError::new("Could not send response."))?)
+    }
+
+    /// Shims a batch of buildpacks, `concurrency` at a time, into a zip or a multipart stream
+    #[allow(clippy::too_many_arguments)]
+    pub async fn batch(
+        request: models::BatchRequest,
+        accept_encoding: Option<String>,
+        client: Option<String>,
+        trace_context: Context,
+        shimmer: Arc<Shimmer>,
+        audit_log: Option<Arc<AuditLog>>,
+        concurrency: usize,
+    ) -> Result<http::Response<Body>, Rejection> {
+        if request.buildpacks.is_empty() {
+            return Err(BadRequestError::new("no buildpacks in batch").into());
+        }
+        if request.buildpacks.len() > batch::MAX_BATCH_SIZE {
+            return Err(BadRequestError::new(format!(
+                "at most {} buildpacks per batch",
+                batch::MAX_BATCH_SIZE
+            ))
+            .into());
+        }
+        // refused as a whole before any of them is shimmed, ids also end up in part headers
+        for spec in &request.buildpacks {
+            if buildpack::BuildpackId::from_str(&spec.id).is_err() {
+                return Err(
+                    BadRequestError::new(format!("invalid buildpack id {:?}", spec.id)).into(),
+                );
+            }
+            if let Some(url) = &spec.url {
+                shimmer
+                    .check_source(&Source::Url(url.clone()))
+                    .map_err(reject)?;
+            }
+        }
+        info!(
+            "shimming batch: {}",
+            request
+                .buildpacks
+                .iter()
+                .map(|spec| spec.id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        // all of them before any is shimmed
+        let formats = request
+            .buildpacks
+            .iter()
+            .map(|spec| negotiate_format(spec.options.format, accept_encoding.as_deref()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let span = telemetry::server_span(&trace_context, "/v1/batch", Vec::new());
+        let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        // dropped with the response, or as soon as it's refused
+        let mut tasks = batch::Tasks::default();
+        for ((index, spec), format) in request.buildpacks.into_iter().enumerate().zip(formats) {
+            let (shimmer, audit_log, client) = (shimmer.clone(), audit_log.clone(), client.clone());
+            let (semaphore, span, tx) = (semaphore.clone(), span.clone(), tx.clone());
+            tasks.push(tokio::spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let source = spec.url.map(Source::Url).unwrap_or(Source::Registry);
+                let started = Instant::now();
+                let result = shimmer
+                    .shim(&spec.id, &source, spec.options, format)
+                    .with_context(span)
+                    .await;
+                if let Some(audit_log) = audit_log {
+                    audit_log
+                        .record(
+                            &spec.id,
+                            source.kind(),
+                            client.as_deref(),
+                            started.elapsed(),
+                            &result,
+                        )
+                        .await;
+                }
+                let _ = tx.send((index, spec.id, format, result));
+            }));
+        }
+        drop(tx);
+
+        match request.output {
+            models::BatchOutput::Zip => {
+                // the zip is built in memory, so it's held to what a single artifact may take
+                let max_size = shimmer.limits().artifact_size;
+                let mut size = 0;
+                let mut results = Vec::new();
+                while let Some(result) = rx.recv().await {
+                    if let (_, _, _, Ok(artifact)) = &result {
+                        size += artifact.data.len() as u64;
+                        if size > max_size {
+                            return Err(UnprocessableError::new(format!(
+                                "batch is larger than the artifact size limit of {} bytes, \
+                                 use output=multipart to stream it instead",
+                                max_size
+                            ))
+                            .into());
+                        }
+                    }
+                    results.push(result);
+                }
+                results.sort_by_key(|(index, ..)| *index);
+                let mut artifacts = Vec::new();
+                let mut failures = Vec::new();
+                for (_, id, format, result) in results {
+                    match result {
+                        Ok(artifact) => artifacts.push((artifact, format)),
+                        Err(err) => failures.push(batch::Failure {
+                            id,
+                            status: status_code(&err).as_u16(),
+                            error: err.to_string(),
+                        }),
+                    }
+                }
+                let bundle = batch::zip(&artifacts, &failures).map_err(|err| {
+                    ServiceError::new(format!("Could not bundle shimmed buildpacks: {}", err))
+                })?;
+
+                Ok(http::response::Builder::new()
+                    .status(200)
+                    .header("Content-Type", "application/zip")
+                    .header(
+                        "Content-Disposition",
+                        format!("attachment; filename=\"{}.zip\"", uuid::Uuid::new_v4()),
+                    )
+                    .body(Body::from(bundle))
+                    .map_err(|_| ServiceError::new("Could not send response."))?)
+            }
+            models::BatchOutput::Multipart => {
+                let boundary = uuid::Uuid::new_v4().to_simple().to_string();
+                let closing = batch::closing(&boundary);
+                let parts = UnboundedReceiverStream::new(rx)
+                    .map({
+                        let boundary = boundary.clone();
+                        // a client going away drops the stream, and with it the shims
+                        move |(_, id, format, result)| {
+                            let _ = &tasks;
+                            Ok::<_, Infallible>(batch_part(&boundary, id, format, result))
+                        }
+                    })
+                    .chain(tokio_stream::once(Ok(closing)));
+
+                Ok(http::response::Builder::new()
+                    .status(200)
+                    .header(
+                        "Content-Type",
+                        format!("multipart/mixed; boundary={}", boundary),
+                    )
+                    .body(Body::wrap_stream(parts))
+                    .map_err(|_| ServiceError::new("Could not send response."))?)
+            }
+        }
+    }
+
+    /// The part of a multipart batch response for one buildpack, its archive or its error
+    fn batch_part(
+        boundary: &str,
+        id: String,
+        format: models::ArchiveFormat,
+        result: Result<Artifact, ShimError>,
+    ) -> Vec<u8> {
+        match result {
+            Ok(artifact) => batch::part(
+                boundary,
+                &[
+                    ("Content-Type", String::from(format.content_type())),
+                    (
+                        "Content-Disposition",
+                        format!(
+                            "attachment; filename=\"{}\"",
+                            batch::file_name(&artifact, format)
+                        ),
+                    ),
+                    (BUILDPACK_ID_HEADER, id),
+                    (STATUS_HEADER, StatusCode::OK.as_u16().to_string()),
+                ],
+                &artifact.data,
+            ),
+            Err(err) => batch::part(
+                boundary,
+                &[
+                    ("Content-Type", String::from("text/plain; charset=utf-8")),
+                    (BUILDPACK_ID_HEADER, id),
+                    (STATUS_HEADER, status_code(&err).as_u16().to_string()),
+                ],
+                err.to_string().as_bytes(),
+            ),
+        }
+    }
+
+    /// Streams the `buildpack` part of a form to `dst`, returning the options from the
+    /// `options` part, if there is one.
+    async fn save_form(
+        mut form: FormData,
+        dst: &Path,
+    ) -> Result<Option<models::UploadOptions>, Rejection> {
+        let mut options = None;
+        let mut saved = false;
+
+        while let Some(part) = form.next().await {
+            let mut part = part
+                .map_err(|err| BadRequestError::new(format!("invalid multipart form: {}", err)))?;
+            let part_name = String::from(part.name());
+            match part_name.as_str() {
+                "buildpack" => {
+                    let write_error = |_| ServiceError::new("Can't write uploaded v2 buildpack");
+                    let mut file = fs::File::create(dst).await.map_err(write_error)?;
+                    while let Some(chunk) = part.data().await {
+                        let chunk = chunk.map_err(|err| {
+                            BadRequestError::new(format!(
+                                "Can't read uploaded v2 buildpack: {}",
+                                err
+                            ))
+                        })?;
+                        file.write_all(chunk.chunk()).await.map_err(write_error)?;
+                    }
+                    // written in the background until it's flushed
+                    file.flush().await.map_err(write_error)?;
+                    saved = true;
+                }
+                "options" => {
+                    let mut data = Vec::new();
+                    while let Some(chunk) = part.data().await {
+                        let chunk = chunk.map_err(|err| {
+                            BadRequestError::new(format!("Can't read options: {}", err))
+                        })?;
+                        data.extend_from_slice(chunk.chunk());
+                    }
+                    options = Some(serde_json::from_slice(&data).map_err(|err| {
+                        BadRequestError::new(format!("invalid options: {}", err))
+                    })?);
+                }
+                _ => (),
+            }
+        }
+
+        if !saved {
+            return Err(BadRequestError::new("missing buildpack part").into());
+        }
+
+        Ok(options)
+    }
+
+    /// Streams a raw upload to `dst`, giving up as soon as more than `max_size` bytes arrived,
+    /// whether or not it came with a Content-Length.
+    async fn save_body(mut body: BodyStream, dst: &Path, max_size: u64) -> Result<(), Rejection> {
+        let write_error = |_| ServiceError::new("Can't write uploaded v2 buildpack to disk");
+        let mut file = fs::File::create(dst).await.map_err(write_error)?;
+        let mut size = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|err| {
+                BadRequestError::new(format!("Can't read uploaded v2 buildpack: {}", err))
+            })?;
+            size += chunk.len() as u64;
+            if size > max_size {
+                return Err(upload_too_large(max_size));
+            }
+            file.write_all(&chunk).await.map_err(write_error)?;
+        }
+        file.flush().await.map_err(write_error)?;
+
+        Ok(())
+    }
+
+    fn upload_too_large(max_size: u64) -> Rejection {
+        PayloadTooLargeError::new(format!(
+            "uploaded v2 buildpack is larger than the download size limit of {} bytes",
+            max_size
+        ))
+        .into()
+    }
+
+    /// An explicitly requested format wins over the `Accept-Encoding` header.
+    fn negotiate_format(
+        format: Option<models::ArchiveFormat>,
+        accept_encoding: Option<&str>,
+    ) -> Result<models::ArchiveFormat, Rejection> {
+        match (format, accept_encoding) {
+            (Some(format), _) => Ok(format),
+            (None, Some(accept_encoding)) => models::ArchiveFormat::negotiate(accept_encoding)
+                .ok_or_else(|| {
+                    NotAcceptableError::new(format!(
+                        "Accept-Encoding {:?} refuses every format, ask for one of gzip, zstd or \
+                         identity or pass format=",
+                        accept_encoding
+                    ))
+                    .into()
+                }),
+            (None, None) => Ok(models::ArchiveFormat::TarGz),
+        }
+    }
+
+    /// The status code a shim failing with `err` is answered with
+    fn status_code(err: &ShimError) -> StatusCode {
+        match err {
+            ShimError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ShimError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ShimError::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+            ShimError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ShimError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ShimError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn reject(err: ShimError) -> Rejection {
+        match err {
+            ShimError::BadRequest(msg) => BadRequestError::new(msg).into(),
+            ShimError::Unavailable(msg) => UnavailableError::new(msg).into(),
+            ShimError::InsufficientStorage(msg) => InsufficientStorageError::new(msg).into(),
+            ShimError::PayloadTooLarge(msg) => PayloadTooLargeError::new(msg).into(),
+            ShimError::Unprocessable(msg) => UnprocessableError::new(msg).into(),
+            ShimError::Internal(msg) => ServiceError::new(msg).into(),
+        }
+    }
+
+    /// The response for a shimmed buildpack, with its signature and provenance as base64
+    /// encoded headers when it's signed
+    fn signed_artifact_response(
+        artifact: Artifact,
+        format: models::ArchiveFormat,
+    ) -> Result<http::Response<Bytes>, Rejection> {
+        let signature = artifact.signature;
+        let mut res = artifact_response(artifact.data, format)?;
+        for warning in artifact.warnings {
+            res.headers_mut().append(
+                http::header::WARNING,
+                http::HeaderValue::from_str(&format!("299 cnb-shim \"{}\"", warning))
+                    .map_err(|_| ServiceError::new("Could not send response."))?,
+            );
+        }
+        if let Some(signature) = signature {
+            let headers = res.headers_mut();
+            for (name, value) in [
+                (SIGNATURE_HEADER, signature.minisig),
+                (PROVENANCE_HEADER, signature.provenance),
+            ] {
+                headers.insert(
+                    name,
+                    http::HeaderValue::from_str(&base64::encode(value))
+                        .map_err(|_| ServiceError::new("Could not send response."))?,
+                );
+            }
+        }
+
+        Ok(res)
+    }
+
+    fn artifact_response(
+        artifact: Bytes,
+        format: models::ArchiveFormat,
+    ) -> Result<http::Response<Bytes>, Rejection> {
+        let shimmed_buildpack = format!("{}.{}", uuid::Uuid::new_v4(), format.extension());
+
+        Ok(http::response::Builder::new()
+            .status(200)
+            .header("Content-Type", format.content_type())
+            .header("Vary", "Accept-Encoding")
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", &shimmed_buildpack),
+            )
+            .body(artifact)
+            .map_err(|_| ServiceError::new("Could not send response."))?)
+    }
+}
+
+mod scripts {
+    use log::info;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    use std::{
+        fmt, fs, io,
+        path::{Path, PathBuf},
+    };
+    use thiserror::Error;
+
+    /// Scripts every shim needs, the CNB `bin/` entry points
+    const REQUIRED_SCRIPTS: [&str; 2] = ["detect", "build"];
+    /// Scripts `build` runs when they're there
+    const OPTIONAL_SCRIPTS: [&str; 2] = ["release", "exports"];
+
+    /// The `bin/` scripts of a shimmed buildpack, loaded once at startup.
+    pub struct ShimScripts {
+        scripts: Vec<(String, Vec<u8>)>,
+        /// Where the scripts were read from, if they weren't bundled
+        dir: Option<PathBuf>,
+    }
+
+    #[derive(Error, Debug)]
+    pub enum ScriptsError {
+        #[error("shim script {} is missing", .0.display())]
+        Missing(PathBuf),
+        #[error("shim script {} is not executable", .0.display())]
+        NotExecutable(PathBuf),
+        #[error("failed to read shim script {}: {}", .0.display(), .1)]
+        IOError(PathBuf, #[source] io::Error),
+        #[error("shim script {0} is required")]
+        Required(String),
+        #[error("there's no bundled shim script {0}")]
+        Unknown(String),
+        #[error("invalid shim script name {0}")]
+        InvalidName(String),
+    }
+
+    impl fmt::Debug for ShimScripts {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_list()
+                .entries(self.scripts.iter().map(|(name, _)| name))
+                .finish()
+        }
+    }
+
+    impl ShimScripts {
+        /// The scripts compiled into cnb-shim from its `bin/` directory, all of them unless
+        /// `names` picks some.
+        pub fn bundled(names: Option<&[String]>) -> Result<Self, ScriptsError> {
+            let bundled: [(&str, &[u8]); 4] = [
+                ("detect", include_bytes!("../bin/detect")),
+                ("build", include_bytes!("../bin/build")),
+                ("release", include_bytes!("../bin/release")),
+                ("exports", include_bytes!("../bin/exports")),
+            ];
+            let names = match names {
+                Some(names) => validate_names(names)?,
+                None => bundled.iter().map(|(name, _)| name.to_string()).collect(),
+            };
+            let scripts = names
+                .into_iter()
+                .map(|name| {
+                    bundled
+                        .iter()
+                        .find(|(bundled_name, _)| *bundled_name == name)
+                        .map(|(_, script)| (name.clone(), script.to_vec()))
+                        .ok_or(ScriptsError::Unknown(name))
+                })
+                .collect::<Result<_, _>>()?;
+
+            Ok(ShimScripts { scripts, dir: None })
+        }
+
+        /// Reads the scripts from `dir`, failing on the first one that's missing or isn't
+        /// executable. Without `names` that's `detect` and `build`, plus `release` and
+        /// `exports` when they're there.
+        pub fn from_dir(
+            dir: impl AsRef<Path>,
+            names: Option<&[String]>,
+        ) -> Result<Self, ScriptsError> {
+            let dir = dir.as_ref();
+            let names = match names {
+                Some(names) => validate_names(names)?,
+                None => REQUIRED_SCRIPTS
+                    .iter()
+                    .map(|name| name.to_string())
+                    .chain(
+                        OPTIONAL_SCRIPTS
+                            .iter()
+                            .filter(|name| {
+                                let present = dir.join(name).exists();
+                                if !present {
+                                    info!(
+                                        "{} has no {} shim script, skipping it",
+                                        dir.display(),
+                                        name
+                                    );
+                                }
+                                present
+                            })
+                            .map(|name| name.to_string()),
+                    )
+                    .collect(),
+            };
+
+            let mut scripts = Vec::new();
+            for name in names {
+                let path = dir.join(&name);
+                check_script(&path)?;
+                let script = fs::read(&path).map_err(|err| ScriptsError::IOError(path, err))?;
+                scripts.push((name, script));
+            }
+
+            Ok(ShimScripts {
+                scripts,
+                dir: Some(dir.to_path_buf()),
+            })
+        }
+
+        /// Checks the scripts read at startup are still in place, so a broken deploy shows up
+        /// in readiness checks before the next restart fails on it.
+        pub fn check(&self) -> Result<(), ScriptsError> {
+            match &self.dir {
+                Some(dir) => self
+                    .scripts
+                    .iter()
+                    .try_for_each(|(name, _)| check_script(&dir.join(name))),
+                None => Ok(()),
+            }
+        }
+
+        /// Writes the scripts as executables into `bin_dir`.
+        pub fn install(&self, bin_dir: impl AsRef<Path>) -> io::Result<()> {
+            fs::create_dir_all(bin_dir.as_ref())?;
+            for (name, script) in &self.scripts {
+                let path = bin_dir.as_ref().join(name);
+                fs::write(&path, script)?;
+                #[cfg(unix)]
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Checks a configured set of scripts has the required ones and nothing but file names
+    fn validate_names(names: &[String]) -> Result<Vec<String>, ScriptsError> {
+        if let Some(required) = REQUIRED_SCRIPTS
+            .iter()
+            .find(|required| !names.iter().any(|name| name == *required))
+        {
+            return Err(ScriptsError::Required(required.to_string()));
+        }
+        if let Some(name) = names
+            .iter()
+            .find(|name| name.is_empty() || name.contains('/') || name.starts_with('.'))
+        {
+            return Err(ScriptsError::InvalidName(name.clone()));
+        }
+
+        let mut unique = Vec::new();
+        for name in names {
+            if !unique.contains(name) {
+                unique.push(name.clone());
+            }
+        }
+
+        Ok(unique)
+    }
+
+    fn check_script(path: &Path) -> Result<(), ScriptsError> {
+        let metadata = fs::metadata(path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => ScriptsError::Missing(path.to_path_buf()),
+            _ => ScriptsError::IOError(path.to_path_buf(), err),
+        })?;
+        if !metadata.is_file() {
+            return Err(ScriptsError::Missing(path.to_path_buf()));
+        }
+        // there are no execute bits to check elsewhere
+        #[cfg(unix)]
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err(ScriptsError::NotExecutable(path.to_path_buf()));
+        }
+
+        Ok(())
+    }
+}
+
+mod signing {
+    use serde::Serialize;
+    use sha2::{Digest, Sha256};
+    use std::{io::Cursor, path::Path};
+    use thiserror::Error;
+
+    const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v0.1";
+    const PREDICATE_TYPE: &str = "https://github.com/hone/cnb-shim/provenance/v1";
+    /// DSSE payload type of an in-toto statement
+    const PAYLOAD_TYPE: &str = "application/vnd.in-toto+json";
+
+    #[derive(Error, Debug)]
+    pub enum SigningError {
+        #[error("{0}")]
+        Minisign(#[from] minisign::PError),
+        #[error("{0}")]
+        Json(#[from] serde_json::Error),
+    }
+
+    /// Where a shimmed buildpack came from, as recorded in its provenance statement
+    #[derive(Debug, Serialize)]
+    pub struct Provenance {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub source_url: Option<String>,
+        /// `sha256:<hex>` of the v2 buildpack tarball, unknown for cached buildpacks
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub source_digest: Option<String>,
+        pub shim_version: &'static str,
+    }
+
+    impl Provenance {
+        pub fn new(source_url: Option<String>, source_digest: Option<String>) -> Self {
+            Provenance {
+                source_url,
+                source_digest,
+                shim_version: env!("CARGO_PKG_VERSION"),
+            }
+        }
+    }
+
+    #[derive(Serialize)]
+    struct Statement<'a> {
+        #[serde(rename = "_type")]
+        statement_type: &'static str,
+        subject: [Subject<'a>; 1],
+        #[serde(rename = "predicateType")]
+        predicate_type: &'static str,
+        predicate: &'a Provenance,
+    }
+
+    #[derive(Serialize)]
+    struct Subject<'a> {
+        name: &'a str,
+        digest: Sha256Digest,
+    }
+
+    #[derive(Serialize)]
+    struct Sha256Digest {
+        sha256: String,
+    }
+
+    #[derive(Serialize)]
+    struct Envelope {
+        #[serde(rename = "payloadType")]
+        payload_type: &'static str,
+        payload: String,
+        signatures: [EnvelopeSignature; 1],
+    }
+
+    #[derive(Serialize)]
+    struct EnvelopeSignature {
+        sig: String,
+    }
+
+    /// A detached minisign signature of a shimmed buildpack, and a DSSE envelope of its in-toto
+    /// provenance statement whose signature is itself a base64 encoded minisign signature.
+    #[derive(Debug, Clone)]
+    pub struct Signature {
+        pub minisig: String,
+        pub provenance: String,
+    }
+
+    /// Signs shimmed buildpacks with a minisign secret key.
+    pub struct Signer {
+        secret_key: minisign::SecretKey,
+    }
+
+    impl std::fmt::Debug for Signer {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Signer").finish()
+        }
+    }
+
+    impl Signer {
+        pub fn from_file(
+            path: impl AsRef<Path>,
+            password: Option<String>,
+        ) -> Result<Self, SigningError> {
+            // an explicit password, even an empty one, keeps minisign from prompting for it
+            let secret_key =
+                minisign::SecretKey::from_file(path, Some(password.unwrap_or_default()))?;
+
+            Ok(Signer { secret_key })
+        }
+
+        /// Signs `data`, published as `name`, and its provenance
+        pub fn sign(
+            &self,
+            name: &str,
+            data: &[u8],
+            provenance: &Provenance,
+        ) -> Result<Signature, SigningError> {
+            let digest = hex::encode(Sha256::digest(data));
+            let minisig = self.minisign(data, &format!("file:{}\tsha256:{}", name, digest))?;
+
+            let statement = serde_json::to_vec(&Statement {
+                statement_type: STATEMENT_TYPE,
+                subject: [Subject {
+                    name,
+                    digest: Sha256Digest { sha256: digest },
+                }],
+                predicate_type: PREDICATE_TYPE,
+                predicate: provenance,
+            })?;
+            let statement_sig = self.minisign(&pae(PAYLOAD_TYPE, &statement), name)?;
+            let provenance = serde_json::to_string(&Envelope {
+                payload_type: PAYLOAD_TYPE,
+                payload: base64::encode(&statement),
+                signatures: [EnvelopeSignature {
+                    sig: base64::encode(statement_sig),
+                }],
+            })?;
+
+            Ok(Signature {
+                minisig,
+                provenance,
+            })
+        }
+
+        fn minisign(&self, data: &[u8], trusted_comment: &str) -> Result<String, SigningError> {
+            Ok(minisign::sign(
+                None,
+                &self.secret_key,
+                Cursor::new(data),
+                Some(trusted_comment),
+                None,
+            )?
+            .into_string())
+        }
+    }
+
+    /// DSSE pre-authentication encoding, what envelope signatures actually sign
+    fn pae(payload_type: &str, payload: &[u8]) -> Vec<u8> {
+        let mut encoded = format!(
+            "DSSEv1 {} {} {} ",
+            payload_type.len(),
+            payload_type,
+            payload.len()
+        )
+        .into_bytes();
+        encoded.extend_from_slice(payload);
+
+        encoded
+    }
+
+    /// Cache key of the signature of the shimmed buildpack cached at `artifact_key`
+    pub fn signature_key(artifact_key: &str) -> String {
+        format!("{}.minisig", artifact_key)
+    }
+
+    /// Cache key of the provenance of the shimmed buildpack cached at `artifact_key`
+    pub fn provenance_key(artifact_key: &str) -> String {
+        format!("{}.intoto.json", artifact_key)
+    }
+}
+
+mod transform {
+    use super::models::ShimOptions;
+    use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+    use std::{
+        fs, io,
+        path::{Path, PathBuf},
+    };
+
+    /// Version control directories dropped by `strip_vcs`
+    const VCS_DIRS: &[&str] = &[".git", ".hg", ".svn", ".bzr"];
+
+    /// Rules for trimming down the v2 buildpack wrapped by a shim
+    #[derive(Debug, Clone, Default)]
+    pub struct Transform {
+        exclude: Option<GlobSet>,
+        strip_vcs: bool,
+        shebang: Option<String>,
+    }
+
+    impl Transform {
+        pub fn from_options(options: &ShimOptions) -> Result<Self, String> {
+            let exclude = match &options.exclude {
+                Some(globs) if !globs.is_empty() => {
+                    let mut builder = GlobSetBuilder::new();
+                    for glob in globs {
+                        builder.add(
+                            GlobBuilder::new(glob.trim_start_matches('/'))
+                                .literal_separator(true)
+                                .build()
+                                .map_err(|err| format!("invalid exclude glob: {}", err))?,
+                        );
+                    }
+                    Some(
+                        builder
+                            .build()
+                            .map_err(|err| format!("invalid exclude glob: {}", err))?,
+                    )
+                }
+                _ => None,
+            };
+            let shebang = match &options.shebang {
+                Some(shebang) if !shebang.starts_with('/') || shebang.contains('\n') => {
+                    return Err(format!("invalid shebang: {}", shebang))
+                }
+                shebang => shebang.clone(),
+            };
+
+            Ok(Transform {
+                exclude,
+                strip_vcs: options.strip_vcs.unwrap_or(false),
+                shebang,
+            })
+        }
+
+        /// Applies the rules to the v2 buildpack unpacked in `dir`
+        pub fn apply(&self, dir: &Path) -> io::Result<()> {
+            if self.exclude.is_some() || self.strip_vcs {
+                self.prune(dir, Path::new(""))?;
+            }
+            if let Some(shebang) = &self.shebang {
+                rewrite_shebangs(&dir.join("bin"), shebang)?;
+            }
+
+            Ok(())
+        }
+
+        /// Removes excluded paths under `dir`, which is `relative` to the buildpack root
+        fn prune(&self, dir: &Path, relative: &Path) -> io::Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                let relative: PathBuf = relative.join(entry.file_name());
+                let is_dir = entry.file_type()?.is_dir();
+
+                let is_vcs = is_dir
+                    && self.strip_vcs
+                    && VCS_DIRS.iter().any(|vcs_dir| entry.file_name() == *vcs_dir);
+                let is_excluded = self
+                    .exclude
+                    .as_ref()
+                    .map_or(false, |exclude| exclude.is_match(&relative));
+                if is_vcs || is_excluded {
+                    if is_dir {
+                        fs::remove_dir_all(&path)?;
+                    } else {
+                        fs::remove_file(&path)?;
+                    }
+                } else if is_dir {
+                    self.prune(&path, &relative)?;
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Points the shebang of every script in `bin_dir` that has one at `interpreter`
+    fn rewrite_shebangs(bin_dir: &Path, interpreter: &str) -> io::Result<()> {
+        if !bin_dir.is_dir() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(bin_dir)? {
+            let path = entry?.path();
+            // links are left alone, what they point to isn't necessarily a script of ours
+            if !fs::symlink_metadata(&path)?.file_type().is_file() {
+                continue;
+            }
+            let script = fs::read(&path)?;
+            if !script.starts_with(b"#!") {
+                continue;
+            }
+
+            let body = match script.iter().position(|byte| *byte == b'\n') {
+                Some(newline) => &script[newline..],
+                None => &[],
+            };
+            let mut rewritten = format!("#!{}", interpreter).into_bytes();
+            rewritten.extend_from_slice(body);
+            // written in place so the file keeps its permissions
+            fs::write(&path, rewritten)?;
+        }
+
+        Ok(())
+    }
+}
+
+mod stacks {
+    /// Every Heroku stack, oldest first
+    const HEROKU_STACKS: &[&str] = &[
+        "heroku-16",
+        "heroku-18",
+        "heroku-20",
+        "heroku-22",
+        "heroku-24",
+    ];
+    /// Stands for the Heroku stacks that aren't deprecated
+    pub const HEROKU_ALL: &str = "heroku-all";
+    /// The wildcard stack, for a shim that runs on any stack
+    pub const ANY_STACK: &str = "*";
+
+    /// Which stacks a shim runs on when the request doesn't say, and which are deprecated
+    #[derive(Debug, Clone)]
+    pub struct Stacks {
+        defaults: Vec<String>,
+        deprecated: Vec<String>,
+    }
+
+    impl Stacks {
+        pub fn new(defaults: Vec<String>, deprecated: Vec<String>) -> Self {
+            Stacks {
+                defaults,
+                deprecated,
+            }
+        }
+
+        /// Expands the aliases in the requested stacks, or the defaults without any. The
+        /// wildcard stack takes over any stack listed with it. Also returns a warning for each
+        /// deprecated stack left.
+        pub fn resolve(&self, requested: Option<&[String]>) -> (Vec<String>, Vec<String>) {
+            let requested = requested
+                .filter(|stacks| !stacks.is_empty())
+                .unwrap_or(&self.defaults);
+            if requested.iter().any(|stack| stack == ANY_STACK) {
+                return (vec![String::from(ANY_STACK)], Vec::new());
+            }
+
+            let mut stacks: Vec<String> = Vec::new();
+            for stack in requested {
+                let expanded = if stack == HEROKU_ALL {
+                    HEROKU_STACKS
+                        .iter()
+                        .map(|stack| String::from(*stack))
+                        .filter(|stack| !self.is_deprecated(stack))
+                        .collect()
+                } else {
+                    vec![stack.clone()]
+                };
+                for stack in expanded {
+                    if !stacks.contains(&stack) {
+                        stacks.push(stack);
+                    }
+                }
+            }
+            let warnings = stacks
+                .iter()
+                .filter(|stack| self.is_deprecated(stack))
+                .map(|stack| format!("stack {} is deprecated", stack))
+                .collect();
+
+            (stacks, warnings)
+        }
+
+        fn is_deprecated(&self, stack: &str) -> bool {
+            self.deprecated.iter().any(|deprecated| deprecated == stack)
+        }
+    }
+}
+
+mod shim {
+    use super::{
+        cache::{Cache, MemoryCache},
+        hosts::AllowedHosts,
+        janitor::{Janitor, JanitorError},
+        models,
+        registry::{self, Registry, RegistryError},
+        scripts::ShimScripts,
+        signing::{self, Signature, Signer},
+        stacks::{self, Stacks},
+        telemetry,
+        transform::Transform,
+    };
+    use chrono::{SecondsFormat, Utc};
+    use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+    use libcnb::data::buildpack;
+    use log::{info, warn};
+    use sha2::{Digest, Sha256};
+    use std::{
+        fs,
+        io::{self, Cursor, Read, Write},
+        path::{Component, Path, PathBuf},
+        str::FromStr,
+        sync::Arc,
+    };
+    use tar::Archive;
+    use thiserror::Error;
+    use tokio::{sync::mpsc, task};
+    use tokio_stream::StreamExt;
+    use warp::hyper::body::Bytes;
+
+    const DEFAULT_API_VERSION: &str = "0.4";
+    /// First Buildpack API version with a `[[targets]]` table
+    const TARGETS_API_VERSION: (u32, u32) = (0, 10);
+    pub const DEFAULT_VERSION: &str = "0.1.0";
+    /// Downloaded chunks buffered ahead of the extraction
+    const DOWNLOAD_BUFFER_CHUNKS: usize = 16;
+    /// Scripts of the classic buildpack API a v2 buildpack can't run without
+    const V2_BUILDPACK_SCRIPTS: &[&str] = &["bin/detect", "bin/compile"];
+    const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+    const TAR_BLOCK_SIZE: usize = 512;
+    /// Bytes of a tar header holding its checksum
+    const TAR_CHECKSUM_FIELD: std::ops::Range<usize> = 148..156;
+    /// Asks for the latest release of a registry buildpack, which is also what an omitted
+    /// version does, except failing to resolve it is an error
+    const LATEST_VERSION: &str = "latest";
+    /// Table of `[metadata]` the shim records itself in
+    const SHIM_METADATA_KEY: &str = "cnb-shim";
+
+    /// Where the v2 buildpack to shim comes from
+    #[derive(Debug, Clone)]
+    pub enum Source {
+        /// The Heroku Buildpack Registry, by the id of the buildpack
+        Registry,
+        /// A tarball to download
+        Url(String),
+        /// A tarball that's already on disk, like an upload
+        File(PathBuf),
+    }
+
+    impl Source {
+        /// What kind of source this is, as recorded in the audit log
+        pub fn kind(&self) -> &'static str {
+            match self {
+                Source::Registry => "registry",
+                Source::Url(_) => "url",
+                Source::File(_) => "upload",
+            }
+        }
+    }
+
+    #[derive(Error, Debug)]
+    pub enum ShimError {
+        #[error("{0}")]
+        BadRequest(String),
+        #[error("{0}")]
+        Unavailable(String),
+        #[error("{0}")]
+        InsufficientStorage(String),
+        #[error("{0}")]
+        PayloadTooLarge(String),
+        #[error("{0}")]
+        Unprocessable(String),
+        #[error("{0}")]
+        Internal(String),
+    }
+
+    /// How large what goes into and comes out of a shim may get, in bytes
+    #[derive(Debug, Clone, Copy)]
+    pub struct Limits {
+        /// The v2 buildpack tarball, downloaded or uploaded
+        pub download_size: u64,
+        /// The v2 buildpack once unpacked, counted as its uncompressed tarball
+        pub extracted_size: u64,
+        /// The shimmed buildpack archive
+        pub artifact_size: u64,
+    }
+
+    /// A shimmed buildpack
+    #[derive(Debug)]
+    pub struct Artifact {
+        pub id: String,
+        pub version: String,
+        pub data: Bytes,
+        /// Where it's cached, if it is
+        pub key: Option<String>,
+        /// Its signature and signed provenance, when signing is set up
+        pub signature: Option<Signature>,
+        /// Things the requester should know about, like deprecated stacks
+        pub warnings: Vec<String>,
+    }
+
+    /// Turns v2 buildpacks into CNBs, independent of how the request for it arrived.
+    #[derive(Debug)]
+    pub struct Shimmer {
+        scripts: Arc<ShimScripts>,
+        janitor: Arc<Janitor>,
+        cache: Option<Arc<Cache>>,
+        memory_cache: Option<MemoryCache>,
+        registry: Registry,
+        signer: Option<Signer>,
+        stacks: Stacks,
+        limits: Limits,
+        client: reqwest::Client,
+        allowed_hosts: AllowedHosts,
+    }
+
+    impl Shimmer {
+        #[allow(clippy::too_many_arguments)]
+        pub fn new(
+            scripts: ShimScripts,
+            janitor: Arc<Janitor>,
+            cache: Option<Arc<Cache>>,
+            memory_cache: Option<MemoryCache>,
+            registry: Registry,
+            signer: Option<Signer>,
+            stacks: Stacks,
+            limits: Limits,
+            client: reqwest::Client,
+            allowed_hosts: AllowedHosts,
+        ) -> Self {
+            Shimmer {
+                scripts: Arc::new(scripts),
+                janitor,
+                cache,
+                memory_cache,
+                registry,
+                signer,
+                stacks,
+                limits,
+                client,
+                allowed_hosts,
+            }
+        }
+
+        pub fn scripts(&self) -> &Arc<ShimScripts> {
+            &self.scripts
+        }
+
+        pub fn limits(&self) -> Limits {
+            self.limits
+        }
+
+        pub fn janitor(&self) -> &Janitor {
+            &self.janitor
+        }
+
+        pub fn cache(&self) -> Option<&Cache> {
+            self.cache.as_deref()
+        }
+
+        pub fn memory_cache(&self) -> Option<&MemoryCache> {
+            self.memory_cache.as_ref()
+        }
+
+        pub fn registry(&self) -> &Registry {
+            &self.registry
+        }
+
+        pub fn allowed_hosts(&self) -> &AllowedHosts {
+            &self.allowed_hosts
+        }
+
+        /// Refuses tarball URLs on hosts that aren't allowed, before anything is fetched
+        pub fn check_source(&self, source: &Source) -> Result<(), ShimError> {
+            match source {
+                Source::Url(url) => self
+                    .allowed_hosts
+                    .check(url)
+                    .map_err(|err| ShimError::BadRequest(format!("Can't shim {}: {}", url, err))),
+                Source::Registry | Source::File(_) => Ok(()),
+            }
+        }
+
+        /// The latest release of a registry buildpack, which is what it's shimmed as without a
+        /// version
+        pub async fn resolve(&self, id: &str) -> Result<registry::Release, ShimError> {
+            buildpack::BuildpackId::from_str(id)
+                .map_err(|_| ShimError::BadRequest(String::from("invalid buildpack id")))?;
+
+            self.registry.latest_release(id).await.map_err(|err| {
+                ShimError::Unavailable(format!(
+                    "Can't resolve the latest release of {}: {}",
+                    id, err
+                ))
+            })
+        }
+
+        pub fn check_capacity(&self) -> Result<(), ShimError> {
+            self.janitor.check().map_err(|err| match err {
+                JanitorError::QuotaExceeded { .. } => {
+                    ShimError::Unavailable(format!("Not accepting new shims: {}", err))
+                }
+                JanitorError::InsufficientSpace { .. } => {
+                    ShimError::InsufficientStorage(format!("Not accepting new shims: {}", err))
+                }
+                JanitorError::IOError(_) => {
+                    ShimError::Internal(format!("Can't check disk space: {}", err))
+                }
+            })
+        }
+
+        /// A fresh directory in the work directory, removed when dropped
+        pub fn work_dir(&self) -> Result<tempfile::TempDir, ShimError> {
+            tempfile::Builder::new()
+                .prefix("shim-")
+                .tempdir_in(self.janitor.work_dir())
+                .map_err(|_| ShimError::Internal(String::from("Can't create tmp dir")))
+        }
+
+        /// Shims the v2 buildpack from `source` as the buildpack `id`, archived as `format`.
+        pub async fn shim(
+            &self,
+            id: &str,
+            source: &Source,
+            mut options: models::ShimOptions,
+            format: models::ArchiveFormat,
+        ) -> Result<Artifact, ShimError> {
+            self.check_source(source)?;
+
+            let id = buildpack::BuildpackId::from_str(id)
+                .map_err(|_| ShimError::BadRequest(String::from("invalid buildpack id")))?;
+            let transform = Transform::from_options(&options).map_err(ShimError::BadRequest)?;
+            let (stacks, warnings) = self.stacks.resolve(options.stacks.as_deref());
+            for warning in &warnings {
+                warn!("shimming {}: {}", id.as_str(), warning);
+            }
+            options.stacks = Some(stacks);
+            let v2_buildpack_url = match source {
+                Source::Registry => Some(
+                    telemetry::in_span(
+                        "registry.resolve",
+                        resolve_release(&self.registry, id.as_str(), &mut options),
+                    )
+                    .await?,
+                ),
+                Source::Url(url) => Some(url.clone()),
+                Source::File(_) => None,
+            };
+            if options.version.as_deref() == Some(LATEST_VERSION) {
+                return Err(ShimError::BadRequest(format!(
+                    "version={} is only supported for registry buildpacks",
+                    LATEST_VERSION
+                )));
+            }
+            let version = options
+                .version
+                .clone()
+                .unwrap_or_else(|| String::from(DEFAULT_VERSION));
+
+            // uploads are shimmed every time, there's nothing to identify them by
+            let artifact_key = v2_buildpack_url
+                .as_ref()
+                .map(|url| Cache::artifact_key(&(id.as_str(), url, &options), format.extension()));
+            if let Some(artifact_key) = &artifact_key {
+                if let Some(data) =
+                    telemetry::in_span("cache.lookup", self.cached(artifact_key)).await
+                {
+                    info!("serving {} from cache", id.as_str());
+                    let signature = match self.cached_signature(artifact_key).await {
+                        Some(signature) => Some(signature),
+                        None => self.sign(
+                            id.as_str(),
+                            format,
+                            &data,
+                            signing::Provenance::new(v2_buildpack_url.clone(), None),
+                        )?,
+                    };
+                    return Ok(Artifact {
+                        id: String::from(id.as_str()),
+                        version,
+                        data,
+                        key: Some(artifact_key.clone()).filter(|_| self.cache.is_some()),
+                        signature,
+                        warnings,
+                    });
+                }
+            }
+
+            // a full disk shouldn't stop cached buildpacks from being served
+            self.check_capacity()?;
+            let artifact_id = String::from(id.as_str());
+            let tmp_dir = self.work_dir()?;
+            let v2_buildpack_path = match source {
+                Source::File(path) => path.clone(),
+                _ => tmp_dir.path().join("buildpack.tgz"),
+            };
+            let target_dir = tmp_dir.path().join("buildpack").join("target");
+
+            // the registry metadata and the v2 buildpack are fetched concurrently
+            let (buildpack_toml, _) = tokio::try_join!(
+                buildpack_toml(
+                    id,
+                    options,
+                    matches!(source, Source::Registry).then(|| &self.registry),
+                    v2_buildpack_url.clone(),
+                ),
+                async {
+                    match &v2_buildpack_url {
+                        Some(url) => {
+                            self.fetch_v2_buildpack(url, &v2_buildpack_path, &target_dir)
+                                .await
+                        }
+                        None => extract_file(&v2_buildpack_path, &target_dir, self.limits).await,
+                    }
+                },
+            )?;
+
+            let data = Bytes::from(
+                self.assemble(tmp_dir.path(), &buildpack_toml, transform, format)
+                    .await?,
+            );
+            let signature = match &self.signer {
+                Some(_) => {
+                    let path = v2_buildpack_path.clone();
+                    let source_digest = task::spawn_blocking(move || file_digest(&path))
+                        .await
+                        .map_err(|_| ShimError::Internal(String::from("Can't read v2 buildpack")))?
+                        .map_err(|_| {
+                            ShimError::Internal(String::from("Can't read v2 buildpack"))
+                        })?;
+                    self.sign(
+                        &artifact_id,
+                        format,
+                        &data,
+                        signing::Provenance::new(v2_buildpack_url.clone(), Some(source_digest)),
+                    )?
+                }
+                None => None,
+            };
+
+            if let Some(artifact_key) = &artifact_key {
+                self.store(artifact_key, data.clone()).await;
+                let metadata = models::CacheMetadata {
+                    id: Some(artifact_id.clone()),
+                    url: v2_buildpack_url,
+                    version: Some(version.clone()),
+                };
+                if let Ok(metadata) = serde_json::to_vec(&metadata) {
+                    self.store(&Cache::metadata_key(artifact_key), Bytes::from(metadata))
+                        .await;
+                }
+                if let Some(signature) = &signature {
+                    self.store(
+                        &signing::signature_key(artifact_key),
+                        Bytes::from(signature.minisig.clone()),
+                    )
+                    .await;
+                    self.store(
+                        &signing::provenance_key(artifact_key),
+                        Bytes::from(signature.provenance.clone()),
+                    )
+                    .await;
+                }
+            }
+
+            Ok(Artifact {
+                id: artifact_id,
+                version,
+                data,
+                key: artifact_key.filter(|_| self.cache.is_some()),
+                signature,
+                warnings,
+            })
+        }
+
+        /// Signs a shimmed buildpack and a provenance statement for it, when there's a key
+        fn sign(
+            &self,
+            id: &str,
+            format: models::ArchiveFormat,
+            data: &[u8],
+            provenance: signing::Provenance,
+        ) -> Result<Option<Signature>, ShimError> {
+            let signer = match &self.signer {
+                Some(signer) => signer,
+                None => return Ok(None),
+            };
+
+            signer
+                .sign(&format!("{}.{}", id, format.extension()), data, &provenance)
+                .map(Some)
+                .map_err(|err| {
+                    ShimError::Internal(format!("Can't sign shimmed buildpack: {}", err))
+                })
+        }
+
+        /// A shimmed buildpack or its signatures from memory, or else from the cache, keeping
+        /// them in memory from then on
+        async fn cached(&self, key: &str) -> Option<Bytes> {
+            if let Some(data) = self
+                .memory_cache
+                .as_ref()
+                .and_then(|memory| memory.get(key))
+            {
+                return Some(data);
+            }
+
+            let data = Bytes::from(self.cache.as_ref()?.get(key).await?);
+            if let Some(memory_cache) = &self.memory_cache {
+                memory_cache.put(key, data.clone());
+            }
+
+            Some(data)
+        }
+
+        /// What the cache entry at `key` is of, when that was recorded along with it. Unlike
+        /// shimmed buildpacks, it isn't kept in memory once read from storage.
+        pub async fn cache_metadata(&self, key: &str) -> Option<models::CacheMetadata> {
+            let key = Cache::metadata_key(key);
+            let metadata = match self
+                .memory_cache
+                .as_ref()
+                .and_then(|memory| memory.get(&key))
+            {
+                Some(metadata) => metadata,
+                None => Bytes::from(self.cache.as_ref()?.get(&key).await?),
+            };
+
+            serde_json::from_slice(&metadata).ok()
+        }
+
+        /// Keeps a shimmed buildpack or its signatures in memory and in the cache
+        async fn store(&self, key: &str, data: Bytes) {
+            if let Some(memory_cache) = &self.memory_cache {
+                memory_cache.put(key, data.clone());
+            }
+            if let Some(cache) = &self.cache {
+                cache.put(key, data.to_vec()).await;
+            }
+        }
+
+        /// The signatures cached along with a cached shimmed buildpack, if both are there
+        async fn cached_signature(&self, artifact_key: &str) -> Option<Signature> {
+            self.signer.as_ref()?;
+            let minisig = self.cached(&signing::signature_key(artifact_key)).await?;
+            let provenance = self.cached(&signing::provenance_key(artifact_key)).await?;
+
+            Some(Signature {
+                minisig: String::from_utf8(minisig.to_vec()).ok()?,
+                provenance: String::from_utf8(provenance.to_vec()).ok()?,
+            })
+        }
+
+        /// Downloads a v2 buildpack tarball to `dst` and unpacks it into `target_dir`, going
+        /// through the cache when there is one.
+        async fn fetch_v2_buildpack(
+            &self,
+            url: &str,
+            dst: &Path,
+            target_dir: &Path,
+        ) -> Result<(), ShimError> {
+            let download_key = Cache::download_key(url);
+            let cached_download = match &self.cache {
+                Some(cache) => telemetry::in_span("cache.lookup", cache.get(&download_key)).await,
+                None => None,
+            };
+            let limits = self.limits;
+            if let Some(data) = cached_download {
+                let (dst, target_dir) = (dst.to_path_buf(), target_dir.to_path_buf());
+                let extraction = task::spawn_blocking(move || {
+                    fs::write(&dst, &data)?;
+                    untar(data.as_slice(), &target_dir, limits.extracted_size)
+                });
+                return telemetry::in_span("extract", extraction)
+                    .await
+                    .map_err(|_| ShimError::Internal(String::from("Could not untar v2 buildpack")))?
+                    .map_err(extract_error);
+            }
+
+            telemetry::in_span(
+                "download",
+                download_and_untar(&self.client, url, dst, target_dir, limits),
+            )
+            .await
+            .map_err(|err| match err {
+                DownloadError::IOError(_) => {
+                    ShimError::Internal(String::from("Could not untar v2 buildpack"))
+                }
+                DownloadError::ReqwestError(err) => match err.status() {
+                    // whatever answered isn't a v2 buildpack, like the error page of a missing
+                    // S3 key
+                    Some(status) => ShimError::Unprocessable(format!(
+                        "v2 buildpack download failed with {}",
+                        status
+                    )),
+                    None => ShimError::BadRequest(String::from("Can't download v2 buildpack")),
+                },
+                DownloadError::TooLarge(limit) => ShimError::PayloadTooLarge(format!(
+                    "v2 buildpack is larger than the download size limit of {} bytes",
+                    limit
+                )),
+                DownloadError::ExtractError(err) => extract_error(err),
+            })?;
+            // before it's cached, it would be served from there over and over otherwise
+            let unpacked = target_dir.to_path_buf();
+            task::spawn_blocking(move || {
+                hoist_single_root(&unpacked).map_err(|_| {
+                    ShimError::Internal(String::from("Could not untar v2 buildpack"))
+                })?;
+                validate_v2_buildpack(&unpacked)
+            })
+            .await
+            .map_err(|_| ShimError::Internal(String::from("Could not untar v2 buildpack")))??;
+            if let Some(cache) = &self.cache {
+                let path = dst.to_path_buf();
+                match task::spawn_blocking(move || fs::read(path)).await {
+                    Ok(Ok(data)) => {
+                        cache.put(&download_key, data).await;
+                        let metadata = models::CacheMetadata {
+                            url: Some(String::from(url)),
+                            ..models::CacheMetadata::default()
+                        };
+                        if let Ok(metadata) = serde_json::to_vec(&metadata) {
+                            cache
+                                .put(&Cache::metadata_key(&download_key), metadata)
+                                .await;
+                        }
+                    }
+                    Ok(Err(err)) => warn!("Could not read v2 buildpack for caching: {}", err),
+                    Err(err) => warn!("Could not read v2 buildpack for caching: {}", err),
+                }
+            }
+
+            Ok(())
+        }
+
+        /// Lays out the shimmed buildpack in `work_dir` around the v2 buildpack already
+        /// unpacked into its `target/`: the shim's bin scripts and its buildpack.toml, and
+        /// returns it archived as `format` once `transform` is applied to the v2 buildpack.
+        async fn assemble(
+            &self,
+            work_dir: &Path,
+            buildpack_toml: &str,
+            transform: Transform,
+            format: models::ArchiveFormat,
+        ) -> Result<Vec<u8>, ShimError> {
+            let shimmed_buildpack_dir = work_dir.join("buildpack");
+            let shimmed_buildpack_archive = work_dir.join(format!("shim.{}", format.extension()));
+            let artifact_size = self.limits.artifact_size;
+            let scripts = self.scripts.clone();
+            let buildpack_toml = String::from(buildpack_toml);
+            let archiving = task::spawn_blocking(move || {
+                scripts
+                    .install(shimmed_buildpack_dir.join("bin"))
+                    .map_err(|_| ShimError::Internal(String::from("Can't install shim scripts")))?;
+                fs::write(shimmed_buildpack_dir.join("buildpack.toml"), buildpack_toml).map_err(
+                    |_| ShimError::Internal(String::from("Can't write buildpack.toml to disk")),
+                )?;
+
+                let target_dir = shimmed_buildpack_dir.join("target");
+                hoist_single_root(&target_dir).map_err(|_| {
+                    ShimError::Internal(String::from("Could not untar v2 buildpack"))
+                })?;
+                transform.apply(&target_dir).map_err(|_| {
+                    ShimError::Internal(String::from("Could not transform v2 buildpack"))
+                })?;
+                validate_v2_buildpack(&target_dir)?;
+                archive(&shimmed_buildpack_archive, shimmed_buildpack_dir, format).map_err(
+                    |_| ShimError::Internal(String::from("Could not create shimmed tarball")),
+                )?;
+                // checked before it's read into memory
+                let size = fs::metadata(&shimmed_buildpack_archive)
+                    .map_err(|_| {
+                        ShimError::Internal(String::from("Could not read shimmed buildpack"))
+                    })?
+                    .len();
+                if size > artifact_size {
+                    return Err(ShimError::Unprocessable(format!(
+                        "shimmed buildpack is larger than the artifact size limit of {} bytes",
+                        artifact_size
+                    )));
+                }
+
+                fs::read(&shimmed_buildpack_archive).map_err(|_| {
+                    ShimError::Internal(String::from("Could not read shimmed buildpack"))
+                })
+            });
+
+            telemetry::in_span("archive", archiving)
+                .await
+                .map_err(|_| {
+                    ShimError::Internal(String::from("Could not create shimmed tarball"))
+                })?
+        }
+    }
+
+    /// Picks the release of a registry buildpack to shim and returns its tarball URL. Without
+    /// a version, or with `latest`, that's the latest release. A release number, as `42` or
+    /// `42.0.0`, has to be one of the registry's releases. The version of the release is
+    /// stamped into `options`, any other version is left as is on the registry's tarball.
+    async fn resolve_release(
+        registry: &Registry,
+        id: &str,
+        options: &mut models::ShimOptions,
+    ) -> Result<String, ShimError> {
+        let explicit = match options.version.as_deref() {
+            None => false,
+            Some(LATEST_VERSION) => true,
+            Some(version) => {
+                // anything but a release number is stamped on the registry's tarball as is
+                let number = match registry::release_number(version) {
+                    Some(number) => number,
+                    None => return Ok(registry.tarball_url(id)),
+                };
+                let release = registry
+                    .release(id, number)
+                    .await
+                    .map_err(|err| match err {
+                        RegistryError::NoSuchRelease(_) | RegistryError::NoReleases => {
+                            ShimError::BadRequest(format!("{} has no release {}", id, version))
+                        }
+                        RegistryError::ReqwestError(_) => ShimError::Unavailable(format!(
+                            "Can't look up release {} of {}: {}",
+                            version, id, err
+                        )),
+                    })?;
+                options.version = Some(release.version());
+                return Ok(release.tar_link);
+            }
+        };
+
+        match registry.latest_release(id).await {
+            Ok(release) => {
+                info!("resolved {} to release v{}", id, release.release);
+                options.version = Some(release.version());
+                Ok(release.tar_link)
+            }
+            Err(err) if explicit => Err(ShimError::Unavailable(format!(
+                "Can't resolve the latest release of {}: {}",
+                id, err
+            ))),
+            Err(err) => {
+                warn!(
+                    "Could not resolve the latest release of {}, stamping {}: {}",
+                    id, DEFAULT_VERSION, err
+                );
+                Ok(registry.tarball_url(id))
+            }
+        }
+    }
+
+    /// Validates the options and renders the buildpack.toml of the shim. Registry buildpacks
+    /// fill in the details the options leave out from `registry`. `[metadata.cnb-shim]`
+    /// records where the v2 buildpack came from.
+    async fn buildpack_toml(
+        id: buildpack::BuildpackId,
+        options: models::ShimOptions,
+        registry: Option<&Registry>,
+        source_url: Option<String>,
+    ) -> Result<String, ShimError> {
+        let version = buildpack::Version::parse(
+            &options
+                .version
+                .unwrap_or_else(|| String::from(DEFAULT_VERSION)),
+        )
+        .map_err(|err| ShimError::BadRequest(format!("invalid buildpack version: {:?}", err)))?;
+        let name = options.name.unwrap_or_else(|| String::from(id.as_str()));
+        let api_version = options
+            .api
+            .unwrap_or_else(|| String::from(DEFAULT_API_VERSION));
+        let api = buildpack::BuildpackApi::from_str(&api_version)
+            .map_err(|_| ShimError::BadRequest(String::from("invalid buildpack api")))?;
+        let targets = options
+            .targets
+            .unwrap_or_default()
+            .iter()
+            .map(|target| target.parse::<models::Target>())
+            .collect::<Result<Vec<models::Target>, String>>()
+            .map_err(ShimError::BadRequest)?;
+        if !targets.is_empty() && parse_api_version(&api_version) < Some(TARGETS_API_VERSION) {
+            return Err(ShimError::BadRequest(format!(
+                "targets require buildpack api {}.{} or newer, got {}",
+                TARGETS_API_VERSION.0, TARGETS_API_VERSION.1, api_version
+            )));
+        }
+        // libcnb has no wildcard stack id, it's written into buildpack.toml when rendering
+        let stacks = options.stacks.unwrap_or_default();
+        let any_stack = stacks.iter().any(|stack| stack == stacks::ANY_STACK);
+        let stacks = stacks
+            .iter()
+            .filter(|stack| *stack != stacks::ANY_STACK)
+            .map(|stack| {
+                Ok(buildpack::Stack {
+                    id: buildpack::StackId::from_str(stack)?,
+                    mixins: Vec::new(),
+                })
+            })
+            .collect::<Result<Vec<buildpack::Stack>, libcnb::Error>>()
+            .map_err(|_| ShimError::BadRequest(String::from("invalid stack")))?;
+
+        let mut metadata = match options.metadata {
+            Some(metadata) => match toml::Value::try_from(metadata) {
+                Ok(toml::Value::Table(metadata)) => metadata,
+                Ok(_) => toml::value::Table::new(),
+                Err(err) => {
+                    return Err(ShimError::BadRequest(format!("invalid metadata: {}", err)))
+                }
+            },
+            None => toml::value::Table::new(),
+        };
+        let shim_metadata = models::ShimMetadata {
+            source_url,
+            shimmed_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            shim_version: env!("CARGO_PKG_VERSION"),
+        };
+        metadata.insert(
+            String::from(SHIM_METADATA_KEY),
+            toml::Value::try_from(shim_metadata).map_err(|err| {
+                ShimError::Internal(format!("Can't convert shim metadata: {}", err))
+            })?,
+        );
+
+        let mut homepage = options.homepage;
+        let mut details = models::BuildpackDetails {
+            description: options.description,
+            keywords: options.keywords.unwrap_or_default(),
+            licenses: options
+                .licenses
+                .unwrap_or_default()
+                .iter()
+                .map(|license| models::License::from(license.as_str()))
+                .collect(),
+        };
+        let incomplete = homepage.is_none()
+            || details.description.is_none()
+            || details.keywords.is_empty()
+            || details.licenses.is_empty();
+        if let Some(registry) = registry.filter(|_| incomplete) {
+            match telemetry::in_span("registry.metadata", registry.info(id.as_str())).await {
+                Ok(info) => {
+                    homepage = homepage.or_else(|| info.homepage());
+                    details.description = details.description.or(info.description);
+                    if details.keywords.is_empty() {
+                        details.keywords = info
+                            .category
+                            .map(|category| category.name)
+                            .into_iter()
+                            .collect();
+                    }
+                    if details.licenses.is_empty() {
+                        details.licenses = info
+                            .license
+                            .iter()
+                            .map(|license| models::License::from(license.as_str()))
+                            .collect();
+                    }
+                }
+                Err(err) => warn!(
+                    "Could not fetch registry metadata for {}: {}",
+                    id.as_str(),
+                    err
+                ),
+            }
+        }
+
+        let buildpack_toml = buildpack::BuildpackToml {
+            api,
+            buildpack: buildpack::Buildpack {
+                id,
+                name,
+                version,
+                homepage,
+                clear_env: false,
+            },
+            stacks,
+            order: Vec::new(),
+            metadata,
+        };
+
+        render_buildpack_toml(&buildpack_toml, &details, &targets, any_stack).map_err(|err| {
+            ShimError::Internal(format!("Can't convert buildpack.toml to string: {:?}", err))
+        })
+    }
+
+    /// Serializes `buildpack.toml`, adding the tables libcnb doesn't model yet, and the
+    /// wildcard stack with `any_stack`.
+    fn render_buildpack_toml(
+        buildpack_toml: &buildpack::BuildpackToml,
+        details: &models::BuildpackDetails,
+        targets: &[models::Target],
+        any_stack: bool,
+    ) -> Result<String, toml::ser::Error> {
+        let mut document = toml::Value::try_from(buildpack_toml)?;
+        if let Some(table) = document.as_table_mut() {
+            if let (Some(toml::Value::Table(buildpack)), toml::Value::Table(details)) =
+                (table.get_mut("buildpack"), toml::Value::try_from(details)?)
+            {
+                buildpack.extend(details);
+            }
+            if !targets.is_empty() {
+                table.insert(String::from("targets"), toml::Value::try_from(targets)?);
+            }
+            if any_stack {
+                let mut stack = toml::value::Table::new();
+                stack.insert(
+                    String::from("id"),
+                    toml::Value::String(String::from(stacks::ANY_STACK)),
+                );
+                table.insert(
+                    String::from("stacks"),
+                    toml::Value::Array(vec![toml::Value::Table(stack)]),
+                );
+            }
+        }
+
+        toml::to_string(&document)
+    }
+
+    /// Parses the `major.minor` of a Buildpack API version for comparisons.
+    fn parse_api_version(api: &str) -> Option<(u32, u32)> {
+        let mut parts = api.splitn(2, '.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+
+        Some((major, minor))
+    }
+
+    /// Tarballs from GitHub and the like wrap the buildpack in a single directory. Moves its
+    /// contents up, so `bin/` ends up directly in `dir`.
+    fn hoist_single_root(dir: &Path) -> io::Result<()> {
+        if dir.join("bin").exists() {
+            return Ok(());
+        }
+
+        let entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        if let [entry] = entries.as_slice() {
+            if entry.file_type()?.is_dir() {
+                // out of the way first, the root may contain an entry with its own name
+                let root = dir.join(".cnb-shim-root");
+                fs::rename(entry.path(), &root)?;
+                for child in fs::read_dir(&root)? {
+                    let child = child?;
+                    fs::rename(child.path(), dir.join(child.file_name()))?;
+                }
+                fs::remove_dir(root)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Refuses to wrap anything that isn't a classic buildpack, which needs at least
+    /// `bin/detect` and `bin/compile` to run.
+    fn validate_v2_buildpack(dir: &Path) -> Result<(), ShimError> {
+        let missing = V2_BUILDPACK_SCRIPTS
+            .iter()
+            .filter(|script| !dir.join(script).is_file())
+            .copied()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(ShimError::Unprocessable(format!(
+                "v2 buildpack is missing {}",
+                missing.join(", ")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Downloads the v2 buildpack at `url` and unpacks it into `target_dir` as it arrives,
+    /// keeping a copy of the tarball at `dst`. The download runs on the runtime while a
+    /// blocking task decompresses and unpacks, so neither waits for the other to finish.
+    async fn download_and_untar(
+        client: &reqwest::Client,
+        url: &str,
+        dst: &Path,
+        target_dir: &Path,
+        limits: Limits,
+    ) -> Result<(), DownloadError> {
+        let response = client.get(url).send().await?.error_for_status()?;
+        // refused upfront when announced, otherwise once that much has arrived
+        if response.content_length().unwrap_or(0) > limits.download_size {
+            return Err(DownloadError::TooLarge(limits.download_size));
+        }
+        let (tx, rx) = mpsc::channel(DOWNLOAD_BUFFER_CHUNKS);
+        let (dst, target_dir) = (dst.to_path_buf(), target_dir.to_path_buf());
+        // extraction runs alongside the download, its span ends once it's done
+        let extracting = telemetry::span("extract");
+        let extraction = task::spawn_blocking(move || -> Result<(), ExtractError> {
+            let mut reader = TeeReader {
+                reader: ChannelReader::new(rx),
+                writer: fs::File::create(&dst)?,
+            };
+            untar(&mut reader, &target_dir, limits.extracted_size)?;
+            // the tarball may have trailing bytes tar doesn't read, keep the copy complete
+            io::copy(&mut reader, &mut io::sink())?;
+
+            Ok(())
+        });
+
+        let mut stream = response.bytes_stream();
+        let mut downloaded = Ok(());
+        let mut size = 0;
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    size += chunk.len() as u64;
+                    if size > limits.download_size {
+                        let _ = tx
+                            .send(Err(io::Error::new(
+                                io::ErrorKind::Other,
+                                "download too large",
+                            )))
+                            .await;
+                        downloaded = Err(DownloadError::TooLarge(limits.download_size));
+                        break;
+                    }
+                    // the extraction stopped early, its error is reported below
+                    if tx.send(Ok(chunk.to_vec())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    let _ = tx
+                        .send(Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "download interrupted",
+                        )))
+                        .await;
+                    downloaded = Err(DownloadError::ReqwestError(err));
+                    break;
+                }
+            }
+        }
+        drop(tx);
+
+        let extracted = extraction
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        drop(extracting);
+        downloaded?;
+        extracted?;
+
+        Ok(())
+    }
+
+    /// Unpacks an uploaded or otherwise local v2 buildpack tarball into `target_dir`
+    async fn extract_file(path: &Path, target_dir: &Path, limits: Limits) -> Result<(), ShimError> {
+        let size = fs::metadata(path)
+            .map_err(|_| ShimError::Internal(String::from("Could not untar v2 buildpack")))?
+            .len();
+        if size > limits.download_size {
+            return Err(ShimError::PayloadTooLarge(format!(
+                "v2 buildpack is larger than the upload size limit of {} bytes",
+                limits.download_size
+            )));
+        }
+
+        let (path, target_dir) = (path.to_path_buf(), target_dir.to_path_buf());
+        let extraction = task::spawn_blocking(move || {
+            untar(fs::File::open(&path)?, &target_dir, limits.extracted_size)
+        });
+        telemetry::in_span("extract", extraction)
+            .await
+            .map_err(|_| ShimError::Internal(String::from("Could not untar v2 buildpack")))?
+            .map_err(extract_error)
+    }
+
+    /// Unpacks a v2 buildpack tarball into `dst`, giving up once its uncompressed tarball
+    /// goes over `max_size` bytes. Its gzip magic and first tar header are checked before
+    /// anything is unpacked, so an error page served in its place is told apart from a
+    /// failure to write to disk.
+    fn untar(mut tar_gz: impl Read, dst: &Path, max_size: u64) -> Result<(), ExtractError> {
+        let mut magic = [0; 2];
+        if tar_gz.read_exact(&mut magic).is_err() || magic != GZIP_MAGIC {
+            return Err(ExtractError::NotATarball);
+        }
+        let mut tar = LimitedReader {
+            reader: GzDecoder::new(Cursor::new(magic).chain(tar_gz)),
+            remaining: max_size,
+            exceeded: false,
+        };
+
+        let mut header = [0; TAR_BLOCK_SIZE];
+        match tar.read_exact(&mut header) {
+            Ok(()) if is_tar_header(&header) => {}
+            Err(_) if tar.exceeded => return Err(ExtractError::TooLarge(max_size)),
+            _ => return Err(ExtractError::NotATarball),
+        }
+        let unpacked =
+            unpack_contained(&mut Archive::new(Cursor::new(header).chain(&mut tar)), dst);
+        if tar.exceeded {
+            return Err(ExtractError::TooLarge(max_size));
+        }
+
+        unpacked
+    }
+
+    /// Unpacks `archive` into `dst` like `Archive::unpack`, refusing symlinks and hardlinks
+    /// that are absolute or lead outside of `dst`. Directories are unpacked last so read-only
+    /// ones don't keep their contents out.
+    fn unpack_contained<R: Read>(archive: &mut Archive<R>, dst: &Path) -> Result<(), ExtractError> {
+        fs::create_dir_all(dst)?;
+        let mut dirs = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            if let Some(link) = entry.link_name()? {
+                // symlinks resolve from their own directory, hardlinks from the archive root
+                let base = match entry.header().entry_type() {
+                    tar::EntryType::Symlink => path.parent().unwrap_or_else(|| Path::new("")),
+                    _ => Path::new(""),
+                };
+                if !is_contained(base, &link) {
+                    return Err(ExtractError::EscapingLink(path, link.into_owned()));
+                }
+            }
+
+            if entry.header().entry_type() == tar::EntryType::Directory {
+                dirs.push(entry);
+            } else {
+                entry.unpack_in(dst)?;
+            }
+        }
+        for mut dir in dirs {
+            dir.unpack_in(dst)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether `link`, taken relative to `base` within an archive, stays inside of it
+    fn is_contained(base: &Path, link: &Path) -> bool {
+        let mut depth = 0usize;
+        for component in base.components().chain(link.components()) {
+            match component {
+                Component::Normal(_) => depth += 1,
+                Component::CurDir => {}
+                Component::ParentDir => match depth.checked_sub(1) {
+                    Some(parent) => depth = parent,
+                    None => return false,
+                },
+                Component::RootDir | Component::Prefix(_) => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Whether `block` holds a tar header with a valid checksum, or ends an empty archive
+    fn is_tar_header(block: &[u8; TAR_BLOCK_SIZE]) -> bool {
+        if block.iter().all(|byte| *byte == 0) {
+            return true;
+        }
+        // summed with the checksum field itself taken as spaces
+        let checksum = block
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| {
+                if TAR_CHECKSUM_FIELD.contains(&i) {
+                    u32::from(b' ')
+                } else {
+                    u32::from(*byte)
+                }
+            })
+            .sum::<u32>();
+
+        tar::Header::from_byte_slice(block)
+            .cksum()
+            .map_or(false, |stored| stored == checksum)
+    }
+
+    fn extract_error(err: ExtractError) -> ShimError {
+        match err {
+            ExtractError::IOError(_) => {
+                ShimError::Internal(String::from("Could not untar v2 buildpack"))
+            }
+            ExtractError::TooLarge(limit) => ShimError::Unprocessable(format!(
+                "v2 buildpack unpacks to more than the extracted size limit of {} bytes",
+                limit
+            )),
+            ExtractError::NotATarball => {
+                ShimError::Unprocessable(String::from("v2 buildpack is not a gzipped tarball"))
+            }
+            ExtractError::EscapingLink(path, link) => ShimError::Unprocessable(format!(
+                "v2 buildpack links {} to {}, outside of the buildpack",
+                path.display(),
+                link.display()
+            )),
+        }
+    }
+
+    /// Fails reads once more than `remaining` bytes were read, noting that's why.
+    struct LimitedReader<R> {
+        reader: R,
+        remaining: u64,
+        exceeded: bool,
+    }
+
+    impl<R: Read> Read for LimitedReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.reader.read(buf)?;
+            if read as u64 > self.remaining {
+                self.exceeded = true;
+                return Err(io::Error::new(io::ErrorKind::Other, "size limit exceeded"));
+            }
+            self.remaining -= read as u64;
+
+            Ok(read)
+        }
+    }
+
+    /// Reads the chunks of a download as they're sent from the runtime, for blocking code.
+    struct ChannelReader {
+        rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+        chunk: Vec<u8>,
+        pos: usize,
+    }
+
+    impl ChannelReader {
+        fn new(rx: mpsc::Receiver<io::Result<Vec<u8>>>) -> Self {
+            ChannelReader {
+                rx,
+                chunk: Vec::new(),
+                pos: 0,
+            }
+        }
+    }
+
+    impl Read for ChannelReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.pos == self.chunk.len() {
+                match self.rx.blocking_recv() {
+                    Some(chunk) => {
+                        self.chunk = chunk?;
+                        self.pos = 0;
+                    }
+                    None => return Ok(0),
+                }
+            }
+
+            let len = buf.len().min(self.chunk.len() - self.pos);
+            buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+            self.pos += len;
+
+            Ok(len)
+        }
+    }
+
+    /// Writes everything read through it to `writer`
+    struct TeeReader<R, W> {
+        reader: R,
+        writer: W,
+    }
+
+    impl<R: Read, W: Write> Read for TeeReader<R, W> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.reader.read(buf)?;
+            self.writer.write_all(&buf[..len])?;
+
+            Ok(len)
+        }
+    }
+
+    fn archive(
+        dst: impl AsRef<Path>,
+        src: impl AsRef<Path>,
+        format: models::ArchiveFormat,
+    ) -> Result<(), ArchiveError> {
+        let file = fs::File::create(dst.as_ref())?;
+        match format {
+            models::ArchiveFormat::TarGz => {
+                append_dir(GzEncoder::new(file, Compression::default()), src)?.finish()?;
+            }
+            models::ArchiveFormat::TarZst => {
+                append_dir(
+                    zstd::Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)?,
+                    src,
+                )?
+                .finish()?;
+            }
+            models::ArchiveFormat::Tar => {
+                append_dir(file, src)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a tarball of `src` into `writer`, handing the writer back so a compressor can be
+    /// finished explicitly and report its errors.
+    fn append_dir<W: Write>(writer: W, src: impl AsRef<Path>) -> Result<W, ArchiveError> {
+        let mut builder = tar::Builder::new(writer);
+        // links are archived as links, never as what they point to
+        builder.follow_symlinks(false);
+        builder.append_dir_all(".", src)?;
+
+        Ok(builder.into_inner()?)
+    }
+
+    /// `sha256:<hex>` of a file
+    fn file_digest(path: &Path) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+
+        Ok(format!("sha256:{}", hex::encode(hasher.finalize())))
+    }
+
+    #[derive(Error, Debug)]
+    enum DownloadError {
+        #[error("failed to write to disk")]
+        IOError(#[from] std::io::Error),
+        #[error("failed to download file")]
+        ReqwestError(#[from] reqwest::Error),
+        #[error("download is larger than {0} bytes")]
+        TooLarge(u64),
+        #[error("failed to unpack download")]
+        ExtractError(#[from] ExtractError),
+    }
+
+    #[derive(Error, Debug)]
+    enum ExtractError {
+        #[error("failed to write to disk")]
+        IOError(#[from] std::io::Error),
+        #[error("unpacks to more than {0} bytes")]
+        TooLarge(u64),
+        #[error("not a gzipped tarball")]
+        NotATarball,
+        #[error("links {0} outside of the tarball to {1}")]
+        EscapingLink(PathBuf, PathBuf),
+    }
+
+    #[derive(Error, Debug)]
+    enum ArchiveError {
+        #[error("failed to write to disk")]
+        IOError(#[from] std::io::Error),
+    }
+}
+
+mod migrate {
+    use super::shim::{Artifact, Source};
+    use flate2::{write::GzEncoder, Compression};
+    use serde::{Deserialize, Serialize};
+    use std::io::{self, Cursor, Write};
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    const DEFAULT_META_API_VERSION: &str = "0.4";
+
+    /// The classic buildpacks of a Heroku app, in order
+    #[derive(Debug)]
+    pub struct Migration {
+        pub buildpacks: Vec<BuildpackReference>,
+        pub stack: Option<String>,
+    }
+
+    /// A classic buildpack as listed by an app, and how to shim it
+    #[derive(Debug)]
+    pub struct BuildpackReference {
+        pub id: String,
+        pub source: Source,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct AppJson {
+        #[serde(default)]
+        buildpacks: Vec<AppJsonBuildpack>,
+        stack: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct AppJsonBuildpack {
+        url: String,
+    }
+
+    impl Migration {
+        /// Reads an `app.json`, or a `.buildpacks` file with one buildpack URL per line.
+        pub fn parse(content: &[u8]) -> Result<Self, String> {
+            let content = std::str::from_utf8(content)
+                .map_err(|_| String::from("app.json or .buildpacks must be UTF-8"))?;
+
+            let (urls, stack) = if content.trim_start().starts_with('{') {
+                let app_json = serde_json::from_str::<AppJson>(content)
+                    .map_err(|err| format!("invalid app.json: {}", err))?;
+                (
+                    app_json
+                        .buildpacks
+                        .into_iter()
+                        .map(|buildpack| buildpack.url)
+                        .collect::<Vec<_>>(),
+                    app_json.stack,
+                )
+            } else {
+                (
+                    content
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(String::from)
+                        .collect(),
+                    None,
+                )
+            };
+
+            Ok(Migration {
+                buildpacks: urls
+                    .iter()
+                    .map(|url| BuildpackReference::parse(url))
+                    .collect::<Result<_, _>>()?,
+                stack,
+            })
+        }
+    }
+
+    impl BuildpackReference {
+        /// Understands registry ids like `heroku/ruby`, GitHub repositories with an optional
+        /// `#<ref>`, and URLs of tarballs.
+        pub fn parse(url: &str) -> Result<Self, String> {
+            if !url.contains("://") {
+                return if url.split('/').count() == 2 {
+                    Ok(BuildpackReference {
+                        id: String::from(url),
+                        source: Source::Registry,
+                    })
+                } else {
+                    Err(format!("unsupported buildpack {}", url))
+                };
+            }
+
+            let (location, git_ref) = url.split_once('#').unwrap_or((url, "HEAD"));
+            let path = location
+                .split_once("://")
+                .map(|(_, path)| path)
+                .unwrap_or(location);
+            let segments = path
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .collect::<Vec<_>>();
+
+            match segments.as_slice() {
+                ["github.com", owner, repository, ..] => {
+                    let repository = repository.trim_end_matches(".git");
+                    Ok(BuildpackReference {
+                        id: format!(
+                            "{}/{}",
+                            sanitize(owner),
+                            sanitize(repository.trim_start_matches("heroku-buildpack-"))
+                        ),
+                        source: Source::Url(format!(
+                            "https://github.com/{}/{}/archive/{}.tar.gz",
+                            owner, repository, git_ref
+                        )),
+                    })
+                }
+                [.., namespace, file] if file.ends_with(".tgz") || file.ends_with(".tar.gz") => {
+                    let name = file.trim_end_matches(".tgz").trim_end_matches(".tar.gz");
+                    Ok(BuildpackReference {
+                        id: format!("{}/{}", sanitize(namespace), sanitize(name)),
+                        source: Source::Url(String::from(location)),
+                    })
+                }
+                _ => Err(format!("unsupported buildpack {}", url)),
+            }
+        }
+    }
+
+    /// Makes a URL segment usable as part of a buildpack id.
+    fn sanitize(segment: &str) -> String {
+        segment
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c.to_ascii_lowercase()
+                } else {
+                    '-'
+                }
+            })
+            .collect()
+    }
+
+    fn artifact_path(artifact: &Artifact) -> String {
+        format!("buildpacks/{}.tgz", artifact.id.replace('/', "_"))
+    }
+
+    #[derive(Debug, Serialize)]
+    struct ProjectToml {
+        #[serde(rename = "_")]
+        project: ProjectTable,
+        io: ProjectIo,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct ProjectTable {
+        #[serde(rename = "schema-version")]
+        schema_version: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct ProjectIo {
+        buildpacks: ProjectBuildpacks,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct ProjectBuildpacks {
+        group: Vec<ProjectGroupEntry>,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct ProjectGroupEntry {
+        id: String,
+        version: String,
+        uri: String,
+    }
+
+    /// A zip of a project.toml using the shimmed buildpacks, and the buildpacks themselves.
+    pub fn project_bundle(artifacts: &[Artifact]) -> io::Result<Vec<u8>> {
+        let project_toml = ProjectToml {
+            project: ProjectTable {
+                schema_version: String::from("0.2"),
+            },
+            io: ProjectIo {
+                buildpacks: ProjectBuildpacks {
+                    group: artifacts
+                        .iter()
+                        .map(|artifact| ProjectGroupEntry {
+                            id: artifact.id.clone(),
+                            version: artifact.version.clone(),
+                            uri: artifact_path(artifact),
+                        })
+                        .collect(),
+                },
+            },
+        };
+        let project_toml = toml::to_string(&project_toml)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+        zip_bundle(&project_toml, artifacts)
+    }
+
+    /// A zip of `project_toml` and the shimmed buildpacks it points at
+    fn zip_bundle(project_toml: &str, artifacts: &[Artifact]) -> io::Result<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("project.toml", FileOptions::default())?;
+        zip.write_all(project_toml.as_bytes())?;
+        // already compressed
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        for artifact in artifacts {
+            zip.start_file(artifact_path(artifact), stored)?;
+            zip.write_all(&artifact.data)?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// A buildpack of a project.toml that looks like a classic buildpack
+    #[derive(Debug)]
+    pub struct ProjectEntry {
+        /// Position in the project's buildpack group
+        pub index: usize,
+        pub id: String,
+        pub source: Source,
+        /// Whether it's only classic if the CNB registry doesn't have it
+        pub check_cnb_registry: bool,
+    }
+
+    /// A CNB project.toml, kept as parsed so rewriting it leaves everything else alone
+    #[derive(Debug)]
+    pub struct ProjectDescriptor {
+        toml: toml::Value,
+    }
+
+    impl ProjectDescriptor {
+        pub fn parse(content: &[u8]) -> Result<Self, String> {
+            let content = std::str::from_utf8(content)
+                .map_err(|_| String::from("project.toml must be UTF-8"))?;
+            let toml = content
+                .parse::<toml::Value>()
+                .map_err(|err| format!("invalid project.toml: {}", err))?;
+
+            Ok(ProjectDescriptor { toml })
+        }
+
+        /// The buildpack group, `[[io.buildpacks.group]]` as of schema 0.2 and
+        /// `[[build.buildpacks]]` before
+        fn group(&self) -> Option<&Vec<toml::Value>> {
+            let toml = &self.toml;
+            toml.get("io")
+                .and_then(|io| io.get("buildpacks"))
+                .and_then(|buildpacks| buildpacks.get("group"))
+                .or_else(|| toml.get("build").and_then(|build| build.get("buildpacks")))
+                .and_then(toml::Value::as_array)
+        }
+
+        fn group_mut(&mut self) -> Option<&mut Vec<toml::Value>> {
+            if self.group_is_v2() {
+                self.toml
+                    .get_mut("io")?
+                    .get_mut("buildpacks")?
+                    .get_mut("group")?
+                    .as_array_mut()
+            } else {
+                self.toml
+                    .get_mut("build")?
+                    .get_mut("buildpacks")?
+                    .as_array_mut()
+            }
+        }
+
+        fn group_is_v2(&self) -> bool {
+            self.toml
+                .get("io")
+                .and_then(|io| io.get("buildpacks"))
+                .and_then(|buildpacks| buildpacks.get("group"))
+                .is_some()
+        }
+
+        /// Entries that reference classic buildpacks: registry ids and `urn:cnb:registry`
+        /// URIs the CNB registry may not have, and URLs of `heroku-buildpack-*` repositories
+        /// or tarballs.
+        pub fn classic_candidates(&self) -> Vec<ProjectEntry> {
+            self.group()
+                .map(|group| {
+                    group
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(index, entry)| {
+                            let id = entry.get("id").and_then(toml::Value::as_str);
+                            let uri = entry.get("uri").and_then(toml::Value::as_str);
+                            let (id, source, check_cnb_registry) = match (id, uri) {
+                                (_, Some(uri)) if uri.starts_with("urn:cnb:registry:") => {
+                                    let id = uri.trim_start_matches("urn:cnb:registry:");
+                                    let id = id.split_once('@').map_or(id, |(id, _)| id);
+                                    (String::from(id), Source::Registry, true)
+                                }
+                                (_, Some(uri)) if uri.contains("heroku-buildpack-") => {
+                                    let reference = BuildpackReference::parse(uri).ok()?;
+                                    (reference.id, reference.source, false)
+                                }
+                                (Some(id), None) => (String::from(id), Source::Registry, true),
+                                _ => return None,
+                            };
+                            if id.split('/').count() != 2 {
+                                return None;
+                            }
+
+                            Some(ProjectEntry {
+                                index,
+                                id,
+                                source,
+                                check_cnb_registry,
+                            })
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+
+        /// Points the entry at `index` at a shimmed buildpack bundled alongside
+        pub fn rewrite(&mut self, index: usize, artifact: &Artifact) {
+            let entry = match self
+                .group_mut()
+                .and_then(|group| group.get_mut(index))
+                .and_then(toml::Value::as_table_mut)
+            {
+                Some(entry) => entry,
+                None => return,
+            };
+            entry.insert(String::from("id"), toml::Value::from(artifact.id.as_str()));
+            entry.insert(
+                String::from("version"),
+                toml::Value::from(artifact.version.as_str()),
+            );
+            entry.insert(
+                String::from("uri"),
+                toml::Value::from(artifact_path(artifact)),
+            );
+        }
+
+        /// A zip of the rewritten project.toml and the shimmed buildpacks
+        pub fn bundle(&self, artifacts: &[Artifact]) -> io::Result<Vec<u8>> {
+            let project_toml = toml::to_string(&self.toml)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+            zip_bundle(&project_toml, artifacts)
+        }
+    }
+
+    #[derive(Debug, Serialize)]
+    struct MetaBuildpackToml {
+        api: String,
+        buildpack: MetaBuildpack,
+        order: Vec<MetaOrder>,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct MetaBuildpack {
+        id: String,
+        version: String,
+        name: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct MetaOrder {
+        group: Vec<MetaGroupEntry>,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct MetaGroupEntry {
+        id: String,
+        version: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct PackageToml {
+        buildpack: PackageUri,
+        dependencies: Vec<PackageUri>,
+    }
+
+    #[derive(Debug, Serialize)]
+    struct PackageUri {
+        uri: String,
+    }
+
+    /// A tarball of a meta-buildpack running the shimmed buildpacks in order, with a
+    /// package.toml and the shimmed buildpacks, so `pack buildpack package` can turn it into
+    /// a single buildpackage.
+    pub fn meta_buildpack(
+        id: &str,
+        version: &str,
+        name: Option<&str>,
+        api: Option<&str>,
+        artifacts: &[Artifact],
+    ) -> io::Result<Vec<u8>> {
+        let to_io_error = |err: toml::ser::Error| io::Error::new(io::ErrorKind::Other, err);
+        let buildpack_toml = toml::to_string(&MetaBuildpackToml {
+            api: String::from(api.unwrap_or(DEFAULT_META_API_VERSION)),
+            buildpack: MetaBuildpack {
+                id: String::from(id),
+                version: String::from(version),
+                name: String::from(name.unwrap_or(id)),
+            },
+            order: vec![MetaOrder {
+                group: artifacts
+                    .iter()
+                    .map(|artifact| MetaGroupEntry {
+                        id: artifact.id.clone(),
+                        version: artifact.version.clone(),
+                    })
+                    .collect(),
+            }],
+        })
+        .map_err(to_io_error)?;
+        let package_toml = toml::to_string(&PackageToml {
+            buildpack: PackageUri {
+                uri: String::from("."),
+            },
+            dependencies: artifacts
+                .iter()
+                .map(|artifact| PackageUri {
+                    uri: artifact_path(artifact),
+                })
+                .collect(),
+        })
+        .map_err(to_io_error)?;
+
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        append_file(&mut builder, "buildpack.toml", buildpack_toml.as_bytes())?;
+        append_file(&mut builder, "package.toml", package_toml.as_bytes())?;
+        for artifact in artifacts {
+            append_file(&mut builder, &artifact_path(artifact), &artifact.data)?;
+        }
+
+        builder.into_inner()?.finish()
+    }
+
+    fn append_file<W: Write>(
+        builder: &mut tar::Builder<W>,
+        path: &str,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+
+        builder.append_data(&mut header, path, data)
+    }
+}
+
+mod warm {
+    use super::{
+        models,
+        shim::{Shimmer, Source},
+    };
+    use log::{error, info, warn};
+    use serde::Deserialize;
+    use std::{
+        fs, io,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::Duration,
+    };
+    use thiserror::Error;
+    use tokio::sync::Semaphore;
+
+    /// Buildpacks to have shimmed before anyone asks for them
+    #[derive(Debug, Deserialize)]
+    pub struct Manifest {
+        #[serde(default)]
+        pub buildpacks: Vec<models::BatchSpec>,
+    }
+
+    impl Manifest {
+        pub fn load(path: &Path) -> Result<Self, WarmError> {
+            Ok(toml::from_slice(&fs::read(path)?)?)
+        }
+    }
+
+    /// Shims every buildpack of `manifest`, `concurrency` at a time, which leaves them in the
+    /// cache. Returns how many failed.
+    pub async fn warm(shimmer: &Arc<Shimmer>, manifest: &Manifest, concurrency: usize) -> usize {
+        let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+        let mut shims = Vec::new();
+        for spec in &manifest.buildpacks {
+            let (shimmer, semaphore) = (shimmer.clone(), semaphore.clone());
+            let id = spec.id.clone();
+            let source = spec
+                .url
+                .clone()
+                .map(Source::Url)
+                .unwrap_or(Source::Registry);
+            let options = spec.options.clone();
+            shims.push(tokio::spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let format = options.format.unwrap_or(models::ArchiveFormat::TarGz);
+                match shimmer.shim(&id, &source, options, format).await {
+                    Ok(artifact) => {
+                        info!("warmed {} {}", artifact.id, artifact.version);
+                        true
+                    }
+                    Err(err) => {
+                        warn!("Could not warm {}: {}", id, err);
+                        false
+                    }
+                }
+            }));
+        }
+
+        let mut failed = 0;
+        for shim in shims {
+            if !shim.await.unwrap_or(false) {
+                failed += 1;
+            }
+        }
+        info!(
+            "warmed {} of {} buildpacks",
+            manifest.buildpacks.len() - failed,
+            manifest.buildpacks.len()
+        );
+
+        failed
+    }
+
+    /// Warms from the manifest at `path` now and then every `interval`, if there is one. The
+    /// manifest is reloaded every time, so it can change without a restart.
+    pub async fn run(
+        shimmer: Arc<Shimmer>,
+        path: PathBuf,
+        interval: Option<Duration>,
+        concurrency: usize,
+    ) {
+        loop {
+            match Manifest::load(&path) {
+                Ok(manifest) => {
+                    warm(&shimmer, &manifest, concurrency).await;
+                }
+                Err(err) => error!("Could not load {}: {}", path.display(), err),
+            }
+
+            match interval {
+                Some(interval) => tokio::time::sleep(interval).await,
+                None => break,
+            }
+        }
+    }
+
+    #[derive(Error, Debug)]
+    pub enum WarmError {
+        #[error("failed to read manifest: {0}")]
+        IOError(#[from] io::Error),
+        #[error("invalid manifest: {0}")]
+        TomlError(#[from] toml::de::Error),
+    }
+}
+
+mod batch {
+    use super::{models::ArchiveFormat, shim::Artifact};
+    use serde::Serialize;
+    use std::io::{self, Cursor, Write};
+    use tokio::task::JoinHandle;
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    /// Most buildpacks shimmed in one batch
+    pub const MAX_BATCH_SIZE: usize = 32;
+    /// File of a batch zip listing the buildpacks that failed
+    const ERRORS_FILE: &str = "errors.json";
+
+    /// A buildpack of a batch that failed, with the status code shimming it would have had
+    #[derive(Debug, Serialize)]
+    pub struct Failure {
+        pub id: String,
+        pub status: u16,
+        pub error: String,
+    }
+
+    /// The shims of a batch, aborted once nobody waits on them anymore
+    #[derive(Debug, Default)]
+    pub struct Tasks(Vec<JoinHandle<()>>);
+
+    impl Tasks {
+        pub fn push(&mut self, handle: JoinHandle<()>) {
+            self.0.push(handle);
+        }
+    }
+
+    impl Drop for Tasks {
+        fn drop(&mut self) {
+            for handle in &self.0 {
+                handle.abort();
+            }
+        }
+    }
+
+    /// File name of a shimmed buildpack in a batch, unique by id and version
+    pub fn file_name(artifact: &Artifact, format: ArchiveFormat) -> String {
+        format!(
+            "{}-{}.{}",
+            artifact.id.replace('/', "_"),
+            artifact.version,
+            format.extension()
+        )
+    }
+
+    /// A zip of shimmed buildpacks, in order, and of the failures among them if there are any
+    pub fn zip(
+        artifacts: &[(Artifact, ArchiveFormat)],
+        failures: &[Failure],
+    ) -> io::Result<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        // already compressed
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        for (artifact, format) in artifacts {
+            zip.start_file(file_name(artifact, *format), stored)?;
+            zip.write_all(&artifact.data)?;
+        }
+        if !failures.is_empty() {
+            zip.start_file(ERRORS_FILE, FileOptions::default())?;
+            serde_json::to_writer_pretty(&mut zip, failures)?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// A body part of a `multipart/mixed` response
+    pub fn part(boundary: &str, headers: &[(&str, String)], body: &[u8]) -> Vec<u8> {
+        let mut part = format!("--{}\r\n", boundary);
+        for (name, value) in headers {
+            part.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        part.push_str("\r\n");
+
+        let mut part = part.into_bytes();
+        part.extend_from_slice(body);
+        part.extend_from_slice(b"\r\n");
+        part
+    }
+
+    /// The end of a `multipart/mixed` response
+    pub fn closing(boundary: &str) -> Vec<u8> {
+        format!("--{}--\r\n", boundary).into_bytes()
+    }
+}
+
+mod models {
+    use serde::{Deserialize, Deserializer, Serialize};
+    use std::str::FromStr;
+
+    /// `os` values of a target, from the Go `GOOS` values the CNB spec uses
+    const OPERATING_SYSTEMS: &[&str] = &["linux", "windows"];
+    /// `arch` values of a target, from the Go `GOARCH` values the CNB spec uses
+    const ARCHITECTURES: &[&str] = &["386", "amd64", "arm", "arm64", "ppc64le", "s390x"];
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct ShimOptions {
+        /// Release of a registry buildpack to shim, as `42`, `42.0.0` or `latest`. Any other
+        /// version is stamped as is, as it is on buildpacks from a URL or an upload.
+        pub version: Option<String>,
+        pub name: Option<String>,
+        pub api: Option<String>,
+        /// Stack ids, `heroku-all` for the Heroku stacks that aren't deprecated or `*` for any
+        #[serde(default, deserialize_with = "comma_separated")]
+        pub stacks: Option<Vec<String>>,
+        #[serde(default, deserialize_with = "comma_separated")]
+        pub targets: Option<Vec<String>>,
+        pub homepage: Option<String>,
+        pub description: Option<String>,
+        #[serde(default, deserialize_with = "comma_separated")]
+        pub keywords: Option<Vec<String>>,
+        /// SPDX license identifiers or URLs of license texts
+        #[serde(default, deserialize_with = "comma_separated")]
+        pub licenses: Option<Vec<String>>,
+        /// Takes precedence over the `Accept-Encoding` header
+        pub format: Option<ArchiveFormat>,
+        /// Globs of paths in the v2 buildpack to leave out, like `test/**`
+        #[serde(
+            default,
+            deserialize_with = "comma_separated",
+            skip_serializing_if = "Option::is_none"
+        )]
+        pub exclude: Option<Vec<String>>,
+        /// Leave out `.git` and other version control directories
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub strip_vcs: Option<bool>,
+        /// Interpreter to rewrite the shebangs of the v2 buildpack's `bin/` scripts to, like
+        /// `/usr/bin/env bash`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub shebang: Option<String>,
+        /// Merged into `[metadata]` of buildpack.toml, except for the `cnb-shim` table the
+        /// shim fills in itself
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    }
+
+    /// Metadata from `<key>=<value>` pairs, where dots in keys nest tables
+    pub fn metadata_from_pairs(
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Option<serde_json::Map<String, serde_json::Value>> {
+        let mut metadata = serde_json::Map::new();
+        for (key, value) in pairs {
+            let mut path = key.split('.').filter(|part| !part.is_empty()).peekable();
+            let mut table = &mut metadata;
+            while let Some(part) = path.next() {
+                if path.peek().is_none() {
+                    table.insert(String::from(part), serde_json::Value::String(value));
+                    break;
+                }
+                let entry = table
+                    .entry(part)
+                    .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+                if !entry.is_object() {
+                    *entry = serde_json::Value::Object(serde_json::Map::new());
+                }
+                table = match entry {
+                    serde_json::Value::Object(nested) => nested,
+                    _ => unreachable!(),
+                };
+            }
+        }
+
+        Some(metadata).filter(|metadata| !metadata.is_empty())
+    }
+
+    /// What the shim records about itself in `[metadata.cnb-shim]` of buildpack.toml
+    #[derive(Debug, Serialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub struct ShimMetadata {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub source_url: Option<String>,
+        pub shimmed_at: String,
+        pub shim_version: &'static str,
+    }
+
+    /// The archive formats a shimmed buildpack can be served in
+    #[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+    pub enum ArchiveFormat {
+        #[serde(rename = "tgz", alias = "tar.gz")]
+        TarGz,
+        #[serde(rename = "tar.zst")]
+        TarZst,
+        #[serde(rename = "tar")]
+        Tar,
+    }
+
+    impl ArchiveFormat {
+        pub fn extension(self) -> &'static str {
+            match self {
+                ArchiveFormat::TarGz => "tgz",
+                ArchiveFormat::TarZst => "tar.zst",
+                ArchiveFormat::Tar => "tar",
+            }
+        }
+
+        /// The format of an archive by the extension of its file name
+        pub fn from_file_name(file_name: &str) -> Option<Self> {
+            [
+                ArchiveFormat::TarGz,
+                ArchiveFormat::TarZst,
+                ArchiveFormat::Tar,
+            ]
+            .iter()
+            .copied()
+            .find(|format| file_name.ends_with(&format!(".{}", format.extension())))
+        }
+
+        pub fn content_type(self) -> &'static str {
+            match self {
+                ArchiveFormat::TarGz => "application/x-gzip",
+                ArchiveFormat::TarZst => "application/zstd",
+                ArchiveFormat::Tar => "application/x-tar",
+            }
+        }
+
+        /// Picks a format from an `Accept-Encoding` header by quality value. gzip wins ties so
+        /// clients that merely advertise zstd support keep getting the tarballs they always got,
+        /// as do clients accepting none of the formats by name. `None` when the header refuses
+        /// gzip and identity without accepting zstd.
+        pub fn negotiate(accept_encoding: &str) -> Option<Self> {
+            let codings = accept_encoding
+                .split(',')
+                .filter_map(|coding| {
+                    let mut params = coding.split(';').map(str::trim);
+                    let coding = params.next().filter(|coding| !coding.is_empty())?;
+                    let quality = params
+                        .find_map(|param| param.strip_prefix("q="))
+                        .map_or(Some(1.0), |quality| quality.parse::<f32>().ok())?;
+
+                    Some((coding, quality))
+                })
+                .collect::<Vec<_>>();
+            // codings the header doesn't name fall under `*` when it has one
+            let quality = |format: Self| {
+                let coding = format.coding();
+                codings
+                    .iter()
+                    .find(|(named, _)| named.eq_ignore_ascii_case(coding))
+                    .or_else(|| codings.iter().find(|(named, _)| *named == "*"))
+                    .map(|(_, quality)| *quality)
+            };
+
+            [
+                ArchiveFormat::TarGz,
+                ArchiveFormat::TarZst,
+                ArchiveFormat::Tar,
+            ]
+            .iter()
+            .filter_map(|format| Some((*format, quality(*format)?)))
+            .filter(|(_, quality)| *quality > 0.0)
+            .fold(
+                None,
+                |best: Option<(Self, f32)>, (format, quality)| match best {
+                    Some((best_format, best_quality))
+                        if best_quality > quality
+                            || (best_quality == quality
+                                && best_format.preference() <= format.preference()) =>
+                    {
+                        best
+                    }
+                    _ => Some((format, quality)),
+                },
+            )
+            .map(|(format, _)| format)
+            .or_else(|| {
+                [ArchiveFormat::TarGz, ArchiveFormat::Tar]
+                    .iter()
+                    .copied()
+                    .find(|format| quality(*format) != Some(0.0))
+            })
+        }
+
+        /// The content coding of `Accept-Encoding` the format corresponds to
+        fn coding(self) -> &'static str {
+            match self {
+                ArchiveFormat::TarGz => "gzip",
+                ArchiveFormat::TarZst => "zstd",
+                ArchiveFormat::Tar => "identity",
+            }
+        }
+
+        /// Lower is preferred when clients accept several formats equally
+        fn preference(self) -> u8 {
+            match self {
+                ArchiveFormat::TarGz => 0,
+                ArchiveFormat::TarZst => 1,
+                ArchiveFormat::Tar => 2,
+            }
+        }
+    }
+
+    /// Where a cache entry is kept
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum CacheTier {
+        Memory,
+        Storage,
+    }
+
+    /// An entry of GET /admin/cache
+    #[derive(Debug, Serialize)]
+    pub struct CacheEntry {
+        pub key: String,
+        pub tier: CacheTier,
+        pub size: u64,
+        /// Seconds since it was cached, when the storage backend knows
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub age: Option<u64>,
+        #[serde(flatten)]
+        pub metadata: CacheMetadata,
+    }
+
+    /// What a cache entry is of, kept next to it since its key doesn't tell
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    pub struct CacheMetadata {
+        /// Id of the shimmed buildpack
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub id: Option<String>,
+        /// The v2 buildpack tarball it was downloaded or shimmed from
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub url: Option<String>,
+        /// Version of the shimmed buildpack
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub version: Option<String>,
+    }
+
+    /// Cache entries removed by DELETE /admin/cache or POST /admin/gc, by tier
+    #[derive(Debug, Default, Serialize)]
+    pub struct CacheRemoval {
+        pub memory: usize,
+        pub storage: usize,
+    }
+
+    /// What POST /admin/gc cleaned up
+    #[derive(Debug, Serialize)]
+    pub struct GcReport {
+        /// Orphaned entries removed from the work directory
+        pub removed: usize,
+        /// Bytes freed in the work directory
+        pub freed: u64,
+        /// Bytes still in use in the work directory
+        pub usage: u64,
+        /// Cache entries past the TTL that were removed
+        pub expired: CacheRemoval,
+    }
+
+    /// Where to notify the caller once a shim finishes or fails. Kept apart from the shim
+    /// options so they don't change what gets cached.
+    #[derive(Debug, Default, Deserialize)]
+    pub struct CallbackOptions {
+        pub callback_url: Option<String>,
+        /// Signs notifications with HMAC-SHA256 when given. Never taken from the query string.
+        pub callback_secret: Option<String>,
+    }
+
+    /// The JSON body of a POST shim request
+    #[derive(Debug, Deserialize)]
+    pub struct ShimBody {
+        #[serde(flatten)]
+        pub shim: ShimOptions,
+        #[serde(flatten)]
+        pub callback: CallbackOptions,
+    }
+
+    /// Options of an uploaded buildpack, which has no registry id to go by
+    #[derive(Debug, Deserialize)]
+    pub struct UploadOptions {
+        pub id: Option<String>,
+        #[serde(flatten)]
+        pub shim: ShimOptions,
+    }
+
+    /// Options of a migration. The shim options apply to every migrated buildpack, the meta
+    /// options to the meta-buildpack composing them.
+    #[derive(Debug, Deserialize)]
+    pub struct MigrateOptions {
+        #[serde(default)]
+        pub output: MigrateOutput,
+        #[serde(default = "default_meta_id")]
+        pub meta_id: String,
+        #[serde(default = "default_meta_version")]
+        pub meta_version: String,
+        pub meta_name: Option<String>,
+        #[serde(flatten)]
+        pub shim: ShimOptions,
+    }
+
+    /// Buildpacks to shim in one request
+    #[derive(Debug, Deserialize)]
+    pub struct BatchRequest {
+        pub buildpacks: Vec<BatchSpec>,
+        #[serde(default)]
+        pub output: BatchOutput,
+    }
+
+    /// A buildpack of a batch or a warm manifest, with the same options as shimming it on its
+    /// own
+    #[derive(Debug, Deserialize)]
+    pub struct BatchSpec {
+        pub id: String,
+        /// v2 buildpack tarball to shim, the registry's for `id` when not given
+        pub url: Option<String>,
+        #[serde(flatten)]
+        pub options: ShimOptions,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum BatchOutput {
+        /// A zip of the shimmed buildpacks, with an `errors.json` listing the ones that failed.
+        /// It fails as a whole when they add up to more than the artifact size limit.
+        Zip,
+        /// A `multipart/mixed` stream with a part for each buildpack as soon as it's shimmed,
+        /// or failed to be
+        Multipart,
+    }
+
+    impl Default for BatchOutput {
+        fn default() -> Self {
+            BatchOutput::Zip
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum MigrateOutput {
+        /// A zip of a project.toml and the shimmed buildpacks it references
+        Project,
+        /// A meta-buildpack ordering the shimmed buildpacks, ready for `pack buildpack package`
+        Meta,
+    }
+
+    impl Default for MigrateOutput {
+        fn default() -> Self {
+            MigrateOutput::Project
+        }
+    }
+
+    fn default_meta_id() -> String {
+        String::from("cnb-shim/migrated")
+    }
+
+    fn default_meta_version() -> String {
+        String::from("0.1.0")
+    }
+
+    /// Keys of the `[buildpack]` table of buildpack.toml that libcnb doesn't model
+    #[derive(Debug, Serialize)]
+    pub struct BuildpackDetails {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub description: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        pub keywords: Vec<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        pub licenses: Vec<License>,
+    }
+
+    /// A `[[buildpack.licenses]]` entry of buildpack.toml
+    #[derive(Debug, Serialize)]
+    pub struct License {
+        #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+        pub license_type: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub uri: Option<String>,
+    }
+
+    impl From<&str> for License {
+        /// URLs become the `uri` of a license, anything else is taken to be an SPDX identifier.
+        fn from(license: &str) -> Self {
+            if license.starts_with("https://") || license.starts_with("http://") {
+                License {
+                    license_type: None,
+                    uri: Some(String::from(license)),
+                }
+            } else {
+                License {
+                    license_type: Some(String::from(license)),
+                    uri: None,
+                }
+            }
+        }
+    }
+
+    /// A `[[targets]]` entry of buildpack.toml, parsed from `os/arch[/variant]`
+    #[derive(Debug, Serialize)]
+    pub struct Target {
+        pub os: String,
+        pub arch: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub variant: Option<String>,
+    }
+
+    impl FromStr for Target {
+        type Err = String;
+
+        fn from_str(target: &str) -> Result<Self, Self::Err> {
+            let mut parts = target.split('/');
+            let (os, arch, variant) = match (parts.next(), parts.next(), parts.next(), parts.next())
+            {
+                (Some(os), Some(arch), variant, None) => (os, arch, variant),
+                _ => {
+                    return Err(format!(
+                        "invalid target {}, expected os/arch[/variant]",
+                        target
+                    ))
+                }
+            };
+
+            if !OPERATING_SYSTEMS.contains(&os) {
+                return Err(format!("invalid target {}, unknown os {}", target, os));
+            }
+            if !ARCHITECTURES.contains(&arch) {
+                return Err(format!("invalid target {}, unknown arch {}", target, arch));
+            }
+            if variant == Some("") {
+                return Err(format!("invalid target {}, empty variant", target));
+            }
+
+            Ok(Target {
+                os: String::from(os),
+                arch: String::from(arch),
+                variant: variant.map(String::from),
+            })
+        }
+    }
+
+    /// Accepts a list or a comma separated string, so an option can be passed as
+    /// `?targets=linux/amd64,linux/arm64` as well as a list in a JSON body.
+    fn comma_separated<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+            Some(OneOrMany::One(value)) => Some(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(String::from)
+                    .collect(),
+            ),
+            Some(OneOrMany::Many(values)) => Some(values),
+            None => None,
+        })
+    }
+}
+
+mod registry {
+    use serde::Deserialize;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+    use thiserror::Error;
+
+    /// Where the Heroku Buildpack Registry publishes v2 buildpack tarballs
+    pub const V2_BUILDPACK_REGISTRY_URL: &str =
+        "https://buildpack-registry.s3.amazonaws.com/buildpacks";
+    /// The Heroku Buildpack Registry API
+    pub const REGISTRY_API_URL: &str = "https://buildpack-registry.heroku.com";
+    /// The Cloud Native Buildpacks registry API, to tell CNBs from classic buildpacks
+    pub const CNB_REGISTRY_API_URL: &str = "https://registry.buildpacks.io/api/v1";
+    const REGISTRY_API_ACCEPT: &str = "application/vnd.heroku+json; version=3.buildpack-registry";
+    /// How long the releases of a buildpack are reused before they're looked up again, so
+    /// versionless shims don't each wait on the registry
+    const RELEASES_TTL: Duration = Duration::from_secs(60);
+    /// Buildpacks whose releases are kept at most, the ones looked up longest ago go first
+    const MAX_CACHED_RELEASES: usize = 1024;
+
+    /// A buildpack as described by the Heroku Buildpack Registry API. Everything is optional so
+    /// a buildpack with sparse metadata still deserializes.
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(default)]
+    pub struct BuildpackInfo {
+        pub description: Option<String>,
+        pub category: Option<Category>,
+        pub license: Option<String>,
+        pub support: Option<Support>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Category {
+        pub name: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Support {
+        pub method: String,
+        pub url: String,
+    }
+
+    impl BuildpackInfo {
+        /// The support URL, when support is offered through a website
+        pub fn homepage(&self) -> Option<String> {
+            self.support
+                .as_ref()
+                .filter(|support| support.method == "website")
+                .map(|support| support.url.clone())
+        }
+    }
+
+    /// A published release of a buildpack, as listed by its revisions
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct Release {
+        pub release: u64,
+        pub tar_link: String,
+    }
+
+    impl Release {
+        /// Registry releases are plain numbers, CNB versions have to be semver
+        pub fn version(&self) -> String {
+            format!("{}.0.0", self.release)
+        }
+    }
+
+    /// The release a version refers to, as the plain number or as its `version()`
+    pub fn release_number(version: &str) -> Option<u64> {
+        version.strip_suffix(".0.0").unwrap_or(version).parse().ok()
+    }
+
+    #[derive(Error, Debug)]
+    pub enum RegistryError {
+        #[error("{0}")]
+        ReqwestError(#[from] reqwest::Error),
+        #[error("buildpack has no releases")]
+        NoReleases,
+        #[error("buildpack has no release {0}")]
+        NoSuchRelease(u64),
+    }
+
+    /// The Heroku Buildpack Registry, or anything serving the same tarballs and API
+    #[derive(Debug, Clone)]
+    pub struct Registry {
+        tarballs_url: String,
+        api_url: String,
+        cnb_api_url: String,
+        client: reqwest::Client,
+        /// Releases by buildpack id, with when they were looked up
+        releases: Arc<Mutex<HashMap<String, (Instant, Vec<Release>)>>>,
+    }
+
+    impl Registry {
+        pub fn new(
+            tarballs_url: impl Into<String>,
+            api_url: impl Into<String>,
+            cnb_api_url: impl Into<String>,
+            client: reqwest::Client,
+        ) -> Self {
+            Registry {
+                tarballs_url: tarballs_url.into().trim_end_matches('/').to_string(),
+                api_url: api_url.into().trim_end_matches('/').to_string(),
+                cnb_api_url: cnb_api_url.into().trim_end_matches('/').to_string(),
+                client,
+                releases: Arc::default(),
+            }
+        }
+
+        /// Whether the CNB registry has a buildpack by this id
+        pub async fn cnb_exists(&self, id: &str) -> Result<bool, reqwest::Error> {
+            let response = self
+                .client
+                .get(format!("{}/buildpacks/{}", self.cnb_api_url, id))
+                .send()
+                .await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(false);
+            }
+            response.error_for_status()?;
+
+            Ok(true)
+        }
+
+        /// Checks the registry API answers at all, within a few seconds.
+        pub async fn ping(&self) -> Result<(), reqwest::Error> {
+            self.client
+                .head(&self.api_url)
+                .timeout(Duration::from_secs(5))
+                .send()
+                .await?;
+
+            Ok(())
+        }
+
+        pub fn tarball_url(&self, id: &str) -> String {
+            format!("{}/{}.tgz", self.tarballs_url, id)
+        }
+
+        pub async fn info(&self, id: &str) -> Result<BuildpackInfo, reqwest::Error> {
+            self.client
+                .get(format!(
+                    "{}/buildpacks/{}",
+                    self.api_url,
+                    id.replace('/', "%2F")
+                ))
+                .header(reqwest::header::ACCEPT, REGISTRY_API_ACCEPT)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+        }
+
+        /// The most recent release of a buildpack
+        pub async fn latest_release(&self, id: &str) -> Result<Release, RegistryError> {
+            self.releases(id)
+                .await?
+                .into_iter()
+                .max_by_key(|release| release.release)
+                .ok_or(RegistryError::NoReleases)
+        }
+
+        /// A specific release of a buildpack
+        pub async fn release(&self, id: &str, release: u64) -> Result<Release, RegistryError> {
+            self.releases(id)
+                .await?
+                .into_iter()
+                .find(|candidate| candidate.release == release)
+                .ok_or(RegistryError::NoSuchRelease(release))
+        }
+
+        /// Every release of a buildpack, reused for `RELEASES_TTL` once looked up
+        async fn releases(&self, id: &str) -> Result<Vec<Release>, RegistryError> {
+            let cached = self
+                .releases
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .get(id)
+                .filter(|(looked_up, _)| looked_up.elapsed() < RELEASES_TTL)
+                .map(|(_, releases)| releases.clone());
+            if let Some(releases) = cached {
+                return Ok(releases);
+            }
+
+            let releases: Vec<Release> = self
+                .client
+                .get(format!(
+                    "{}/buildpacks/{}/revisions",
+                    self.api_url,
+                    id.replace('/', "%2F")
+                ))
+                .header(reqwest::header::ACCEPT, REGISTRY_API_ACCEPT)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let mut cached = self.releases.lock().unwrap_or_else(|err| err.into_inner());
+            // expired releases would only be looked up again, they're dropped rather than kept
+            // around for ids that aren't asked for anymore
+            cached.retain(|cached_id, (looked_up, _)| {
+                cached_id != id && looked_up.elapsed() < RELEASES_TTL
+            });
+            if cached.len() >= MAX_CACHED_RELEASES {
+                if let Some(oldest) = cached
+                    .iter()
+                    .min_by_key(|(_, (looked_up, _))| *looked_up)
+                    .map(|(oldest, _)| oldest.clone())
+                {
+                    cached.remove(&oldest);
+                }
+            }
+            cached.insert(String::from(id), (Instant::now(), releases.clone()));
+
+            Ok(releases)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
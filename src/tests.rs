@@ -0,0 +1,2971 @@
+//! End to end tests of the shim endpoints, against a mock buildpack registry.
+
+#[cfg(feature = "grpc")]
+use super::grpc::{
+    proto::{self, shim_server::Shim},
+    ShimService,
+};
+use super::{
+    audit::AuditLog,
+    cache::{Cache, MemoryCache},
+    callback::Notifier,
+    filters,
+    hosts::AllowedHosts,
+    http_client,
+    janitor::Janitor,
+    registry::Registry,
+    scripts, shim,
+    signing::Signer,
+    stacks::Stacks,
+    storage::{LocalStorage, Storage},
+    telemetry, tls, warm,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use httpmock::{
+    Method::{GET, POST},
+    Mock, MockServer,
+};
+use opentelemetry::trace::TraceContextExt;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io::Read,
+    net::SocketAddr,
+    path::{Component, Path},
+    sync::Arc,
+    time::Duration,
+};
+use warp::{http::StatusCode, hyper::body::Bytes, Filter, Rejection, Reply};
+
+/// The bin scripts of a v2 buildpack
+const V2_BUILDPACK_BIN: &[(&str, &str)] = &[
+    ("bin/detect", "#!/usr/bin/env bash\necho Ruby\n"),
+    ("bin/compile", "#!/usr/bin/env bash\necho compiling\n"),
+    ("bin/release", "#!/usr/bin/env bash\necho '--- {}'\n"),
+];
+
+/// A v2 buildpack tarball, with everything nested under `root` when given
+fn v2_buildpack(root: Option<&str>) -> Vec<u8> {
+    tarball(root, V2_BUILDPACK_BIN)
+}
+
+/// A gzipped tarball of `files`, nested under `root` when given
+fn tarball(root: Option<&str>, files: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for &(path, contents) in files {
+        let path = match root {
+            Some(root) => format!("{}/{}", root, path),
+            None => String::from(path),
+        };
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, path, contents.as_bytes())
+            .unwrap();
+    }
+
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+/// A gzipped tarball of `files` and a symlink or hardlink at `path` to `link`
+fn tarball_with_link(
+    files: &[(&str, &str)],
+    entry_type: tar::EntryType,
+    path: &str,
+    link: &str,
+) -> Vec<u8> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for &(path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, path, contents.as_bytes())
+            .unwrap();
+    }
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_size(0);
+    header.set_mode(0o777);
+    header.set_link_name(link).unwrap();
+    header.set_cksum();
+    builder
+        .append_data(&mut header, path, std::io::empty())
+        .unwrap();
+
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+/// The files of a shimmed buildpack tarball by path, with their mode and contents
+fn unpack(tgz: &[u8]) -> HashMap<String, (u32, Vec<u8>)> {
+    unpack_tar(GzDecoder::new(tgz))
+}
+
+/// The files of an uncompressed tarball, like `unpack`
+fn unpack_tar(tar: impl Read) -> HashMap<String, (u32, Vec<u8>)> {
+    let mut archive = tar::Archive::new(tar);
+    archive
+        .entries()
+        .unwrap()
+        .map(Result::unwrap)
+        .filter(|entry| entry.header().entry_type().is_file())
+        .map(|mut entry| {
+            let path = entry
+                .path()
+                .unwrap()
+                .components()
+                .filter(|component| *component != Component::CurDir)
+                .collect::<std::path::PathBuf>();
+            let mode = entry.header().mode().unwrap();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).unwrap();
+
+            (path.to_string_lossy().into_owned(), (mode, contents))
+        })
+        .collect()
+}
+
+/// The service's routes with the registry pointed at `server`, shimming in `work_dir`
+fn routes(
+    server: &MockServer,
+    work_dir: &Path,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    routes_with_audit_log(server, work_dir, None)
+}
+
+/// The stacks as configured by default
+fn stacks() -> Stacks {
+    let strings = |stacks: &[&str]| stacks.iter().map(|stack| stack.to_string()).collect();
+
+    Stacks::new(
+        strings(&["heroku-22", "heroku-24"]),
+        strings(&["heroku-16", "heroku-18", "heroku-20"]),
+    )
+}
+
+const ADMIN_TOKEN: &str = "s3cr3t";
+/// The reverse proxy `X-Forwarded-For` is taken from
+const TRUSTED_PROXY: &str = "10.0.0.1";
+
+/// Limits no test gets close to
+const LIMITS: shim::Limits = shim::Limits {
+    download_size: 1024 * 1024,
+    extracted_size: 1024 * 1024,
+    artifact_size: 1024 * 1024,
+};
+
+fn routes_with_audit_log(
+    server: &MockServer,
+    work_dir: &Path,
+    audit_log: Option<Arc<AuditLog>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    routes_with(server, work_dir, audit_log, LIMITS)
+}
+
+fn routes_with(
+    server: &MockServer,
+    work_dir: &Path,
+    audit_log: Option<Arc<AuditLog>>,
+    limits: shim::Limits,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    routes_for(Arc::new(shimmer(server, work_dir, limits)), audit_log)
+}
+
+fn routes_for(
+    shimmer: Arc<shim::Shimmer>,
+    audit_log: Option<Arc<AuditLog>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    filters::routes(
+        shimmer,
+        Arc::new(Notifier::new(None, reqwest::Client::new())),
+        audit_log,
+        false,
+        2,
+        Some(String::from(ADMIN_TOKEN)),
+        vec![TRUSTED_PROXY.parse().unwrap()],
+    )
+}
+
+/// A shimmer with the registry pointed at `server`, shimming in `work_dir`
+fn shimmer(server: &MockServer, work_dir: &Path, limits: shim::Limits) -> shim::Shimmer {
+    let janitor = Janitor::new(work_dir, None, 0, Duration::from_secs(3600)).unwrap();
+
+    shim::Shimmer::new(
+        scripts::ShimScripts::bundled(None).unwrap(),
+        Arc::new(janitor),
+        None,
+        Some(MemoryCache::new(1024 * 1024, None)),
+        Registry::new(
+            server.base_url(),
+            server.base_url(),
+            server.base_url(),
+            reqwest::Client::new(),
+        ),
+        None,
+        stacks(),
+        limits,
+        reqwest::Client::new(),
+        allowed_hosts(),
+    )
+}
+
+/// Allows the mock server on top of github.com
+fn allowed_hosts() -> AllowedHosts {
+    AllowedHosts::new(vec![String::from("127.0.0.1")])
+}
+
+fn buildpack_toml(files: &HashMap<String, (u32, Vec<u8>)>) -> toml::Value {
+    toml::from_slice(&files["buildpack.toml"].1).unwrap()
+}
+
+/// Mocks the registry listing `releases` of `id`, all with the tarball at `/<id>.tgz`
+async fn mock_releases<'a>(server: &'a MockServer, id: &str, releases: &[u64]) -> Mock<'a> {
+    let releases = releases
+        .iter()
+        .map(
+            |release| json!({ "release": release, "tar_link": server.url(format!("/{}.tgz", id)) }),
+        )
+        .collect::<Vec<_>>();
+
+    server
+        .mock_async(|when, then| {
+            when.method(GET)
+                .path(format!("/buildpacks/{}/revisions", id.replace('/', "%2F")));
+            then.status(200).json_body(json!(releases));
+        })
+        .await
+}
+
+#[tokio::test]
+async fn shims_the_latest_registry_release() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    let revisions = server
+        .mock_async(|when, then| {
+            when.method(GET).path("/buildpacks/heroku%2Fruby/revisions");
+            then.status(200).json_body(json!([
+                { "release": 41, "tar_link": server.url("/releases/v41.tgz") },
+                { "release": 42, "tar_link": server.url("/releases/v42.tgz") },
+            ]));
+        })
+        .await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/buildpacks/heroku%2Fruby");
+            then.status(200).json_body(json!({
+                "description": "Ruby on Heroku",
+                "license": "MIT",
+                "category": { "name": "languages" },
+                "support": { "method": "website", "url": "https://example.com/ruby" },
+            }));
+        })
+        .await;
+    let tarball = server
+        .mock_async(|when, then| {
+            when.method(GET).path("/releases/v42.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+
+    let routes = routes(&server, work_dir.path());
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby")
+        .reply(&routes)
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    revisions.assert_async().await;
+    tarball.assert_async().await;
+
+    let files = unpack(res.body());
+    let buildpack_toml = buildpack_toml(&files);
+    assert_eq!(buildpack_toml["api"].as_str(), Some("0.4"));
+    assert_eq!(
+        buildpack_toml["buildpack"]["id"].as_str(),
+        Some("heroku/ruby")
+    );
+    assert_eq!(
+        buildpack_toml["buildpack"]["version"].as_str(),
+        Some("42.0.0")
+    );
+    assert_eq!(
+        buildpack_toml["buildpack"]["description"].as_str(),
+        Some("Ruby on Heroku")
+    );
+    assert_eq!(
+        buildpack_toml["buildpack"]["homepage"].as_str(),
+        Some("https://example.com/ruby")
+    );
+    assert_eq!(
+        buildpack_toml["buildpack"]["keywords"],
+        toml::Value::from(vec!["languages"])
+    );
+    let licenses = buildpack_toml["buildpack"]["licenses"].as_array().unwrap();
+    assert_eq!(licenses.len(), 1);
+    assert_eq!(licenses[0]["type"].as_str(), Some("MIT"));
+    assert!(licenses[0].get("uri").is_none());
+    assert_eq!(
+        buildpack_toml["stacks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|stack| stack["id"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        vec!["heroku-22", "heroku-24"]
+    );
+
+    for (name, contents) in [
+        ("detect", &include_bytes!("../bin/detect")[..]),
+        ("build", &include_bytes!("../bin/build")[..]),
+        ("exports", &include_bytes!("../bin/exports")[..]),
+    ] {
+        let (mode, data) = &files[&format!("bin/{}", name)];
+        assert_eq!(data.as_slice(), contents, "bin/{}", name);
+        assert_eq!(mode & 0o755, 0o755, "bin/{} is executable", name);
+    }
+    assert!(files.contains_key("bin/release"));
+    assert_eq!(
+        files["target/bin/compile"].1,
+        b"#!/usr/bin/env bash\necho compiling\n"
+    );
+    assert!(files.contains_key("target/bin/detect"));
+    assert!(files.contains_key("target/bin/release"));
+
+    // the release just resolved is reused rather than looked up again
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    revisions.assert_hits_async(1).await;
+}
+
+#[tokio::test]
+async fn shims_without_registry_metadata_when_there_is_none() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+
+    for status in [404, 500] {
+        // a fresh shimmer each time, nothing comes from its memory cache
+        let routes = routes(&server, work_dir.path());
+        let mut metadata = server
+            .mock_async(|when, then| {
+                when.method(GET).path("/buildpacks/heroku%2Fruby");
+                then.status(status);
+            })
+            .await;
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/v1/heroku/ruby?version=1.0.0")
+            .reply(&routes)
+            .await;
+
+        assert_eq!(res.status(), StatusCode::OK, "{}", status);
+        metadata.assert_async().await;
+        let buildpack = buildpack_toml(&unpack(res.body()))["buildpack"].clone();
+        assert_eq!(buildpack["id"].as_str(), Some("heroku/ruby"));
+        for key in ["description", "homepage", "keywords", "licenses"] {
+            assert!(buildpack.get(key).is_none(), "{} with {}", key, status);
+        }
+        metadata.delete_async().await;
+    }
+}
+
+#[tokio::test]
+async fn shims_the_registry_tarball_with_an_explicit_version() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/buildpacks/heroku%2Fruby/revisions");
+            then.status(200).json_body(json!([
+                { "release": 41, "tar_link": server.url("/releases/v41.tgz") },
+                { "release": 42, "tar_link": server.url("/releases/v42.tgz") },
+            ]));
+        })
+        .await;
+    let tarball = server
+        .mock_async(|when, then| {
+            when.method(GET).path("/releases/v41.tgz");
+            then.status(200)
+                .body(v2_buildpack(Some("heroku-buildpack-ruby-main")));
+        })
+        .await;
+    let routes = routes(&server, work_dir.path());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/heroku/ruby")
+        .json(&json!({
+            "version": "41",
+            "name": "Ruby",
+            "stacks": ["heroku-20"],
+            "api": "0.10",
+            "targets": "linux/amd64",
+        }))
+        .reply(&routes)
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    tarball.assert_async().await;
+
+    let files = unpack(res.body());
+    let buildpack_toml = buildpack_toml(&files);
+    assert_eq!(
+        buildpack_toml["buildpack"]["version"].as_str(),
+        Some("41.0.0")
+    );
+    assert_eq!(buildpack_toml["buildpack"]["name"].as_str(), Some("Ruby"));
+    assert_eq!(buildpack_toml["targets"][0]["os"].as_str(), Some("linux"));
+    assert_eq!(buildpack_toml["targets"][0]["arch"].as_str(), Some("amd64"));
+    // the single root directory of the tarball is hoisted into target/
+    assert!(files.contains_key("target/bin/compile"));
+    assert!(!files
+        .keys()
+        .any(|path| path.contains("heroku-buildpack-ruby-main")));
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=7.0.0")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(res.body()).contains("heroku/ruby has no release 7.0.0"));
+    tarball.assert_hits_async(1).await;
+
+    // versions that aren't release numbers are stamped on the registry's tarball
+    let latest = server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    for version in ["0.1.0", "1.2.3"] {
+        let res = warp::test::request()
+            .method("GET")
+            .path(&format!("/v1/heroku/ruby?version={}", version))
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK, "{}", version);
+        let files = unpack(res.body());
+        let shimmed_toml: toml::Value = toml::from_slice(&files["buildpack.toml"].1).unwrap();
+        assert_eq!(shimmed_toml["buildpack"]["version"].as_str(), Some(version));
+    }
+    latest.assert_hits_async(2).await;
+}
+
+#[tokio::test]
+async fn falls_back_to_the_default_version_when_resolution_fails() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/buildpacks/heroku%2Fruby/revisions");
+            then.status(500);
+        })
+        .await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby")
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        buildpack_toml(&unpack(res.body()))["buildpack"]["version"].as_str(),
+        Some(shim::DEFAULT_VERSION)
+    );
+}
+
+#[tokio::test]
+async fn fails_when_latest_cannot_be_resolved() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/buildpacks/heroku%2Fruby/revisions");
+            then.status(500);
+        })
+        .await;
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=latest")
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn rejects_missing_upstream_tarballs() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(404);
+        })
+        .await;
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=1.0.0")
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(String::from_utf8_lossy(res.body()).contains("download failed with 404 Not Found"));
+}
+
+#[tokio::test]
+async fn rejects_invalid_options() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=1.0.0&api=not-an-api")
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn signs_shimmed_buildpacks_and_their_provenance() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    let key_pair =
+        minisign::KeyPair::generate_encrypted_keypair(Some(String::from("password"))).unwrap();
+    let key_path = work_dir.path().join("minisign.key");
+    std::fs::write(&key_path, key_pair.sk.to_box(None).unwrap().to_string()).unwrap();
+    let janitor = Janitor::new(work_dir.path(), None, 0, Duration::from_secs(3600)).unwrap();
+    let shimmer = Arc::new(shim::Shimmer::new(
+        scripts::ShimScripts::bundled(None).unwrap(),
+        Arc::new(janitor),
+        None,
+        Some(MemoryCache::new(1024 * 1024, None)),
+        Registry::new(
+            server.base_url(),
+            server.base_url(),
+            server.base_url(),
+            reqwest::Client::new(),
+        ),
+        Some(Signer::from_file(&key_path, Some(String::from("password"))).unwrap()),
+        stacks(),
+        LIMITS,
+        reqwest::Client::new(),
+        allowed_hosts(),
+    ));
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=1.0.0")
+        .reply(&routes_for(shimmer, None))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let digest = hex::encode(Sha256::digest(res.body()));
+    let header = |name| base64::decode(res.headers()[name].as_bytes()).unwrap();
+
+    let minisig = minisign::SignatureBox::from_string(
+        &String::from_utf8(header("x-cnb-shim-minisig")).unwrap(),
+    )
+    .unwrap();
+    minisign::verify(
+        &key_pair.pk,
+        &minisig,
+        std::io::Cursor::new(res.body().to_vec()),
+        true,
+        false,
+        false,
+    )
+    .unwrap();
+    assert_eq!(
+        minisig.trusted_comment().unwrap(),
+        format!("file:heroku/ruby.tgz\tsha256:{}", digest)
+    );
+
+    let envelope: serde_json::Value =
+        serde_json::from_slice(&header("x-cnb-shim-provenance")).unwrap();
+    assert_eq!(
+        envelope["payloadType"].as_str(),
+        Some("application/vnd.in-toto+json")
+    );
+    let statement = base64::decode(envelope["payload"].as_str().unwrap()).unwrap();
+    let mut pae = format!(
+        "DSSEv1 28 application/vnd.in-toto+json {} ",
+        statement.len()
+    )
+    .into_bytes();
+    pae.extend_from_slice(&statement);
+    let statement_sig = minisign::SignatureBox::from_string(
+        &String::from_utf8(
+            base64::decode(envelope["signatures"][0]["sig"].as_str().unwrap()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    minisign::verify(
+        &key_pair.pk,
+        &statement_sig,
+        std::io::Cursor::new(pae),
+        true,
+        false,
+        false,
+    )
+    .unwrap();
+
+    let statement: serde_json::Value = serde_json::from_slice(&statement).unwrap();
+    assert_eq!(
+        statement["subject"][0]["name"].as_str(),
+        Some("heroku/ruby.tgz")
+    );
+    assert_eq!(
+        statement["subject"][0]["digest"]["sha256"].as_str(),
+        Some(digest.as_str())
+    );
+    assert_eq!(
+        statement["predicate"]["source_url"].as_str(),
+        Some(server.url("/heroku/ruby.tgz").as_str())
+    );
+}
+
+#[tokio::test]
+async fn serves_the_format_asked_for() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    let routes = routes(&server, work_dir.path());
+
+    for (query, accept_encoding, content_type) in [
+        ("", None, "application/x-gzip"),
+        ("&format=tgz", Some("zstd"), "application/x-gzip"),
+        ("&format=tar.zst", None, "application/zstd"),
+        ("&format=tar", Some("gzip"), "application/x-tar"),
+        ("&format=tar.zst", Some("*;q=0"), "application/zstd"),
+        ("", Some("gzip, zstd"), "application/x-gzip"),
+        ("", Some("zstd, gzip;q=0.5"), "application/zstd"),
+        ("", Some("identity"), "application/x-tar"),
+        ("", Some("br, deflate"), "application/x-gzip"),
+        ("", Some("gzip;q=0"), "application/x-tar"),
+    ] {
+        let mut request = warp::test::request()
+            .method("GET")
+            .path(&format!("/v1/heroku/ruby?version=1.0.0{}", query));
+        if let Some(accept_encoding) = accept_encoding {
+            request = request.header("accept-encoding", accept_encoding);
+        }
+        let res = request.reply(&routes).await;
+
+        let case = format!("{} {:?}", query, accept_encoding);
+        assert_eq!(res.status(), StatusCode::OK, "{}", case);
+        assert_eq!(res.headers()["content-type"], content_type, "{}", case);
+        assert_eq!(res.headers()["vary"], "Accept-Encoding", "{}", case);
+        let files = match content_type {
+            "application/x-gzip" => unpack(res.body()),
+            "application/zstd" => unpack_tar(zstd::Decoder::new(&res.body()[..]).unwrap()),
+            _ => unpack_tar(&res.body()[..]),
+        };
+        assert_eq!(
+            buildpack_toml(&files)["buildpack"]["id"].as_str(),
+            Some("heroku/ruby"),
+            "{}",
+            case
+        );
+        assert!(files.contains_key("target/bin/compile"), "{}", case);
+    }
+
+    for accept_encoding in ["gzip;q=0, identity;q=0", "*;q=0", "br, *;q=0"] {
+        let res = warp::test::request()
+            .method("GET")
+            .path("/v1/heroku/ruby?version=1.0.0")
+            .header("accept-encoding", accept_encoding)
+            .reply(&routes)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            StatusCode::NOT_ACCEPTABLE,
+            "{}",
+            accept_encoding
+        );
+        assert!(String::from_utf8_lossy(res.body()).contains("refuses every format"));
+    }
+}
+
+#[tokio::test]
+async fn shims_for_any_stack_and_warns_about_deprecated_ones() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=1.0.0&stacks=*")
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().get("warning").is_none());
+    let buildpack_toml = buildpack_toml(&unpack(res.body()));
+    assert_eq!(buildpack_toml["stacks"].as_array().unwrap().len(), 1);
+    assert_eq!(buildpack_toml["stacks"][0]["id"].as_str(), Some("*"));
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=1.0.0&stacks=heroku-18,heroku-22")
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()
+            .get_all("warning")
+            .iter()
+            .map(|warning| warning.to_str().unwrap())
+            .collect::<Vec<_>>(),
+        vec!["299 cnb-shim \"stack heroku-18 is deprecated\""]
+    );
+}
+
+#[tokio::test]
+async fn enforces_size_limits() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    // hex digits of a pseudo random sequence, so it doesn't compress away
+    let mut state = 1u32;
+    let padding = (0..64 * 1024)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            std::char::from_digit((state >> 16) % 16, 16).unwrap()
+        })
+        .collect::<String>();
+    let v2_buildpack = tarball(
+        None,
+        &[
+            ("bin/detect", V2_BUILDPACK_BIN[0].1),
+            ("bin/compile", padding.as_str()),
+        ],
+    );
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(&v2_buildpack);
+        })
+        .await;
+
+    for (limits, status, message) in [
+        (
+            shim::Limits {
+                download_size: 512,
+                ..LIMITS
+            },
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "download size limit",
+        ),
+        (
+            shim::Limits {
+                extracted_size: 16 * 1024,
+                ..LIMITS
+            },
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "extracted size limit",
+        ),
+        (
+            shim::Limits {
+                artifact_size: 512,
+                ..LIMITS
+            },
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "artifact size limit",
+        ),
+    ] {
+        let res = warp::test::request()
+            .method("GET")
+            .path("/v1/heroku/ruby?version=1.0.0")
+            .reply(&routes_with(&server, work_dir.path(), None, limits))
+            .await;
+
+        assert_eq!(res.status(), status, "{}", message);
+        assert!(String::from_utf8_lossy(res.body()).contains(message));
+    }
+}
+
+#[tokio::test]
+async fn rejects_what_isnt_a_v2_buildpack() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    let mut gzipped_text = GzEncoder::new(Vec::new(), Compression::default());
+    std::io::Write::write_all(&mut gzipped_text, &[b'x'; 1024]).unwrap();
+
+    for (body, message) in [
+        (
+            b"<?xml version=\"1.0\"?><Error><Code>NoSuchKey</Code></Error>".to_vec(),
+            "not a gzipped tarball",
+        ),
+        (gzipped_text.finish().unwrap(), "not a gzipped tarball"),
+        (
+            tarball(Some("ruby"), &[("bin/detect", "#!/usr/bin/env bash\n")]),
+            "missing bin/compile",
+        ),
+        (
+            tarball(None, &[("README.md", "# Ruby\n")]),
+            "missing bin/detect, bin/compile",
+        ),
+    ] {
+        let mut mock = server
+            .mock_async(|when, then| {
+                when.method(GET).path("/heroku/ruby.tgz");
+                then.status(200).body(&body);
+            })
+            .await;
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/v1/heroku/ruby?version=1.0.0")
+            .reply(&routes(&server, work_dir.path()))
+            .await;
+
+        assert_eq!(
+            res.status(),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "{}",
+            message
+        );
+        assert!(String::from_utf8_lossy(res.body()).contains(message));
+        mock.delete_async().await;
+    }
+}
+
+#[tokio::test]
+async fn lists_only_objects_written_in_full_to_local_storage() {
+    let storage_dir = tempfile::tempdir().unwrap();
+    let storage = LocalStorage::new(storage_dir.path()).unwrap();
+
+    storage
+        .put("artifacts/ruby.tgz", b"ruby".to_vec())
+        .await
+        .unwrap();
+    // as left behind by a write that's still going, or one cut short
+    std::fs::write(
+        storage_dir.path().join("artifacts").join(".tmp-a1b2c3"),
+        b"partial",
+    )
+    .unwrap();
+
+    let entries = storage.list("artifacts/").await.unwrap();
+    assert_eq!(
+        entries
+            .iter()
+            .map(|entry| entry.key.as_str())
+            .collect::<Vec<_>>(),
+        ["artifacts/ruby.tgz"]
+    );
+    assert_eq!(entries[0].size, 4);
+    let object = storage.get("artifacts/ruby.tgz").await.unwrap().unwrap();
+    assert_eq!(object.data, b"ruby");
+    storage.delete("artifacts/ruby.tgz").await.unwrap();
+    assert!(storage.get("artifacts/ruby.tgz").await.unwrap().is_none());
+    assert!(storage.list("").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn doesnt_cache_downloads_that_arent_v2_buildpacks() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    let storage_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    let janitor = Janitor::new(work_dir.path(), None, 0, Duration::from_secs(3600)).unwrap();
+    let storage = LocalStorage::new(storage_dir.path()).unwrap();
+    let shimmer = Arc::new(shim::Shimmer::new(
+        scripts::ShimScripts::bundled(None).unwrap(),
+        Arc::new(janitor),
+        Some(Arc::new(Cache::new(Arc::new(storage), None))),
+        None,
+        Registry::new(
+            server.base_url(),
+            server.base_url(),
+            server.base_url(),
+            reqwest::Client::new(),
+        ),
+        None,
+        stacks(),
+        LIMITS,
+        reqwest::Client::new(),
+        allowed_hosts(),
+    ));
+    let routes = routes_for(shimmer, None);
+
+    // the second request downloads again, nothing of the first was cached
+    for (body, status) in [
+        (
+            tarball(None, &[("README.md", "# Ruby\n")]),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        (v2_buildpack(None), StatusCode::OK),
+    ] {
+        let mut mock = server
+            .mock_async(|when, then| {
+                when.method(GET).path("/heroku/ruby.tgz");
+                then.status(200).body(&body);
+            })
+            .await;
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/v1/heroku/ruby?version=1.0.0")
+            .reply(&routes)
+            .await;
+
+        assert_eq!(res.status(), status);
+        mock.assert_async().await;
+        mock.delete_async().await;
+    }
+}
+
+#[tokio::test]
+async fn serves_cached_shims_when_over_quota() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    let storage_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    mock_releases(&server, "heroku/go", &[1]).await;
+    for path in ["/heroku/ruby.tgz", "/heroku/go.tgz"] {
+        server
+            .mock_async(|when, then| {
+                when.method(GET).path(path);
+                then.status(200).body(v2_buildpack(None));
+            })
+            .await;
+    }
+    let routes = |quota| {
+        let janitor = Janitor::new(work_dir.path(), quota, 0, Duration::from_secs(3600)).unwrap();
+        let storage = LocalStorage::new(storage_dir.path()).unwrap();
+        routes_for(
+            Arc::new(shim::Shimmer::new(
+                scripts::ShimScripts::bundled(None).unwrap(),
+                Arc::new(janitor),
+                Some(Arc::new(Cache::new(Arc::new(storage), None))),
+                None,
+                Registry::new(
+                    server.base_url(),
+                    server.base_url(),
+                    server.base_url(),
+                    reqwest::Client::new(),
+                ),
+                None,
+                stacks(),
+                LIMITS,
+                reqwest::Client::new(),
+                allowed_hosts(),
+            )),
+            None,
+        )
+    };
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby")
+        .reply(&routes(None))
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // with no room left, only cache misses are refused
+    let full = routes(Some(0));
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby")
+        .reply(&full)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/go")
+        .reply(&full)
+        .await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn serves_repeated_shims_from_memory() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    let tarball = server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    let routes = routes(&server, work_dir.path());
+
+    let mut bodies = Vec::new();
+    for _ in 0..2 {
+        let res = warp::test::request()
+            .method("GET")
+            .path("/v1/heroku/ruby?version=1.0.0")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        bodies.push(res.into_body());
+    }
+
+    tarball.assert_hits_async(1).await;
+    assert_eq!(bodies[0], bodies[1]);
+}
+
+#[tokio::test]
+async fn merges_metadata_into_buildpack_toml() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=1.0.0&metadata.team=languages&metadata.owner.slack=%23ruby")
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let metadata = &buildpack_toml(&unpack(res.body()))["metadata"];
+    assert_eq!(metadata["team"].as_str(), Some("languages"));
+    assert_eq!(metadata["owner"]["slack"].as_str(), Some("#ruby"));
+    assert_eq!(
+        metadata["cnb-shim"]["source-url"].as_str(),
+        Some(server.url("/heroku/ruby.tgz").as_str())
+    );
+    assert_eq!(
+        metadata["cnb-shim"]["shim-version"].as_str(),
+        Some(env!("CARGO_PKG_VERSION"))
+    );
+    assert!(metadata["cnb-shim"]["shimmed-at"].is_str());
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/heroku/ruby")
+        .json(&json!({
+            "version": "1.0.0",
+            "metadata": { "tier": 2, "cnb-shim": "overridden" },
+        }))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let metadata = &buildpack_toml(&unpack(res.body()))["metadata"];
+    assert_eq!(metadata["tier"].as_integer(), Some(2));
+    assert!(metadata["cnb-shim"].is_table());
+}
+
+#[tokio::test]
+async fn shims_batches() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    mock_releases(&server, "heroku/php", &[1]).await;
+    for path in ["/heroku/ruby.tgz", "/releases/python.tgz"] {
+        server
+            .mock_async(|when, then| {
+                when.method(GET).path(path);
+                then.status(200).body(v2_buildpack(None));
+            })
+            .await;
+    }
+    let buildpacks = json!([
+        { "id": "heroku/ruby", "version": "1.0.0" },
+        { "id": "heroku/python", "url": server.url("/releases/python.tgz"), "version": "2.0.0" },
+    ]);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/batch")
+        .json(&json!({ "buildpacks": buildpacks }))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(res.body().to_vec())).unwrap();
+    assert_eq!(
+        zip.file_names().collect::<std::collections::HashSet<_>>(),
+        ["heroku_ruby-1.0.0.tgz", "heroku_python-2.0.0.tgz"]
+            .iter()
+            .copied()
+            .collect()
+    );
+    let mut tgz = Vec::new();
+    zip.by_name("heroku_python-2.0.0.tgz")
+        .unwrap()
+        .read_to_end(&mut tgz)
+        .unwrap();
+    assert_eq!(
+        buildpack_toml(&unpack(&tgz))["buildpack"]["id"].as_str(),
+        Some("heroku/python")
+    );
+
+    // each fits, both together don't
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/batch")
+        .json(&json!({ "buildpacks": buildpacks }))
+        .reply(&routes_with(
+            &server,
+            work_dir.path(),
+            None,
+            shim::Limits {
+                artifact_size: tgz.len() as u64 * 3 / 2,
+                ..LIMITS
+            },
+        ))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(String::from_utf8_lossy(res.body()).contains("output=multipart"));
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/batch")
+        .json(&json!({
+            "buildpacks": [
+                buildpacks[0],
+                { "id": "heroku/php", "version": "7.0.0" },
+            ],
+        }))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(res.body().to_vec())).unwrap();
+    assert_eq!(
+        zip.file_names().collect::<std::collections::HashSet<_>>(),
+        ["heroku_ruby-1.0.0.tgz", "errors.json"]
+            .iter()
+            .copied()
+            .collect()
+    );
+    let mut errors = Vec::new();
+    zip.by_name("errors.json")
+        .unwrap()
+        .read_to_end(&mut errors)
+        .unwrap();
+    let errors: serde_json::Value = serde_json::from_slice(&errors).unwrap();
+    assert_eq!(errors.as_array().map(Vec::len), Some(1));
+    assert_eq!(errors[0]["id"], "heroku/php");
+    assert_eq!(errors[0]["status"], 400);
+    assert!(errors[0]["error"].as_str().unwrap().contains("7.0.0"));
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/batch")
+        .json(&json!({
+            "buildpacks": [
+                buildpacks[0],
+                { "id": "heroku/php", "version": "7.0.0" },
+            ],
+            "output": "multipart",
+        }))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let content_type = res.headers()["content-type"].to_str().unwrap();
+    let boundary = content_type
+        .strip_prefix("multipart/mixed; boundary=")
+        .unwrap();
+    let body = String::from_utf8_lossy(res.body());
+    assert_eq!(body.matches(&format!("--{}\r\n", boundary)).count(), 2);
+    assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+    assert!(body.contains("X-Cnb-Shim-Buildpack-Id: heroku/ruby\r\nX-Cnb-Shim-Status: 200"));
+    assert!(body.contains("X-Cnb-Shim-Buildpack-Id: heroku/php\r\nX-Cnb-Shim-Status: 400"));
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/batch")
+        .json(&json!({ "buildpacks": [] }))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/batch")
+        .json(&json!({
+            "buildpacks": [
+                buildpacks[0],
+                { "id": "acme/internal", "url": "http://10.0.0.1/internal.tgz" },
+            ],
+            "output": "multipart",
+        }))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(res.body()).contains("10.0.0.1 is not an allowed host"));
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/batch")
+        .json(&json!({
+            "buildpacks": [{ "id": "heroku/ruby\r\nX-Cnb-Shim-Status: 200", "version": "1.0.0" }],
+            "output": "multipart",
+        }))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(res.body()).contains("invalid buildpack id"));
+}
+
+#[tokio::test]
+async fn warms_the_buildpacks_of_a_manifest() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    let tarball = server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/missing.tgz");
+            then.status(404);
+        })
+        .await;
+    let manifest_path = work_dir.path().join("buildpacks.toml");
+    std::fs::write(
+        &manifest_path,
+        r#"
+[[buildpacks]]
+id = "heroku/ruby"
+version = "1.0.0"
+
+[[buildpacks]]
+id = "heroku/missing"
+version = "1.0.0"
+"#,
+    )
+    .unwrap();
+    let shimmer = Arc::new(shimmer(&server, work_dir.path(), LIMITS));
+
+    let manifest = warm::Manifest::load(&manifest_path).unwrap();
+    assert_eq!(warm::warm(&shimmer, &manifest, 2).await, 1);
+    tarball.assert_hits_async(1).await;
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=1.0.0")
+        .reply(&routes_for(shimmer, None))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    tarball.assert_hits_async(1).await;
+}
+
+#[tokio::test]
+async fn shims_uploaded_tarballs() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/upload?id=acme/ruby&version=2.0.0")
+        .body(v2_buildpack(None))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let files = unpack(res.body());
+    let buildpack_toml = buildpack_toml(&files);
+    assert_eq!(
+        buildpack_toml["buildpack"]["id"].as_str(),
+        Some("acme/ruby")
+    );
+    assert_eq!(
+        buildpack_toml["buildpack"]["version"].as_str(),
+        Some("2.0.0")
+    );
+    assert!(files.contains_key("target/bin/compile"));
+}
+
+#[tokio::test]
+async fn refuses_uploads_linking_outside_of_themselves() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    let routes = routes(&server, work_dir.path());
+
+    for (entry_type, path, link) in [
+        (tar::EntryType::Symlink, "lib/passwd", "/etc/passwd"),
+        (tar::EntryType::Symlink, "lib/etc", "../../etc"),
+        (tar::EntryType::Symlink, "etc", ".."),
+        (tar::EntryType::Link, "lib/passwd", "/etc/passwd"),
+        (tar::EntryType::Link, "lib/compile", "../bin/compile"),
+    ] {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/v1/upload?id=acme/ruby&version=2.0.0")
+            .body(tarball_with_link(V2_BUILDPACK_BIN, entry_type, path, link))
+            .reply(&routes)
+            .await;
+
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", link);
+        assert!(String::from_utf8_lossy(res.body()).contains("outside of the buildpack"));
+    }
+
+    // links within the buildpack are kept as links
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/upload?id=acme/ruby&version=2.0.0")
+        .body(tarball_with_link(
+            V2_BUILDPACK_BIN,
+            tar::EntryType::Symlink,
+            "lib/bin",
+            "../bin",
+        ))
+        .reply(&routes)
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = res.body().to_vec();
+    let mut archive = tar::Archive::new(GzDecoder::new(&body[..]));
+    let link = archive
+        .entries()
+        .unwrap()
+        .map(Result::unwrap)
+        .find(|entry| entry.header().entry_type() == tar::EntryType::Symlink)
+        .unwrap();
+    assert!(link.path().unwrap().ends_with("target/lib/bin"));
+    assert_eq!(
+        link.link_name().unwrap().as_deref(),
+        Some(Path::new("../bin"))
+    );
+}
+
+#[tokio::test]
+async fn refuses_uploads_over_the_download_size_limit_before_reading_them() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    let routes = routes_with(
+        &server,
+        work_dir.path(),
+        None,
+        shim::Limits {
+            download_size: 1024,
+            ..LIMITS
+        },
+    );
+    let tarball = vec![0; 128 * 1024];
+    let mut form = b"--boundary\r\n\
+        Content-Disposition: form-data; name=\"buildpack\"; filename=\"ruby.tgz\"\r\n\r\n"
+        .to_vec();
+    form.extend_from_slice(&tarball);
+    form.extend_from_slice(b"\r\n--boundary--\r\n");
+
+    for (content_type, body) in [
+        ("application/gzip", tarball.clone()),
+        ("multipart/form-data; boundary=boundary", form),
+    ] {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/v1/upload?id=acme/ruby&version=2.0.0")
+            .header("content-type", content_type)
+            .body(body)
+            .reply(&routes)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "{}",
+            content_type
+        );
+        // the shim's own check of what was read didn't get to run
+        assert!(!String::from_utf8_lossy(res.body()).contains("upload size limit"));
+    }
+}
+
+#[tokio::test]
+async fn streams_chunked_uploads_up_to_the_download_size_limit() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    let routes = routes_with(
+        &server,
+        work_dir.path(),
+        None,
+        shim::Limits {
+            download_size: 4096,
+            ..LIMITS
+        },
+    );
+    let (addr, serving) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(serving);
+    // chunked, without a Content-Length
+    let upload = |chunks: Vec<Vec<u8>>| {
+        reqwest::Client::new()
+            .post(format!(
+                "http://{}/v1/upload?id=acme/ruby&version=2.0.0",
+                addr
+            ))
+            .header("content-type", "application/gzip")
+            .body(reqwest::Body::wrap_stream(tokio_stream::iter(
+                chunks.into_iter().map(Ok::<_, std::io::Error>),
+            )))
+            .send()
+    };
+
+    let tarball = v2_buildpack(None);
+    let (head, tail) = tarball.split_at(tarball.len() / 2);
+    let res = upload(vec![head.to_vec(), tail.to_vec()]).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let files = unpack(&res.bytes().await.unwrap());
+    assert!(files.contains_key("target/bin/compile"));
+
+    let res = upload(vec![vec![0; 1024]; 16]).await.unwrap();
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(res
+        .text()
+        .await
+        .unwrap()
+        .contains("larger than the download size limit of 4096 bytes"));
+    // what was received is gone with the request
+    assert_eq!(std::fs::read_dir(work_dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn excludes_and_rewrites_v2_buildpack_contents() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    let files = [
+        V2_BUILDPACK_BIN,
+        &[
+            ("lib/ruby.rb", "puts 'ruby'\n"),
+            (
+                "test/fixtures/app/Gemfile",
+                "source 'https://rubygems.org'\n",
+            ),
+            ("docs/README.md", "# Ruby\n"),
+            (".git/HEAD", "ref: refs/heads/main\n"),
+        ],
+    ]
+    .concat();
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200)
+                .body(tarball(Some("heroku-buildpack-ruby-main"), &files));
+        })
+        .await;
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/heroku/ruby")
+        .json(&json!({
+            "version": "1.0.0",
+            "exclude": "test/**,docs",
+            "strip_vcs": true,
+            "shebang": "/bin/bash",
+        }))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let files = unpack(res.body());
+    assert!(files.contains_key("target/lib/ruby.rb"));
+    assert!(!files.keys().any(|path| path.starts_with("target/test")));
+    assert!(!files.keys().any(|path| path.starts_with("target/docs")));
+    assert!(!files.keys().any(|path| path.starts_with("target/.git")));
+    assert_eq!(
+        files["target/bin/compile"].1,
+        b"#!/bin/bash\necho compiling\n"
+    );
+}
+
+#[tokio::test]
+async fn rewrites_shebangs_of_scripts_but_not_of_what_they_link_to() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    let files = [
+        V2_BUILDPACK_BIN,
+        &[("lib/release.sh", "#!/bin/sh\necho releasing\n")],
+    ]
+    .concat();
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(tarball_with_link(
+                &files,
+                tar::EntryType::Symlink,
+                "bin/release",
+                "../lib/release.sh",
+            ));
+        })
+        .await;
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/heroku/ruby")
+        .json(&json!({ "version": "1.0.0", "shebang": "/bin/bash" }))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let files = unpack(res.body());
+    assert_eq!(
+        files["target/bin/compile"].1,
+        b"#!/bin/bash\necho compiling\n"
+    );
+    assert_eq!(
+        files["target/lib/release.sh"].1,
+        b"#!/bin/sh\necho releasing\n"
+    );
+    assert!(!files.contains_key("target/bin/release"));
+}
+
+#[tokio::test]
+async fn records_shims_in_the_audit_log() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/missing.tgz");
+            then.status(404);
+        })
+        .await;
+    mock_releases(&server, "heroku/ruby", &[1, 2, 3]).await;
+    mock_releases(&server, "heroku/missing", &[1]).await;
+    let audit_log = AuditLog::connect(&format!(
+        "sqlite://{}",
+        work_dir.path().join("audit.db").display()
+    ))
+    .await
+    .unwrap();
+    let routes = routes_with_audit_log(&server, work_dir.path(), Some(Arc::new(audit_log)));
+
+    for (path, remote, status) in [
+        (
+            "/v1/heroku/ruby?version=1.0.0",
+            TRUSTED_PROXY,
+            StatusCode::OK,
+        ),
+        (
+            "/v1/heroku/ruby?version=2.0.0",
+            TRUSTED_PROXY,
+            StatusCode::OK,
+        ),
+        (
+            "/v1/heroku/missing?version=1.0.0",
+            TRUSTED_PROXY,
+            StatusCode::BAD_REQUEST,
+        ),
+        // not a trusted proxy, so its X-Forwarded-For goes unheeded
+        (
+            "/v1/heroku/ruby?version=3.0.0",
+            "198.51.100.2",
+            StatusCode::OK,
+        ),
+    ] {
+        let res = warp::test::request()
+            .method("GET")
+            .path(path)
+            .remote_addr(SocketAddr::new(remote.parse().unwrap(), 443))
+            .header("x-forwarded-for", "192.0.2.1, 203.0.113.7")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), status, "{}", path);
+    }
+
+    let bearer = format!("Bearer {}", ADMIN_TOKEN);
+    for path in ["/v1/audit", "/v1/stats"] {
+        let res = warp::test::request()
+            .method("GET")
+            .path(path)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{}", path);
+    }
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/audit?buildpack_id=heroku/ruby&limit=2")
+        .header("authorization", &bearer)
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let audit: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(audit["items"].as_array().unwrap().len(), 2);
+    assert_eq!(audit["items"][0]["version"], "3.0.0");
+    assert_eq!(audit["items"][0]["client"], "198.51.100.2");
+    assert_eq!(audit["items"][1]["version"], "2.0.0");
+    assert_eq!(audit["items"][1]["source"], "registry");
+    assert_eq!(audit["items"][1]["client"], "203.0.113.7");
+    assert_eq!(audit["items"][1]["result"], "succeeded");
+    assert!(audit["items"][1]["digest"]
+        .as_str()
+        .unwrap()
+        .starts_with("sha256:"));
+    assert_eq!(audit["next_offset"], 2);
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/stats")
+        .header("authorization", &bearer)
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let stats: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(stats["items"][0]["buildpack_id"], "heroku/ruby");
+    assert_eq!(stats["items"][0]["shims"], 3);
+    assert_eq!(stats["items"][1]["buildpack_id"], "heroku/missing");
+    assert_eq!(stats["items"][1]["failures"], 1);
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/audit?since=yesterday")
+        .header("authorization", &bearer)
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn expands_stack_aliases() {
+    let strings = |stacks: &[&str]| {
+        stacks
+            .iter()
+            .map(|stack| stack.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        stacks().resolve(None),
+        (strings(&["heroku-22", "heroku-24"]), Vec::new())
+    );
+    assert_eq!(
+        stacks().resolve(Some(&strings(&["heroku-24", "heroku-all"]))),
+        (strings(&["heroku-24", "heroku-22"]), Vec::new())
+    );
+    assert_eq!(
+        stacks().resolve(Some(&strings(&["heroku-20", "*"]))),
+        (strings(&["*"]), Vec::new())
+    );
+    assert_eq!(
+        stacks().resolve(Some(&strings(&["heroku-20"]))),
+        (
+            strings(&["heroku-20"]),
+            strings(&["stack heroku-20 is deprecated"])
+        )
+    );
+}
+
+#[test]
+fn evicts_the_least_recently_used_from_memory() {
+    let memory_cache = MemoryCache::new(10, None);
+    memory_cache.put("a", Bytes::from(vec![0; 4]));
+    memory_cache.put("b", Bytes::from(vec![1; 4]));
+    assert_eq!(memory_cache.get("a"), Some(Bytes::from(vec![0; 4])));
+
+    memory_cache.put("c", Bytes::from(vec![2; 4]));
+    assert_eq!(memory_cache.get("b"), None);
+    assert_eq!(memory_cache.get("a"), Some(Bytes::from(vec![0; 4])));
+    assert_eq!(memory_cache.get("c"), Some(Bytes::from(vec![2; 4])));
+    // handed out without copying
+    assert_eq!(
+        memory_cache.get("c").unwrap().as_ptr(),
+        memory_cache.get("c").unwrap().as_ptr()
+    );
+
+    // larger than the whole cache
+    memory_cache.put("d", Bytes::from(vec![3; 11]));
+    assert_eq!(memory_cache.get("d"), None);
+    assert_eq!(memory_cache.get("a"), Some(Bytes::from(vec![0; 4])));
+
+    let memory_cache = MemoryCache::new(10, Some(Duration::from_secs(0)));
+    memory_cache.put("a", Bytes::from(vec![0; 4]));
+    std::thread::sleep(Duration::from_millis(5));
+    assert_eq!(memory_cache.get("a"), None);
+}
+
+#[tokio::test]
+async fn inspects_and_clears_the_cache() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    let storage_dir = tempfile::tempdir().unwrap();
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    let janitor = Janitor::new(work_dir.path(), None, 0, Duration::from_secs(3600)).unwrap();
+    let storage = LocalStorage::new(storage_dir.path()).unwrap();
+    let shimmer = Arc::new(shim::Shimmer::new(
+        scripts::ShimScripts::bundled(None).unwrap(),
+        Arc::new(janitor),
+        Some(Arc::new(Cache::new(Arc::new(storage), None))),
+        Some(MemoryCache::new(1024 * 1024, None)),
+        Registry::new(
+            server.base_url(),
+            server.base_url(),
+            server.base_url(),
+            reqwest::Client::new(),
+        ),
+        None,
+        stacks(),
+        LIMITS,
+        reqwest::Client::new(),
+        allowed_hosts(),
+    ));
+    let routes = routes_for(shimmer.clone(), None);
+    let bearer = format!("Bearer {}", ADMIN_TOKEN);
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=1.0.0")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    for authorization in [None, Some("Bearer wrong"), Some(ADMIN_TOKEN)] {
+        let mut request = warp::test::request().method("GET").path("/admin/cache");
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        assert_eq!(
+            request.reply(&routes).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    let entries = || {
+        let (routes, bearer) = (&routes, bearer.clone());
+        async move {
+            let res = warp::test::request()
+                .method("GET")
+                .path("/admin/cache")
+                .header("authorization", bearer)
+                .reply(routes)
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            serde_json::from_slice::<Vec<serde_json::Value>>(res.body()).unwrap()
+        }
+    };
+    let cached = entries().await;
+    let tiers = |key_prefix: &str| {
+        cached
+            .iter()
+            .filter(|entry| entry["key"].as_str().unwrap().starts_with(key_prefix))
+            .map(|entry| entry["tier"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(tiers("downloads/"), ["storage"]);
+    assert_eq!(tiers("artifacts/"), ["memory", "storage"]);
+    // told apart by what they're of, not only by their digest keys
+    let tarball_url = server.url("/heroku/ruby.tgz");
+    for entry in &cached {
+        assert_eq!(entry["url"], tarball_url.as_str(), "{}", entry["key"]);
+        if entry["key"].as_str().unwrap().starts_with("artifacts/") {
+            assert_eq!(entry["id"], "heroku/ruby");
+            assert_eq!(entry["version"], "1.0.0");
+        }
+    }
+    let artifact_key = cached
+        .iter()
+        .find(|entry| entry["tier"] == "memory")
+        .unwrap()["key"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/admin/cache/{}", artifact_key))
+        .header("authorization", &bearer)
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let cached = entries().await;
+    assert_eq!(cached.len(), 1);
+    assert!(cached[0]["key"].as_str().unwrap().starts_with("downloads/"));
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/admin/gc")
+        .header("authorization", &bearer)
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let report: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(report["expired"], json!({"memory": 0, "storage": 0}));
+
+    let res = warp::test::request()
+        .method("DELETE")
+        .path("/admin/cache")
+        .header("authorization", &bearer)
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let removed: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    // the download and its metadata
+    assert_eq!(removed, json!({"memory": 0, "storage": 2}));
+    assert!(entries().await.is_empty());
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/admin/cache")
+        .header("authorization", &bearer)
+        .reply(&filters::admin(shimmer, None))
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn continues_the_trace_of_a_request() {
+    telemetry::init();
+    let mut headers = http::HeaderMap::new();
+    headers.insert(
+        "traceparent",
+        http::HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+    );
+
+    let context = telemetry::extract(&headers);
+    let span = context.span();
+    assert!(span.span_context().is_valid());
+    assert!(span.span_context().is_remote());
+    assert!(!telemetry::extract(&http::HeaderMap::new())
+        .span()
+        .span_context()
+        .is_valid());
+}
+
+#[tokio::test]
+async fn routes_outgoing_requests_through_the_proxy() {
+    let server = MockServer::start_async().await;
+    let proxied = server
+        .mock_async(|when, then| {
+            when.method(GET).path("/buildpack.tgz");
+            then.status(200).body("proxied");
+        })
+        .await;
+    let settings = |no_proxy: &[&str]| http_client::Settings {
+        https_proxy: None,
+        http_proxy: Some(server.address().to_string()),
+        no_proxy: no_proxy.iter().map(|host| String::from(*host)).collect(),
+        ca_bundle: None,
+        pool_idle_timeout: Duration::from_secs(90),
+        pool_max_idle_per_host: None,
+        timeout: Duration::from_secs(300),
+        connect_timeout: Duration::from_secs(10),
+        allowed_hosts: allowed_hosts(),
+    };
+
+    let client = http_client::build(&settings(&["localhost"])).unwrap();
+    let response = client
+        .get("http://buildpacks.invalid/buildpack.tgz")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "proxied");
+    proxied.assert_async().await;
+
+    // the proxy isn't one, so this only gets through by bypassing it
+    let mut unreachable = settings(&["127.0.0.1"]);
+    unreachable.http_proxy = Some(String::from("http://127.0.0.1:9"));
+    let client = http_client::build(&unreachable).unwrap();
+    assert!(client
+        .get(&server.url("/buildpack.tgz"))
+        .send()
+        .await
+        .unwrap()
+        .status()
+        .is_success());
+
+    // a server slower than the timeout gives up rather than hanging
+    let slow = server
+        .mock_async(|when, then| {
+            when.method(GET).path("/slow.tgz");
+            then.status(200).delay(Duration::from_secs(2));
+        })
+        .await;
+    let client = http_client::build(&http_client::Settings {
+        timeout: Duration::from_millis(200),
+        ..settings(&["127.0.0.1"])
+    })
+    .unwrap();
+    let err = client
+        .get(&server.url("/slow.tgz"))
+        .send()
+        .await
+        .unwrap_err();
+    assert!(err.is_timeout());
+    slow.assert_async().await;
+
+    let missing = http_client::Settings {
+        ca_bundle: Some(std::env::temp_dir().join("cnb-shim-missing-ca-bundle.pem")),
+        ..settings(&[])
+    };
+    assert!(http_client::build(&missing).is_err());
+
+    let env = |name: &str| match name {
+        "https_proxy" => Some(String::from("http://proxy.internal:3128")),
+        "http_proxy" => Some(String::from("http://proxy.internal:3128")),
+        "no_proxy" => Some(String::from("localhost,.internal")),
+        _ => None,
+    };
+    let lowercase = settings(&[]).or_lowercase_env(env);
+    assert_eq!(
+        lowercase.https_proxy.as_deref(),
+        Some("http://proxy.internal:3128")
+    );
+    assert_eq!(lowercase.no_proxy, ["localhost", ".internal"]);
+    // the flags and uppercase variables come first
+    let uppercase = settings(&["example.com"]).or_lowercase_env(env);
+    assert_eq!(
+        uppercase.http_proxy.as_deref(),
+        Some(server.address().to_string().as_str())
+    );
+    assert_eq!(uppercase.no_proxy, ["example.com"]);
+}
+
+#[test]
+fn validates_the_shim_script_set() {
+    let names = |names: &[&str]| {
+        names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert!(matches!(
+        scripts::ShimScripts::bundled(Some(&names(&["detect", "release"]))),
+        Err(scripts::ScriptsError::Required(name)) if name == "build"
+    ));
+    assert!(matches!(
+        scripts::ShimScripts::bundled(Some(&names(&["detect", "build", "compile"]))),
+        Err(scripts::ScriptsError::Unknown(name)) if name == "compile"
+    ));
+
+    let bin_dir = tempfile::tempdir().unwrap();
+    scripts::ShimScripts::bundled(Some(&names(&["detect", "build"])))
+        .unwrap()
+        .install(bin_dir.path())
+        .unwrap();
+    // release and exports are optional unless they're asked for
+    assert!(scripts::ShimScripts::from_dir(bin_dir.path(), None).is_ok());
+    assert!(matches!(
+        scripts::ShimScripts::from_dir(bin_dir.path(), Some(&names(&["detect", "build", "release"]))),
+        Err(scripts::ScriptsError::Missing(path)) if path.ends_with("release")
+    ));
+}
+
+#[tokio::test]
+async fn reports_readiness_from_the_shim_scripts_on_disk() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    let bin_dir = tempfile::tempdir().unwrap();
+    scripts::ShimScripts::bundled(None)
+        .unwrap()
+        .install(bin_dir.path())
+        .unwrap();
+    let routes = |registry_url: String| {
+        let janitor = Janitor::new(work_dir.path(), None, 0, Duration::from_secs(3600)).unwrap();
+        filters::routes(
+            Arc::new(shim::Shimmer::new(
+                scripts::ShimScripts::from_dir(bin_dir.path(), None).unwrap(),
+                Arc::new(janitor),
+                None,
+                None,
+                Registry::new(
+                    registry_url.clone(),
+                    registry_url.clone(),
+                    registry_url,
+                    reqwest::Client::new(),
+                ),
+                None,
+                stacks(),
+                LIMITS,
+                reqwest::Client::new(),
+                allowed_hosts(),
+            )),
+            Arc::new(Notifier::new(None, reqwest::Client::new())),
+            None,
+            true,
+            2,
+            None,
+            Vec::new(),
+        )
+    };
+    async fn check<F>(routes: &F) -> (StatusCode, serde_json::Value)
+    where
+        F: Filter + 'static,
+        F::Extract: Reply + Send,
+    {
+        let ready = warp::test::request().path("/readyz").reply(routes).await;
+        let live = warp::test::request().path("/healthz").reply(routes).await;
+        // liveness doesn't depend on anything a shim needs
+        assert_eq!(live.status(), StatusCode::OK);
+        let report = serde_json::from_slice(ready.body()).unwrap();
+
+        (ready.status(), report)
+    }
+    let routes_up = routes(server.base_url());
+
+    let (status, report) = check(&routes_up).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(report["status"], "ok");
+    for name in ["scripts", "work_dir", "disk_space", "registry"] {
+        assert_eq!(report["checks"][name]["status"], "ok", "{}", name);
+    }
+
+    // what's on disk is checked, the scripts loaded at startup notwithstanding
+    let detect = bin_dir.path().join("detect");
+    std::fs::set_permissions(&detect, std::fs::Permissions::from_mode(0o644)).unwrap();
+    let (status, report) = check(&routes_up).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(report["status"], "fail");
+    assert_eq!(report["checks"]["scripts"]["status"], "fail");
+    assert!(report["checks"]["scripts"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("not executable"));
+
+    std::fs::set_permissions(&detect, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::remove_file(bin_dir.path().join("build")).unwrap();
+    let (status, report) = check(&routes_up).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(report["checks"]["scripts"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("missing"));
+
+    scripts::ShimScripts::bundled(None)
+        .unwrap()
+        .install(bin_dir.path())
+        .unwrap();
+    let (status, _) = check(&routes_up).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // nothing listens on the discard port
+    let (status, report) = check(&routes(String::from("http://127.0.0.1:9"))).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(report["checks"]["scripts"]["status"], "ok");
+    assert_eq!(report["checks"]["registry"]["status"], "fail");
+}
+
+#[tokio::test]
+async fn rewrites_project_toml_to_shimmed_classic_buildpacks() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/buildpacks/heroku/ruby");
+            then.status(404);
+        })
+        .await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/buildpacks/heroku/nodejs");
+            then.status(200).json_body(json!({}));
+        })
+        .await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+
+    let project_toml = r#"
+[_]
+schema-version = "0.2"
+id = "acme/app"
+
+[[io.buildpacks.group]]
+uri = "urn:cnb:registry:heroku/ruby@1.0.0"
+
+[[io.buildpacks.group]]
+id = "heroku/nodejs"
+
+[[io.buildpacks.group]]
+uri = "docker://gcr.io/paketo-buildpacks/procfile"
+"#;
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/project?version=1.0.0")
+        .body(project_toml)
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(res.body().to_vec())).unwrap();
+    let mut rewritten = String::new();
+    zip.by_name("project.toml")
+        .unwrap()
+        .read_to_string(&mut rewritten)
+        .unwrap();
+    let rewritten: toml::Value = rewritten.parse().unwrap();
+    let group = rewritten["io"]["buildpacks"]["group"].as_array().unwrap();
+    assert_eq!(rewritten["_"]["id"].as_str(), Some("acme/app"));
+    assert_eq!(group[0]["id"].as_str(), Some("heroku/ruby"));
+    assert_eq!(group[0]["version"].as_str(), Some("1.0.0"));
+    assert_eq!(group[0]["uri"].as_str(), Some("buildpacks/heroku_ruby.tgz"));
+    assert_eq!(group[1]["id"].as_str(), Some("heroku/nodejs"));
+    assert!(group[1].get("uri").is_none());
+    assert_eq!(
+        group[2]["uri"].as_str(),
+        Some("docker://gcr.io/paketo-buildpacks/procfile")
+    );
+
+    let mut shimmed = Vec::new();
+    zip.by_name("buildpacks/heroku_ruby.tgz")
+        .unwrap()
+        .read_to_end(&mut shimmed)
+        .unwrap();
+    assert!(unpack(&shimmed).contains_key("target/bin/compile"));
+}
+
+#[tokio::test]
+async fn migrates_the_buildpacks_of_an_app() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    for path in ["/heroku/ruby.tgz", "/acme/go.tgz"] {
+        server
+            .mock_async(|when, then| {
+                when.method(GET).path(path);
+                then.status(200).body(v2_buildpack(None));
+            })
+            .await;
+    }
+    let routes = routes(&server, work_dir.path());
+    let go_url = server.url("/acme/go.tgz");
+
+    let app_json = json!({
+        "buildpacks": [{ "url": "heroku/ruby" }, { "url": go_url }],
+        "stack": "heroku-22",
+    });
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/migrate?output=meta&meta_id=acme/app&meta_version=1.2.3")
+        .body(app_json.to_string())
+        .reply(&routes)
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let files = unpack(res.body());
+    let meta_toml = buildpack_toml(&files);
+    assert_eq!(meta_toml["api"].as_str(), Some("0.4"));
+    assert_eq!(meta_toml["buildpack"]["id"].as_str(), Some("acme/app"));
+    assert_eq!(meta_toml["buildpack"]["version"].as_str(), Some("1.2.3"));
+    assert_eq!(meta_toml["buildpack"]["name"].as_str(), Some("acme/app"));
+    let group = meta_toml["order"][0]["group"].as_array().unwrap();
+    assert_eq!(
+        group
+            .iter()
+            .map(|entry| entry["id"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        vec!["heroku/ruby", "acme/go"]
+    );
+    let package_toml: toml::Value = toml::from_slice(&files["package.toml"].1).unwrap();
+    assert_eq!(package_toml["buildpack"]["uri"].as_str(), Some("."));
+    assert_eq!(
+        package_toml["dependencies"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|dependency| dependency["uri"].as_str().unwrap())
+            .collect::<Vec<_>>(),
+        vec!["buildpacks/heroku_ruby.tgz", "buildpacks/acme_go.tgz"]
+    );
+    for (entry, path) in group
+        .iter()
+        .zip(["buildpacks/heroku_ruby.tgz", "buildpacks/acme_go.tgz"])
+    {
+        let shimmed = unpack(&files[path].1);
+        let shimmed_toml = buildpack_toml(&shimmed);
+        assert_eq!(shimmed_toml["buildpack"]["id"], entry["id"]);
+        assert_eq!(shimmed_toml["buildpack"]["version"], entry["version"]);
+        // shimmed for the app's stack
+        assert_eq!(shimmed_toml["stacks"].as_array().unwrap().len(), 1);
+        assert_eq!(shimmed_toml["stacks"][0]["id"].as_str(), Some("heroku-22"));
+        assert!(shimmed.contains_key("target/bin/compile"));
+    }
+
+    let buildpacks = format!("# the app's buildpacks\n\n{}\n  heroku/ruby  \n", go_url);
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/migrate")
+        .body(buildpacks)
+        .reply(&routes)
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(res.body().to_vec())).unwrap();
+    let mut project_toml = String::new();
+    zip.by_name("project.toml")
+        .unwrap()
+        .read_to_string(&mut project_toml)
+        .unwrap();
+    let project_toml: toml::Value = project_toml.parse().unwrap();
+    let group = project_toml["io"]["buildpacks"]["group"]
+        .as_array()
+        .unwrap();
+    assert_eq!(group.len(), 2);
+    assert_eq!(group[0]["id"].as_str(), Some("acme/go"));
+    assert_eq!(group[0]["uri"].as_str(), Some("buildpacks/acme_go.tgz"));
+    assert_eq!(group[1]["id"].as_str(), Some("heroku/ruby"));
+    assert_eq!(group[1]["uri"].as_str(), Some("buildpacks/heroku_ruby.tgz"));
+    assert!(zip.by_name("buildpacks/acme_go.tgz").is_ok());
+    assert!(zip.by_name("buildpacks/heroku_ruby.tgz").is_ok());
+}
+
+#[tokio::test]
+async fn refuses_tarballs_from_hosts_that_arent_allowed() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    let tarball = server
+        .mock_async(|when, then| {
+            when.method(GET).path("/acme/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+
+    let buildpacks = format!(
+        "{}\nhttp://169.254.169.254/latest/meta-data.tgz\n",
+        server.url("/acme/ruby.tgz")
+    );
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/migrate")
+        .body(buildpacks)
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(res.body()).contains("169.254.169.254 is not an allowed host"));
+    // refused before shimming any of them
+    tarball.assert_hits_async(0).await;
+
+    let hosts = AllowedHosts::new(vec![String::from(".example.com")]);
+    assert!(hosts
+        .check("https://github.com/heroku/heroku-buildpack-ruby")
+        .is_ok());
+    assert!(hosts
+        .check("https://buildpacks.example.com/ruby.tgz")
+        .is_ok());
+    assert!(hosts
+        .check("https://example.com.evil.test/ruby.tgz")
+        .is_err());
+    assert!(hosts
+        .check("https://github.com@169.254.169.254/ruby.tgz")
+        .is_err());
+    assert!(hosts.check("file:///etc/passwd").is_err());
+}
+
+#[tokio::test]
+async fn keeps_redirects_from_allowed_hosts_on_allowed_hosts() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    mock_releases(&server, "heroku/go", &[1]).await;
+    let moved = server
+        .mock_async(|when, then| {
+            when.method(GET).path("/moved.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    // the same server, by a name that isn't allowed
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(302).header(
+                "location",
+                format!("http://localhost:{}/moved.tgz", server.port()),
+            );
+        })
+        .await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/go.tgz");
+            then.status(302)
+                .header("location", server.url("/moved.tgz"));
+        })
+        .await;
+    let client = http_client::build(&http_client::Settings {
+        https_proxy: None,
+        http_proxy: None,
+        no_proxy: Vec::new(),
+        ca_bundle: None,
+        pool_idle_timeout: Duration::from_secs(90),
+        pool_max_idle_per_host: None,
+        timeout: Duration::from_secs(300),
+        connect_timeout: Duration::from_secs(10),
+        allowed_hosts: allowed_hosts(),
+    })
+    .unwrap();
+    let janitor = Janitor::new(work_dir.path(), None, 0, Duration::from_secs(3600)).unwrap();
+    let routes = routes_for(
+        Arc::new(shim::Shimmer::new(
+            scripts::ShimScripts::bundled(None).unwrap(),
+            Arc::new(janitor),
+            None,
+            None,
+            Registry::new(
+                server.base_url(),
+                server.base_url(),
+                server.base_url(),
+                client.clone(),
+            ),
+            None,
+            stacks(),
+            LIMITS,
+            client.clone(),
+            allowed_hosts(),
+        )),
+        None,
+    );
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=1.0.0")
+        .reply(&routes)
+        .await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    moved.assert_hits_async(0).await;
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/go?version=1.0.0")
+        .reply(&routes)
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    moved.assert_hits_async(1).await;
+
+    // requests to hosts the service was pointed at itself, like its registries, aren't held
+    // to the allowed hosts
+    let res = client
+        .get(&format!("http://localhost:{}/heroku/go.tgz", server.port()))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    moved.assert_hits_async(2).await;
+}
+
+#[tokio::test]
+async fn refuses_callbacks_to_hosts_that_arent_allowed() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    let tarball = server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/v1/heroku/ruby?version=1.0.0&callback_url=http://10.0.0.1/hook")
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(res.body()).contains("10.0.0.1 is not an allowed host"));
+    tarball.assert_hits_async(0).await;
+}
+
+/// Waits for a notification delivered in the background to reach `callback`
+async fn wait_for_callback(callback: &Mock<'_>) {
+    for _ in 0..100 {
+        if callback.hits_async().await > 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the callback was never notified");
+}
+
+#[tokio::test]
+async fn takes_the_callback_secret_from_its_header_only() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    let callback = server
+        .mock_async(|when, then| {
+            when.method(POST)
+                .path("/hook")
+                .header_exists("x-cnb-shim-signature")
+                .json_body_partial(r#"{ "id": "heroku/ruby", "status": "succeeded" }"#);
+            then.status(204);
+        })
+        .await;
+    let hook = server.url("/hook");
+
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!(
+            "/v1/heroku/ruby?version=1.0.0&callback_url={}&callback_secret=s3cr3t",
+            hook
+        ))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(res.body()).contains("X-Cnb-Shim-Callback-Secret"));
+
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!(
+            "/v1/heroku/ruby?version=1.0.0&callback_url={}",
+            hook
+        ))
+        .header("X-Cnb-Shim-Callback-Secret", "s3cr3t")
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    wait_for_callback(&callback).await;
+}
+
+#[tokio::test]
+async fn takes_callback_options_from_the_json_body() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    let callback = server
+        .mock_async(|when, then| {
+            when.method(POST)
+                .path("/hook")
+                .header_exists("x-cnb-shim-signature")
+                .json_body_partial(r#"{ "id": "heroku/ruby", "version": "1.0.0" }"#);
+            then.status(204);
+        })
+        .await;
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/v1/heroku/ruby")
+        .json(&json!({
+            "version": "1.0.0",
+            "callback_url": server.url("/hook"),
+            "callback_secret": "s3cr3t",
+        }))
+        .reply(&routes(&server, work_dir.path()))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    wait_for_callback(&callback).await;
+}
+
+#[cfg(feature = "grpc")]
+#[tokio::test]
+async fn grpc_refuses_tarballs_from_hosts_that_arent_allowed() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    let service = ShimService::new(
+        Arc::new(shimmer(&server, work_dir.path(), LIMITS)),
+        None,
+        None,
+    );
+
+    let request = tonic::Request::new(proto::ShimRequest {
+        id: String::from("acme/internal"),
+        url: String::from("http://10.0.0.1/internal.tgz"),
+        options: None,
+        format: proto::ArchiveFormat::TarGz as i32,
+    });
+    match service.shim(request).await {
+        Ok(_) => panic!("shimmed from a host that isn't allowed"),
+        Err(status) => {
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+            assert!(status.message().contains("10.0.0.1 is not an allowed host"));
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[tokio::test]
+async fn grpc_streams_shimmed_buildpacks() {
+    use tokio_stream::StreamExt;
+
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/buildpacks/heroku%2Fruby/revisions");
+            then.status(200).json_body(json!([
+                { "release": 1, "tar_link": server.url("/heroku/ruby.tgz") },
+                { "release": 42, "tar_link": server.url("/releases/v42.tgz") },
+            ]));
+        })
+        .await;
+    let service = ShimService::new(
+        Arc::new(shimmer(&server, work_dir.path(), LIMITS)),
+        None,
+        None,
+    );
+
+    let request = tonic::Request::new(proto::ShimRequest {
+        id: String::from("heroku/ruby"),
+        url: String::new(),
+        options: Some(proto::ShimOptions {
+            version: String::from("1.0.0"),
+            name: String::from("Ruby"),
+            stacks: vec![String::from("heroku-24")],
+            metadata: vec![(String::from("owner.team"), String::from("languages"))]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        }),
+        format: proto::ArchiveFormat::TarGz as i32,
+    });
+    let mut stream = match service.shim(request).await {
+        Ok(response) => response.into_inner(),
+        Err(status) => panic!("shim failed: {}", status),
+    };
+
+    let mut info = None;
+    let mut data = Vec::new();
+    while let Some(response) = stream.next().await {
+        match response.unwrap().payload.unwrap() {
+            proto::shim_response::Payload::Artifact(artifact) => {
+                assert!(info.is_none() && data.is_empty(), "artifact comes first");
+                info = Some(artifact);
+            }
+            proto::shim_response::Payload::Chunk(chunk) => data.extend(chunk),
+        }
+    }
+    let info = info.unwrap();
+    assert_eq!(info.id, "heroku/ruby");
+    assert_eq!(info.version, "1.0.0");
+    assert_eq!(info.size, data.len() as u64);
+    assert_eq!(info.content_type, "application/x-gzip");
+    let buildpack_toml = buildpack_toml(&unpack(&data));
+    assert_eq!(buildpack_toml["buildpack"]["name"].as_str(), Some("Ruby"));
+    assert_eq!(
+        buildpack_toml["stacks"][0]["id"].as_str(),
+        Some("heroku-24")
+    );
+    assert_eq!(
+        buildpack_toml["metadata"]["owner"]["team"].as_str(),
+        Some("languages")
+    );
+
+    let resolved = service
+        .resolve(tonic::Request::new(proto::ResolveRequest {
+            id: String::from("heroku/ruby"),
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(resolved.version, "42.0.0");
+    assert_eq!(resolved.tarball_url, server.url("/releases/v42.tgz"));
+}
+
+#[cfg(feature = "grpc")]
+#[tokio::test]
+async fn grpc_maps_shim_errors_to_status_codes() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/error.xml");
+            then.status(200)
+                .body("<?xml version=\"1.0\"?><Error><Code>NoSuchKey</Code></Error>");
+        })
+        .await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    let request = |id: &str, url: &str, format: i32| {
+        tonic::Request::new(proto::ShimRequest {
+            id: String::from(id),
+            url: String::from(url),
+            options: Some(proto::ShimOptions {
+                version: String::from("1.0.0"),
+                ..Default::default()
+            }),
+            format,
+        })
+    };
+    let tar_gz = proto::ArchiveFormat::TarGz as i32;
+    let limits = shim::Limits {
+        download_size: 16,
+        ..LIMITS
+    };
+
+    for (request, limits, code) in [
+        (
+            request("not an id", "", tar_gz),
+            LIMITS,
+            tonic::Code::InvalidArgument,
+        ),
+        (
+            request("heroku/ruby", "", 42),
+            LIMITS,
+            tonic::Code::InvalidArgument,
+        ),
+        (
+            request("acme/error", &server.url("/error.xml"), tar_gz),
+            LIMITS,
+            tonic::Code::FailedPrecondition,
+        ),
+        (
+            request("heroku/ruby", "", tar_gz),
+            limits,
+            tonic::Code::ResourceExhausted,
+        ),
+    ] {
+        let service = ShimService::new(
+            Arc::new(shimmer(&server, work_dir.path(), limits)),
+            None,
+            None,
+        );
+        match service.shim(request).await {
+            Ok(_) => panic!("expected {:?}", code),
+            Err(status) => assert_eq!(status.code(), code, "{}", status.message()),
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[tokio::test]
+async fn grpc_publishes_to_the_cache_and_reports_jobs() {
+    let server = MockServer::start_async().await;
+    let work_dir = tempfile::tempdir().unwrap();
+    let storage_dir = tempfile::tempdir().unwrap();
+    mock_releases(&server, "heroku/ruby", &[1]).await;
+    server
+        .mock_async(|when, then| {
+            when.method(GET).path("/heroku/ruby.tgz");
+            then.status(200).body(v2_buildpack(None));
+        })
+        .await;
+    let janitor = Janitor::new(work_dir.path(), None, 0, Duration::from_secs(3600)).unwrap();
+    let storage = LocalStorage::new(storage_dir.path()).unwrap();
+    let cached = Arc::new(shim::Shimmer::new(
+        scripts::ShimScripts::bundled(None).unwrap(),
+        Arc::new(janitor),
+        Some(Arc::new(Cache::new(Arc::new(storage), None))),
+        None,
+        Registry::new(
+            server.base_url(),
+            server.base_url(),
+            server.base_url(),
+            reqwest::Client::new(),
+        ),
+        None,
+        stacks(),
+        LIMITS,
+        reqwest::Client::new(),
+        allowed_hosts(),
+    ));
+    let service = ShimService::new(
+        cached.clone(),
+        None,
+        Some(String::from("https://shim.example.com/")),
+    );
+    let publish = |version: &str| {
+        tonic::Request::new(proto::ShimRequest {
+            id: String::from("heroku/ruby"),
+            url: String::new(),
+            options: Some(proto::ShimOptions {
+                version: String::from(version),
+                ..Default::default()
+            }),
+            format: proto::ArchiveFormat::TarGz as i32,
+        })
+    };
+    async fn finished(service: &ShimService, job_id: &str) -> proto::JobStatusResponse {
+        for _ in 0..100 {
+            let status = service
+                .job_status(tonic::Request::new(proto::JobStatusRequest {
+                    job_id: String::from(job_id),
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            if status.state() != proto::job_status_response::State::Running {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("job {} never finished", job_id);
+    }
+
+    let job_id = service
+        .publish(publish("1.0.0"))
+        .await
+        .unwrap()
+        .into_inner()
+        .job_id;
+    let status = finished(&service, &job_id).await;
+    assert_eq!(status.state(), proto::job_status_response::State::Succeeded);
+    let artifact = status.artifact.unwrap();
+    assert_eq!(artifact.id, "heroku/ruby");
+    assert_eq!(artifact.version, "1.0.0");
+    let file_name = status
+        .artifact_url
+        .strip_prefix("https://shim.example.com/v1/artifacts/")
+        .unwrap();
+
+    // served from the cache, without shimming it again
+    let res = warp::test::request()
+        .method("GET")
+        .path(&format!("/v1/artifacts/{}", file_name))
+        .reply(&routes_for(cached, None))
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.body().len() as u64, artifact.size);
+    assert_eq!(
+        artifact.digest,
+        format!("sha256:{}", hex::encode(Sha256::digest(res.body())))
+    );
+
+    let job_id = service
+        .publish(publish("7.0.0"))
+        .await
+        .unwrap()
+        .into_inner()
+        .job_id;
+    let status = finished(&service, &job_id).await;
+    assert_eq!(status.state(), proto::job_status_response::State::Failed);
+    assert_eq!(status.code, tonic::Code::InvalidArgument as i32);
+    assert!(status.error.contains("has no release 7.0.0"));
+    assert!(status.artifact.is_none());
+
+    match service
+        .job_status(tonic::Request::new(proto::JobStatusRequest {
+            job_id: String::from("unknown"),
+        }))
+        .await
+    {
+        Ok(_) => panic!("reported a job that doesn't exist"),
+        Err(status) => assert_eq!(status.code(), tonic::Code::NotFound),
+    }
+
+    // without a cache there's nowhere to publish to
+    let service = ShimService::new(
+        Arc::new(shimmer(&server, work_dir.path(), LIMITS)),
+        None,
+        None,
+    );
+    match service.publish(publish("1.0.0")).await {
+        Ok(_) => panic!("published without a cache"),
+        Err(status) => assert_eq!(status.code(), tonic::Code::FailedPrecondition),
+    }
+}
+
+#[test]
+fn sweeps_while_work_directories_come_and_go() {
+    let work_dir = tempfile::tempdir().unwrap();
+    let janitor = Janitor::new(work_dir.path(), None, 0, Duration::from_secs(0)).unwrap();
+    let churned = work_dir.path().to_path_buf();
+    let churn = std::thread::spawn(move || {
+        for i in 0..500 {
+            let dir = churned.join(format!("request-{}", i % 4));
+            // the janitor may remove any of it at any point, max_age is 0
+            std::fs::create_dir_all(dir.join("buildpack/bin")).ok();
+            std::fs::write(dir.join("buildpack/bin/detect"), "#!/bin/sh").ok();
+            std::fs::remove_dir_all(&dir).ok();
+        }
+    });
+
+    while !churn.is_finished() {
+        janitor.sweep().unwrap();
+    }
+    churn.join().unwrap();
+    assert_eq!(janitor.sweep().unwrap().usage, 0);
+}
+
+/// A self-signed certificate for localhost and its private key, PEM encoded
+fn self_signed_certificate() -> (String, String) {
+    let certificate = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+
+    (
+        certificate.serialize_pem().unwrap(),
+        certificate.serialize_private_key_pem(),
+    )
+}
+
+/// The DER encoded certificate of a PEM encoded one
+fn der(pem: &str) -> Vec<u8> {
+    base64::decode(
+        pem.lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect::<String>(),
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn waits_for_tls_files_to_change_and_settle() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cert.pem");
+    std::fs::write(&path, "before").unwrap();
+    let interval = Duration::from_millis(20);
+
+    assert!(tokio::time::timeout(
+        Duration::from_millis(200),
+        tls::wait_for_change(&[path.as_path()], interval)
+    )
+    .await
+    .is_err());
+
+    let changed = path.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::write(&changed, "after").unwrap();
+    });
+    assert!(tokio::time::timeout(
+        Duration::from_secs(5),
+        tls::wait_for_change(&[path.as_path()], interval)
+    )
+    .await
+    .is_ok());
+}
+
+#[tokio::test]
+async fn reloads_the_tls_certificate_once_a_new_pair_loads() {
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    let (cert, key) = self_signed_certificate();
+    std::fs::write(&cert_path, &cert).unwrap();
+    std::fs::write(&key_path, &key).unwrap();
+    let resolver = Arc::new(tls::CertResolver::load(&cert_path, &key_path).unwrap());
+    assert_eq!(resolver.chain(), [der(&cert)]);
+    tokio::spawn(tls::reload_on_change(
+        resolver.clone(),
+        Duration::from_millis(20),
+    ));
+
+    // half way through a rotation, the previous pair keeps being served
+    std::fs::write(&cert_path, &cert[..cert.len() / 2]).unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(resolver.chain(), [der(&cert)]);
+
+    let (new_cert, new_key) = self_signed_certificate();
+    std::fs::write(&cert_path, &new_cert).unwrap();
+    std::fs::write(&key_path, &new_key).unwrap();
+    for _ in 0..100 {
+        if resolver.chain() == [der(&new_cert)] {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the new TLS certificate wasn't loaded");
+}
+
+#[tokio::test]
+async fn serves_over_tls_with_the_address_of_the_peer() {
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    let (cert, key) = self_signed_certificate();
+    std::fs::write(&cert_path, &cert).unwrap();
+    std::fs::write(&key_path, &key).unwrap();
+    let resolver = Arc::new(tls::CertResolver::load(&cert_path, &key_path).unwrap());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let routes =
+        warp::ext::get::<tls::RemoteAddr>().map(|peer: tls::RemoteAddr| peer.0.ip().to_string());
+    tokio::spawn(tls::serve(listener, resolver, warp::service(routes)));
+
+    let res = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap()
+        .get(format!("https://localhost:{}/", port))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.text().await.unwrap(), "127.0.0.1");
+}